@@ -0,0 +1,149 @@
+use crate::config::AnnounceConfig;
+use log::{debug, warn};
+use mdns_sd::{ServiceDaemon, ServiceInfo};
+use std::collections::BTreeMap;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Gap before the first extra announcement pair; doubles for each one after
+const FIRST_EXTRA_DELAY_SECS: u64 = 3;
+/// How long the thread sleeps when nothing is scheduled
+const IDLE_WAIT_SECS: u64 = 3_600;
+
+struct Schedule {
+    service_info: ServiceInfo,
+    next: Instant,
+    extra_rounds: u32,
+    gap: Duration,
+    interval: Option<Duration>,
+}
+
+/// Background thread sending the extra and periodic announcements that
+/// `mdns-sd` does not schedule itself.
+///
+/// Re-registering a service makes the daemon announce it again. Stops when
+/// dropped.
+pub(crate) struct Announcer {
+    schedules: Arc<Mutex<BTreeMap<String, Schedule>>>,
+    wake: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Announcer {
+    pub(crate) fn start(daemon: ServiceDaemon) -> Self {
+        let schedules: Arc<Mutex<BTreeMap<String, Schedule>>> = Arc::default();
+        let (wake, wake_rx) = mpsc::channel();
+
+        let thread = thread::spawn({
+            let schedules = Arc::clone(&schedules);
+            move || {
+                loop {
+                    let next = announce_due(&daemon, &schedules, Instant::now());
+                    let wait = next
+                        .map(|next| next.saturating_duration_since(Instant::now()))
+                        .unwrap_or(Duration::from_secs(IDLE_WAIT_SECS));
+                    match wake_rx.recv_timeout(wait) {
+                        Ok(()) | Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+            }
+        });
+
+        Self {
+            schedules,
+            wake: Some(wake),
+            thread: Some(thread),
+        }
+    }
+
+    /// Schedule announcements for a service that was just registered.
+    pub(crate) fn add(&self, service_info: &ServiceInfo, config: &AnnounceConfig) {
+        let extra_rounds = config.extra_rounds();
+        let interval = config.reannounce_interval();
+        if extra_rounds == 0 && interval.is_none() {
+            return;
+        }
+
+        let gap = Duration::from_secs(FIRST_EXTRA_DELAY_SECS);
+        let first = match interval {
+            Some(interval) if extra_rounds == 0 => interval,
+            _ => gap,
+        };
+        let schedule = Schedule {
+            service_info: service_info.clone(),
+            next: Instant::now() + first,
+            extra_rounds,
+            gap,
+            interval,
+        };
+        self.lock()
+            .insert(service_info.get_fullname().to_string(), schedule);
+        self.wake();
+    }
+
+    pub(crate) fn remove(&self, fullname: &str) {
+        self.lock().remove(fullname);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<String, Schedule>> {
+        self.schedules
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn wake(&self) {
+        if let Some(wake) = &self.wake {
+            wake.send(()).ok();
+        }
+    }
+}
+
+impl Drop for Announcer {
+    fn drop(&mut self) {
+        // Disconnecting the wake channel ends the thread
+        self.wake.take();
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// Announce every service due at `now` and return when the next one is due.
+///
+/// Registers while holding the lock so a service removed concurrently is not
+/// announced again after its goodbye.
+fn announce_due(
+    daemon: &ServiceDaemon,
+    schedules: &Mutex<BTreeMap<String, Schedule>>,
+    now: Instant,
+) -> Option<Instant> {
+    let mut schedules = schedules.lock().unwrap_or_else(PoisonError::into_inner);
+
+    schedules.retain(|fullname, schedule| {
+        if schedule.next > now {
+            return true;
+        }
+        debug!("Announcing {} again", fullname);
+        if let Err(e) = daemon.register(schedule.service_info.clone()) {
+            warn!("Scheduled announcement of {} failed: {}", fullname, e);
+        }
+
+        if schedule.extra_rounds > 1 {
+            schedule.extra_rounds -= 1;
+            schedule.gap *= 2;
+            schedule.next = now + schedule.gap;
+            true
+        } else if let Some(interval) = schedule.interval {
+            schedule.extra_rounds = 0;
+            schedule.next = now + interval;
+            true
+        } else {
+            false
+        }
+    });
+
+    schedules.values().map(|schedule| schedule.next).min()
+}
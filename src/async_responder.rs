@@ -0,0 +1,128 @@
+use crate::config::ServiceConfig;
+use crate::error::{MdnsError, Result};
+use crate::mdns_service;
+use crate::responder::{Responder, ResponderStatus};
+use log::{info, warn};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::Duration;
+use tokio::runtime::Handle;
+use tokio::task;
+use tokio_util::sync::CancellationToken;
+
+/// Async wrapper around [`Responder`] for use inside a tokio runtime.
+///
+/// Daemon calls block for short periods, so each operation runs on tokio's
+/// blocking thread pool instead of stalling the caller's worker thread.
+/// Operations are cancellation-safe: a dropped future lets its blocking call
+/// finish and the handle stays usable. Dropping the handle shuts the
+/// responder down on the blocking pool as well.
+pub struct AsyncResponder {
+    inner: Arc<Mutex<Responder>>,
+}
+
+impl AsyncResponder {
+    /// Start an mDNS daemon and register the service described by `config`.
+    pub async fn start(config: ServiceConfig) -> Result<Self> {
+        let responder = blocking(move || Responder::start(config)).await??;
+        Ok(Self::from(responder))
+    }
+
+    /// Replace the primary service with one built from `config`.
+    pub async fn update(&self, config: ServiceConfig) -> Result<()> {
+        self.with_inner(move |responder| responder.update(config))
+            .await
+    }
+
+    /// Register an additional service and return its full name.
+    pub async fn add_service(&self, config: ServiceConfig) -> Result<String> {
+        self.with_inner(move |responder| responder.add_service(config))
+            .await
+    }
+
+    /// Unregister the service with the given full name.
+    pub async fn remove_service(&self, fullname: &str) -> Result<()> {
+        let fullname = fullname.to_string();
+        self.with_inner(move |responder| responder.remove_service(&fullname))
+            .await
+    }
+
+    /// Report daemon health and the services currently advertised.
+    pub async fn status(&self) -> Result<ResponderStatus> {
+        self.with_inner(|responder| Ok(responder.status())).await
+    }
+
+    /// Unregister every service and stop the daemon, waiting up to `timeout`.
+    pub async fn shutdown(self, timeout: Duration) -> Result<()> {
+        self.with_inner(move |responder| responder.shutdown(timeout))
+            .await
+    }
+
+    /// Run `f` on the blocking pool with its own reference to the responder,
+    /// so cancelling the caller cannot lose it.
+    async fn with_inner<T, F>(&self, f: F) -> Result<T>
+    where
+        T: Send + 'static,
+        F: FnOnce(&mut Responder) -> Result<T> + Send + 'static,
+    {
+        let inner = Arc::clone(&self.inner);
+        blocking(move || f(&mut lock(&inner))).await?
+    }
+}
+
+impl From<Responder> for AsyncResponder {
+    fn from(responder: Responder) -> Self {
+        Self {
+            inner: Arc::new(Mutex::new(responder)),
+        }
+    }
+}
+
+impl Drop for AsyncResponder {
+    fn drop(&mut self) {
+        let inner = Arc::clone(&self.inner);
+        let shutdown = move || {
+            let mut responder = lock(&inner);
+            let timeout = responder.shutdown_timeout();
+            if let Err(e) = responder.shutdown(timeout) {
+                warn!("Responder shutdown on drop failed: {}", e);
+            }
+        };
+        // Goodbyes and the daemon shutdown wait must not block a worker thread
+        match Handle::try_current() {
+            Ok(handle) => drop(handle.spawn_blocking(shutdown)),
+            Err(_) => shutdown(),
+        }
+    }
+}
+
+/// Register the configured service and wait until `token` is cancelled.
+///
+/// Async counterpart of [`mdns_service::run`].
+pub async fn run(token: CancellationToken, config_override: Option<ServiceConfig>) -> Result<()> {
+    info!("Initializing mDNS Responder Service...");
+
+    let config = mdns_service::load_config(config_override)?;
+    info!("Using configuration: {:?}", config);
+
+    let shutdown_timeout = config.shutdown_timeout();
+    let responder = AsyncResponder::start(config).await?;
+
+    token.cancelled().await;
+    info!("Received shutdown signal.");
+
+    responder.shutdown(shutdown_timeout).await
+}
+
+async fn blocking<T, F>(f: F) -> Result<T>
+where
+    T: Send + 'static,
+    F: FnOnce() -> T + Send + 'static,
+{
+    task::spawn_blocking(f)
+        .await
+        .map_err(|e| MdnsError::Thread(e.to_string()))
+}
+
+fn lock(responder: &Mutex<Responder>) -> MutexGuard<'_, Responder> {
+    responder.lock().unwrap_or_else(PoisonError::into_inner)
+}
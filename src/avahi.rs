@@ -0,0 +1,307 @@
+use crate::error::{MdnsError, Result};
+use crate::publisher::Publisher;
+use log::{info, warn};
+use mdns_sd::ServiceInfo;
+use std::collections::BTreeMap;
+use std::ops::Deref;
+use std::thread;
+use std::time::{Duration, Instant};
+use zbus::blocking::{Connection, Proxy};
+use zbus::zvariant::OwnedObjectPath;
+
+const DESTINATION: &str = "org.freedesktop.Avahi";
+const SERVER: &str = "org.freedesktop.Avahi.Server";
+const ENTRY_GROUP: &str = "org.freedesktop.Avahi.EntryGroup";
+/// `AVAHI_IF_UNSPEC` and `AVAHI_PROTO_UNSPEC`: every interface and protocol
+const UNSPEC: i32 = -1;
+/// `AVAHI_SERVER_RUNNING`
+const SERVER_RUNNING: i32 = 2;
+/// `AVAHI_ENTRY_GROUP_ESTABLISHED`, `_COLLISION` and `_FAILURE`
+const GROUP_ESTABLISHED: i32 = 2;
+const GROUP_COLLISION: i32 = 3;
+const GROUP_FAILURE: i32 = 4;
+/// How long registering waits for avahi to finish probing
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(5);
+const STATE_POLL_MILLIS: u64 = 100;
+
+/// Publishes through avahi-daemon's D-Bus API, so avahi answers for the
+/// services instead of our own daemon.
+///
+/// Each service gets an entry group of its own. A host name other than
+/// avahi's gets its address records from a group shared by the services on
+/// that host.
+pub(crate) struct AvahiPublisher {
+    connection: Connection,
+    /// avahi's own host name, e.g. `myhost.local`
+    host_fqdn: String,
+    services: BTreeMap<String, Service>,
+    hosts: BTreeMap<String, Host>,
+}
+
+struct Service {
+    group: Proxy<'static>,
+    /// Host name whose address records this service uses, unless avahi's
+    host: Option<String>,
+}
+
+struct Host {
+    group: Proxy<'static>,
+    services: usize,
+}
+
+impl AvahiPublisher {
+    /// Connect to avahi-daemon on the system bus.
+    pub(crate) fn connect() -> Result<Self> {
+        let connection = Connection::system().map_err(MdnsError::avahi("connect", None))?;
+        let server = Proxy::new_owned(connection.clone(), DESTINATION, "/", SERVER)
+            .map_err(MdnsError::avahi("connect", None))?;
+        let host_fqdn: String = server
+            .call("GetHostNameFqdn", &())
+            .map_err(MdnsError::avahi("look up its host name", None))?;
+        Ok(Self {
+            connection,
+            host_fqdn,
+            services: BTreeMap::new(),
+            hosts: BTreeMap::new(),
+        })
+    }
+
+    fn server(&self) -> Result<Proxy<'static>> {
+        Proxy::new_owned(self.connection.clone(), DESTINATION, "/", SERVER)
+            .map_err(MdnsError::avahi("connect", None))
+    }
+
+    /// A new, empty entry group, freed again unless it is kept.
+    fn entry_group(&self, fullname: &str) -> Result<PendingGroup> {
+        let path: OwnedObjectPath = self
+            .server()?
+            .call("EntryGroupNew", &())
+            .map_err(MdnsError::avahi("create an entry group", Some(fullname)))?;
+        let group = Proxy::new_owned(self.connection.clone(), DESTINATION, path, ENTRY_GROUP)
+            .map_err(MdnsError::avahi("create an entry group", Some(fullname)))?;
+        Ok(PendingGroup {
+            group,
+            name: fullname.to_string(),
+            kept: false,
+        })
+    }
+
+    /// Publish the address records of `hostname` for `service_info`, or
+    /// count one more user of those already published.
+    fn add_host(&mut self, hostname: &str, service_info: &ServiceInfo) -> Result<()> {
+        if let Some(host) = self.hosts.get_mut(hostname) {
+            host.services += 1;
+            return Ok(());
+        }
+        let group = self.entry_group(hostname)?;
+        for ip in service_info.get_addresses() {
+            group
+                .call::<_, _, ()>(
+                    "AddAddress",
+                    &(UNSPEC, UNSPEC, 0u32, hostname, ip.to_string()),
+                )
+                .map_err(MdnsError::avahi("add an address", Some(hostname)))?;
+        }
+        commit(&group, hostname)?;
+        self.hosts.insert(
+            hostname.to_string(),
+            Host {
+                group: group.keep(),
+                services: 1,
+            },
+        );
+        Ok(())
+    }
+
+    fn remove_host(&mut self, hostname: &str) {
+        let Some(host) = self.hosts.get_mut(hostname) else {
+            return;
+        };
+        host.services -= 1;
+        if host.services == 0 {
+            if let Some(host) = self.hosts.remove(hostname) {
+                free(&host.group, hostname);
+            }
+        }
+    }
+}
+
+impl Publisher for AvahiPublisher {
+    fn name(&self) -> &'static str {
+        "avahi-daemon"
+    }
+
+    fn register(&mut self, service_info: &ServiceInfo) -> Result<()> {
+        let fullname = service_info.get_fullname();
+        // `_smb._tcp.local.` is type `_smb._tcp` in the default domain
+        let domain_type = service_info.get_type();
+        let instance = fullname
+            .strip_suffix(domain_type)
+            .and_then(|name| name.strip_suffix('.'))
+            .unwrap_or(fullname);
+        let service_type = domain_type.trim_end_matches('.').trim_end_matches(".local");
+        let hostname = service_info.get_hostname().trim_end_matches('.');
+        let host = (!hostname.eq_ignore_ascii_case(&self.host_fqdn)).then(|| hostname.to_string());
+        let txt: Vec<Vec<u8>> = service_info
+            .get_properties()
+            .iter()
+            .map(|property| match property.val() {
+                Some(value) => [property.key().as_bytes(), b"=", value].concat(),
+                None => property.key().as_bytes().to_vec(),
+            })
+            .collect();
+
+        if let Some(host) = &host {
+            self.add_host(host, service_info)?;
+        }
+        let published = self.entry_group(fullname).and_then(|group| {
+            group
+                .call::<_, _, ()>(
+                    "AddService",
+                    &(
+                        UNSPEC,
+                        UNSPEC,
+                        0u32,
+                        instance,
+                        service_type,
+                        "",
+                        host.as_deref().unwrap_or(""),
+                        service_info.get_port(),
+                        txt,
+                    ),
+                )
+                .map_err(MdnsError::avahi("add service", Some(fullname)))?;
+            if let Some(subtype) = service_info.get_subtype() {
+                let subtype = subtype.trim_end_matches('.').trim_end_matches(".local");
+                group
+                    .call::<_, _, ()>(
+                        "AddServiceSubtype",
+                        &(UNSPEC, UNSPEC, 0u32, instance, service_type, "", subtype),
+                    )
+                    .map_err(MdnsError::avahi("add subtype", Some(fullname)))?;
+            }
+            commit(&group, fullname)?;
+            Ok(group.keep())
+        });
+        match published {
+            Ok(group) => {
+                info!("Registered {} with avahi-daemon", fullname);
+                self.services
+                    .insert(fullname.to_string(), Service { group, host });
+                Ok(())
+            }
+            Err(e) => {
+                if let Some(host) = &host {
+                    self.remove_host(host);
+                }
+                Err(e)
+            }
+        }
+    }
+
+    /// Freeing the entry group makes avahi send the goodbyes; it does not
+    /// report when they went out.
+    fn unregister(&mut self, fullname: &str, _timeout: Duration) -> Result<()> {
+        let service = self.services.remove(fullname).ok_or_else(|| {
+            MdnsError::Service(format!("{} is not registered with avahi-daemon", fullname))
+        })?;
+        service
+            .group
+            .call::<_, _, ()>("Free", &())
+            .map_err(MdnsError::avahi("withdraw", Some(fullname)))?;
+        if let Some(host) = &service.host {
+            self.remove_host(host);
+        }
+        Ok(())
+    }
+
+    fn is_running(&self) -> bool {
+        self.server()
+            .and_then(|server| {
+                server
+                    .call::<_, _, i32>("GetState", &())
+                    .map_err(MdnsError::avahi("report its state", None))
+            })
+            .is_ok_and(|state| state == SERVER_RUNNING)
+    }
+
+    fn shutdown(&mut self, _timeout: Duration) -> Result<()> {
+        for (hostname, host) in std::mem::take(&mut self.hosts) {
+            free(&host.group, &hostname);
+        }
+        Ok(())
+    }
+}
+
+/// An entry group being filled in, freed when dropped so a failed
+/// registration does not leave it behind in avahi-daemon.
+struct PendingGroup {
+    group: Proxy<'static>,
+    name: String,
+    kept: bool,
+}
+
+impl PendingGroup {
+    /// Hand the published group over to the caller instead of freeing it.
+    fn keep(mut self) -> Proxy<'static> {
+        self.kept = true;
+        self.group.clone()
+    }
+}
+
+impl Deref for PendingGroup {
+    type Target = Proxy<'static>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.group
+    }
+}
+
+impl Drop for PendingGroup {
+    fn drop(&mut self) {
+        if !self.kept {
+            free(&self.group, &self.name);
+        }
+    }
+}
+
+/// Commit `group` and wait until avahi has probed its records.
+fn commit(group: &Proxy, name: &str) -> Result<()> {
+    group
+        .call::<_, _, ()>("Commit", &())
+        .map_err(MdnsError::avahi("commit", Some(name)))?;
+    let deadline = Instant::now() + REGISTER_TIMEOUT;
+    loop {
+        let state: i32 = group
+            .call("GetState", &())
+            .map_err(MdnsError::avahi("report the state", Some(name)))?;
+        match state {
+            GROUP_ESTABLISHED => return Ok(()),
+            GROUP_COLLISION => {
+                return Err(MdnsError::Service(format!(
+                    "avahi-daemon found {} already in use on the network",
+                    name
+                )));
+            }
+            GROUP_FAILURE => {
+                return Err(MdnsError::Service(format!(
+                    "avahi-daemon failed to publish {}",
+                    name
+                )));
+            }
+            _ if Instant::now() >= deadline => {
+                return Err(MdnsError::Timeout(format!(
+                    "avahi-daemon did not publish {} within {:?}",
+                    name, REGISTER_TIMEOUT
+                )));
+            }
+            _ => thread::sleep(Duration::from_millis(STATE_POLL_MILLIS)),
+        }
+    }
+}
+
+fn free(group: &Proxy, name: &str) {
+    if let Err(e) = group.call::<_, _, ()>("Free", &()) {
+        warn!("Failed to free avahi entry group for {}: {}", name, e);
+    }
+}
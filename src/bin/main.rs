@@ -1,45 +1,543 @@
-use log::info;
-use mdns_responder::Result;
-#[cfg(debug_assertions)]
-use mdns_responder::discovery;
-use mdns_responder::{mdns_service, windows_service};
+use log::{error, info, warn};
+use mdns_responder::config::{self, ConfigOverrides, LoggingConfig, ServiceConfig};
+use mdns_responder::control::{self, ControlServer, HostStatus, Request};
+use mdns_responder::discovery::{self, DiscoveredService, DiscoveryEvent};
+#[cfg(windows)]
+use mdns_responder::firewall;
+use mdns_responder::logging;
+use mdns_responder::mdns_service::{self, ConfigFallback};
+use mdns_responder::metrics::MetricsServer;
+use mdns_responder::permissions;
+use mdns_responder::selfcheck;
+#[cfg(unix)]
+use mdns_responder::systemd_service as service_host;
+#[cfg(windows)]
+use mdns_responder::windows_service as service_host;
+use mdns_responder::{MdnsError, Responder, Result};
 use std::env;
+use std::path::Path;
+use std::sync::{Arc, Mutex, mpsc};
+use std::time::Duration;
+
+const DEFAULT_DISCOVER_TYPE: &str = "_smb._tcp.local.";
+const DEFAULT_DISCOVER_TIMEOUT_SECS: u64 = 10;
+const DEFAULT_SELFCHECK_TIMEOUT_SECS: u64 = 10;
 
 fn main() -> Result<()> {
     let args: Vec<String> = env::args().collect();
 
     if args.len() > 1 {
-        env_logger::builder().init();
+        match args[1].as_str() {
+            // Long-running hosts log where the config file says
+            "run" | "service" => logging::init(&logging_config(), true)?,
+            _ => env_logger::builder().init(),
+        }
         match args[1].as_str() {
             "install" => {
-                info!("Installing Windows service...");
-                windows_service::install()?;
+                let options = install_options(&args[0], &args[2..])?;
+                info!("Installing service...");
+                service_host::install(&options)?;
             }
             "uninstall" => {
-                info!("Uninstalling Windows service...");
-                windows_service::uninstall()?;
+                info!("Uninstalling service...");
+                service_host::uninstall()?;
             }
             "run" => {
+                let flags = allow_default_config(&args[2..]);
+                mdns_service::set_cli_overrides(cli_overrides(&flags)?);
                 info!("Running mDNS responder service in foreground...");
-                mdns_service::run(None, None)?;
+                exit_on_error(run_foreground());
+            }
+            #[cfg(unix)]
+            "service" => {
+                if !allow_default_config(&args[2..]).is_empty() {
+                    usage(&args[0]);
+                }
+                info!("Running mDNS responder under systemd...");
+                exit_on_error(service_host::run_service());
             }
-            #[cfg(debug_assertions)]
             "discover" => {
                 info!("Discovering mDNS services on network...");
-                discovery::test_discovery()?;
+                discover(&args[0], &args[2..])?;
+            }
+            "selfcheck" => {
+                info!("Checking our advertisement from the network...");
+                self_check(&args[0], &args[2..])?;
+            }
+            "status" => status(&args[0], &args[2..])?,
+            "print-effective-config" => print_effective_config(&args[2..])?,
+            "migrate-config" => match &args[2..] {
+                [] => migrate_config(&ServiceConfig::config_path())?,
+                [path] => migrate_config(Path::new(path))?,
+                _ => usage(&args[0]),
+            },
+            "secure-config" => {
+                let path = match &args[2..] {
+                    [] => ServiceConfig::config_path(),
+                    [path] => path.into(),
+                    _ => usage(&args[0]),
+                };
+                permissions::secure_config(&path)?;
+                println!("Only administrators can now change {}", path.display());
             }
-            _ => {
-                #[cfg(debug_assertions)]
-                let usage_msg = "Usage: {} [install|uninstall|run|discover]";
-                #[cfg(not(debug_assertions))]
-                let usage_msg = "Usage: {} [install|uninstall|run]";
-                eprintln!("{}", usage_msg.replace("{}", &args[0]));
-                std::process::exit(1);
+            #[cfg(windows)]
+            "firewall-rule" => {
+                let profiles = match &args[2..] {
+                    [] => firewall::Profile::DEFAULT.to_vec(),
+                    [list] => firewall::Profile::parse_list(list)?,
+                    _ => usage(&args[0]),
+                };
+                firewall::add_rule(&env::current_exe()?, &profiles)?;
+                println!("Inbound UDP 5353 is allowed by '{}'", firewall::RULE_NAME);
             }
+            "config-schema" => {
+                println!("{}", serde_json::to_string_pretty(&config::json_schema())?)
+            }
+            "control" => match args.get(2).map(String::as_str) {
+                Some("status") => send_control(Request::Status)?,
+                Some("reload") => send_control(Request::Reload)?,
+                Some("reannounce") => send_control(Request::Reannounce)?,
+                Some("list-services") => send_control(Request::ListServices)?,
+                Some("discovered") => send_control(Request::Discovered {
+                    service_type: args.get(3).cloned(),
+                })?,
+                _ => usage(&args[0]),
+            },
+            _ => usage(&args[0]),
         }
     } else {
-        windows_service::run_dispatcher()?;
+        #[cfg(windows)]
+        {
+            logging::init(&logging_config(), false)?;
+            service_host::run_dispatcher()?;
+        }
+        #[cfg(unix)]
+        usage(&args[0]);
+    }
+
+    Ok(())
+}
+
+/// Logging section of the config file, before the full config is loaded.
+fn logging_config() -> LoggingConfig {
+    ServiceConfig::from_file(&ServiceConfig::config_path())
+        .map(|config| config.logging)
+        .unwrap_or_default()
+}
+
+/// Opt into default settings when `args` has `--allow-default-config`, and
+/// return the other arguments.
+fn allow_default_config(args: &[String]) -> Vec<String> {
+    if args
+        .iter()
+        .any(|arg| arg == mdns_service::ALLOW_DEFAULT_CONFIG_FLAG)
+    {
+        mdns_service::set_config_fallback(ConfigFallback::Defaults);
+    }
+    args.iter()
+        .filter(|arg| *arg != mdns_service::ALLOW_DEFAULT_CONFIG_FLAG)
+        .cloned()
+        .collect()
+}
+
+/// Exit with the error's stable code, which service managers report and
+/// which tells configuration errors apart from runtime failures.
+fn exit_on_error(result: Result<()>) {
+    if let Err(e) = result {
+        error!("Exiting with code {}: {}", e.code(), e);
+        std::process::exit(e.code() as i32);
+    }
+}
+
+/// `--port 8445` or `--port=8445` style overrides of config keys.
+fn cli_overrides(args: &[String]) -> Result<ConfigOverrides> {
+    let mut overrides = ConfigOverrides::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let (flag, value) = match arg.split_once('=') {
+            Some((flag, value)) => (flag, value),
+            None => (
+                arg.as_str(),
+                args.next()
+                    .map(String::as_str)
+                    .ok_or_else(|| MdnsError::ConfigValidation(format!("{} needs a value", arg)))?,
+            ),
+        };
+        if !flag.starts_with("--") {
+            return Err(MdnsError::ConfigValidation(format!(
+                "unexpected argument '{}'",
+                arg
+            )));
+        }
+        overrides.set_flag(flag, value)?;
+    }
+    Ok(overrides)
+}
+
+fn print_effective_config(args: &[String]) -> Result<()> {
+    let json = args.iter().any(|arg| arg == "--json");
+    let flags: Vec<String> = args
+        .iter()
+        .filter(|arg| *arg != "--json")
+        .cloned()
+        .collect();
+    mdns_service::set_cli_overrides(cli_overrides(&flags)?);
+
+    let resolved = mdns_service::resolve_config()?;
+    let config = resolved.config.to_redacted_value()?;
+    if json {
+        let output = serde_json::json!({ "config": config, "sources": resolved.sources });
+        println!("{}", serde_json::to_string_pretty(&output)?);
+        return Ok(());
+    }
+
+    println!("{:<24} {:<40} SOURCE", "KEY", "VALUE");
+    for (key, source) in &resolved.sources {
+        let value = config.get(key).unwrap_or(&serde_json::Value::Null);
+        println!("{:<24} {:<40} {}", key, value.to_string(), source);
+    }
+    Ok(())
+}
+
+fn migrate_config(path: &Path) -> Result<()> {
+    match ServiceConfig::migrate_file(path)? {
+        Some(backup) => println!(
+            "Migrated {} to config version {}; the original is in {}",
+            path.display(),
+            config::CONFIG_VERSION,
+            backup.display()
+        ),
+        None => println!(
+            "{} is already at config version {}",
+            path.display(),
+            config::CONFIG_VERSION
+        ),
+    }
+    Ok(())
+}
+
+fn run_foreground() -> Result<()> {
+    let config = mdns_service::load_config(None)?;
+    info!("Using configuration: {:?}", config);
+
+    let metrics_address = config.metrics_address.clone();
+    let responder = Arc::new(Mutex::new(Responder::start(config)?));
+    let control_server = ControlServer::start(control::default_endpoint(), Arc::clone(&responder))
+        .inspect_err(|e| warn!("Control channel unavailable: {}", e))
+        .ok();
+    let metrics_server = metrics_address.and_then(|address| {
+        MetricsServer::start(&address, Arc::clone(&responder))
+            .inspect_err(|e| warn!("Metrics endpoint unavailable: {}", e))
+            .ok()
+    });
+
+    let (tx, rx) = mpsc::channel();
+    ctrlc::set_handler(move || tx.send(()).unwrap())
+        .map_err(|e| MdnsError::Thread(e.to_string()))?;
+    info!("Waiting for Ctrl-C...");
+    rx.recv().ok();
+    info!("Received Ctrl-C signal.");
+
+    drop(control_server);
+    drop(metrics_server);
+    let mut responder = control::lock(&responder);
+    let shutdown_timeout = responder.shutdown_timeout();
+    responder.shutdown(shutdown_timeout)
+}
+
+fn status(program: &str, args: &[String]) -> Result<()> {
+    let json = match args {
+        [] => false,
+        [flag] if flag == "--json" => true,
+        _ => usage(program),
+    };
+
+    // Checked first, as a blocked port is a common reason for the service
+    // to be unreachable
+    #[cfg(windows)]
+    let firewall_warning = matches!(firewall::rule_exists(), Ok(false)).then(|| {
+        format!(
+            "Firewall: no '{}' rule, so mDNS queries are likely blocked; run `{} firewall-rule` as Administrator",
+            firewall::RULE_NAME,
+            program
+        )
+    });
+    #[cfg(unix)]
+    let firewall_warning: Option<String> = None;
+
+    let response =
+        control::request(control::default_endpoint(), Request::Status).inspect_err(|_| {
+            firewall_warning
+                .iter()
+                .for_each(|line| eprintln!("{}", line))
+        })?;
+    if json {
+        firewall_warning
+            .iter()
+            .for_each(|line| eprintln!("{}", line));
+        println!("{}", serde_json::to_string_pretty(&response)?);
+        return Ok(());
+    }
+
+    let status: HostStatus = match (response.ok, response.result) {
+        (true, Some(result)) => serde_json::from_value(result)?,
+        _ => {
+            return Err(MdnsError::Service(format!(
+                "{} (code {})",
+                response.error.as_deref().unwrap_or("empty reply"),
+                response.code.unwrap_or_default()
+            )));
+        }
+    };
+    println!(
+        "Service: {} (pid {}, up {}s)",
+        if status.responder.daemon_running {
+            "running"
+        } else {
+            "daemon not responding"
+        },
+        status.pid,
+        status.uptime_secs
+    );
+    if let Some(line) = firewall_warning {
+        println!("{}", line);
+    }
+    for service in &status.responder.services {
+        let addresses: Vec<String> = service.addresses.iter().map(|ip| ip.to_string()).collect();
+        let marker = if status.responder.primary.as_ref() == Some(&service.fullname) {
+            "*"
+        } else {
+            " "
+        };
+        println!(
+            "{} {} -> {}:{} [{}]",
+            marker,
+            service.fullname,
+            service.hostname,
+            service.port,
+            addresses.join(",")
+        );
     }
+    Ok(())
+}
 
+fn send_control(request: Request) -> Result<()> {
+    let response = control::request(control::default_endpoint(), request)?;
+    println!("{}", serde_json::to_string_pretty(&response)?);
+    if !response.ok {
+        std::process::exit(1);
+    }
     Ok(())
 }
+
+fn install_options(program: &str, args: &[String]) -> Result<service_host::InstallOptions> {
+    let mut options = service_host::InstallOptions::default();
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match (arg.as_str(), args.next()) {
+            ("--account", Some(name)) => options.account = name.parse()?,
+            #[cfg(windows)]
+            ("--firewall-profiles", Some(list)) => {
+                options.firewall_profiles = firewall::Profile::parse_list(list)?
+            }
+            _ => usage(program),
+        }
+    }
+    Ok(options)
+}
+
+fn discover(program: &str, args: &[String]) -> Result<()> {
+    let mut service_type = DEFAULT_DISCOVER_TYPE.to_string();
+    let mut timeout = Duration::from_secs(DEFAULT_DISCOVER_TIMEOUT_SECS);
+    let mut watch = false;
+    let mut json = false;
+    let mut cached = false;
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--json" => json = true,
+            "--watch" => watch = true,
+            "--cached" => cached = true,
+            "--timeout" => match args.next().and_then(|secs| secs.parse().ok()) {
+                Some(secs) => timeout = Duration::from_secs(secs),
+                None => usage(program),
+            },
+            other if !other.starts_with('-') => service_type = other.to_string(),
+            _ => usage(program),
+        }
+    }
+
+    if !json {
+        println!(
+            "{:<8} {:<48} {:<28} {:>5}  {:<32} TXT",
+            "EVENT", "INSTANCE", "HOST", "PORT", "ADDRESSES"
+        );
+    }
+
+    let print = |event: DiscoveryEvent| {
+        if json {
+            match serde_json::to_string(&event) {
+                Ok(line) => println!("{}", line),
+                Err(e) => eprintln!("Failed to encode event: {}", e),
+            }
+        } else {
+            print_discovery_row(&event);
+        }
+    };
+
+    if !cached {
+        let timeout = (!watch).then_some(timeout);
+        return discovery::discover(&service_type, timeout, print);
+    }
+    // Ask the running service, which keeps browsing on its own socket
+    let endpoint = control::default_endpoint();
+    if watch {
+        return control::watch(endpoint, Some(&service_type), print);
+    }
+    let response = control::request(endpoint, Request::Browse { service_type })?;
+    if !response.ok {
+        return Err(MdnsError::Service(response.error.unwrap_or_default()));
+    }
+    let services: Vec<DiscoveredService> =
+        serde_json::from_value(response.result.unwrap_or_default())?;
+    services
+        .into_iter()
+        .for_each(|service| print(DiscoveryEvent::Added(service)));
+    Ok(())
+}
+
+fn print_discovery_row(event: &DiscoveryEvent) {
+    match event {
+        DiscoveryEvent::TypeFound { service_type } => {
+            println!("{:<8} {}", "type", service_type);
+        }
+        DiscoveryEvent::Added(service) | DiscoveryEvent::Updated(service) => {
+            let kind = if matches!(event, DiscoveryEvent::Added(_)) {
+                "added"
+            } else {
+                "updated"
+            };
+            let addresses: Vec<String> =
+                service.addresses.iter().map(|ip| ip.to_string()).collect();
+            let txt: Vec<String> = service
+                .txt
+                .iter()
+                .map(|(key, value)| format!("{}={}", key, value))
+                .collect();
+            println!(
+                "{:<8} {:<48} {:<28} {:>5}  {:<32} {}",
+                kind,
+                service.fullname,
+                service.hostname,
+                service.port,
+                addresses.join(","),
+                txt.join(" ")
+            );
+        }
+        DiscoveryEvent::Removed { fullname, .. } => {
+            println!("{:<8} {}", "removed", fullname);
+        }
+    }
+}
+
+fn self_check(program: &str, args: &[String]) -> Result<()> {
+    let mut attach = false;
+    let mut timeout = Duration::from_secs(DEFAULT_SELFCHECK_TIMEOUT_SECS);
+
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--attach" => attach = true,
+            "--timeout" => match args.next().and_then(|secs| secs.parse().ok()) {
+                Some(secs) => timeout = Duration::from_secs(secs),
+                None => usage(program),
+            },
+            _ => usage(program),
+        }
+    }
+
+    let config = mdns_service::load_config(None)?;
+    let report = selfcheck::run(&config, attach, timeout)?;
+
+    if report.passed() {
+        println!("PASS {}", report.expected.fullname);
+        return Ok(());
+    }
+
+    println!("FAIL {}", report.expected.fullname);
+    if report.resolved.is_none() {
+        println!("  not resolved within {:?}", timeout);
+    }
+    for mismatch in &report.mismatches {
+        println!("  {}:", mismatch.field);
+        println!("    - expected {}", mismatch.expected);
+        println!("    + resolved {}", mismatch.actual);
+    }
+    std::process::exit(1);
+}
+
+fn usage(program: &str) -> ! {
+    #[cfg(windows)]
+    let service_cmd = "  firewall-rule [PROFILES]\n               Allow inbound UDP 5353 to this executable in PROFILES\n               (default domain,private), replacing the existing rule\n";
+    #[cfg(unix)]
+    let service_cmd =
+        "  service [--allow-default-config]\n               Run under systemd (Type=notify)\n";
+    #[cfg(windows)]
+    let accounts = "system (LocalSystem, default), service\n               (NT SERVICE\\MDNSResponder) or local-service";
+    #[cfg(windows)]
+    let (install_flags, install_firewall) = (
+        " [--firewall-profiles LIST]",
+        "\n               An inbound firewall rule for UDP 5353 applies in the LIST\n               profiles (default domain,private)",
+    );
+    #[cfg(unix)]
+    let (install_flags, install_firewall) = ("", "");
+    #[cfg(unix)]
+    let accounts = "system (root, default) or service (mdns-responder user)";
+    eprintln!(
+        "Usage: {} <command>
+
+Commands:
+  install [--account ACCOUNT]{}
+               Install and enable the service running as ACCOUNT:
+               {}.
+               Other accounts than system are started once to check
+               that they can bind UDP 5353{}
+  uninstall    Stop and remove the service
+  run [--allow-default-config] [--KEY VALUE]...
+               Advertise in the foreground until Ctrl-C; flags such as
+               --port or --bind-address override the config file. A bad or
+               missing config stops it unless --allow-default-config is given
+{}  discover [TYPE] [--timeout SECS] [--watch] [--json] [--cached]
+               Browse TYPE (default {}); {} lists every type;
+               --cached reads the running service's browse cache instead
+  selfcheck [--attach] [--timeout SECS]
+               Resolve the configured service from the network and compare it
+               with the config file; --attach checks the running service instead
+  status [--json]
+               Show the running service's health and advertised services
+  print-effective-config [--json] [--KEY VALUE]...
+               Show the config merged from defaults, the config file,
+               MDNS_RESPONDER_* environment variables and flags, and where
+               each value came from
+  migrate-config [PATH]
+               Rewrite the config file (default: the installed one) for the
+               current schema version, keeping a .v<N>.bak backup
+  secure-config [PATH]
+               Let only administrators change the config file (default: the
+               installed one) and its directory; configs anyone else can
+               write are refused
+  config-schema
+               Print the JSON Schema of the config file
+  control <status|reload|reannounce|list-services|discovered [TYPE]>
+               Send a raw request to the running service's control channel",
+        program,
+        install_flags,
+        accounts,
+        install_firewall,
+        service_cmd,
+        DEFAULT_DISCOVER_TYPE,
+        discovery::SERVICE_TYPE_ENUMERATION
+    );
+    std::process::exit(1);
+}
@@ -0,0 +1,187 @@
+use crate::config::{ServiceConfig, SystemResponderPolicy};
+use crate::error::{MdnsError, Result};
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+/// Another mDNS responder running on this host.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SystemResponder {
+    /// e.g. `Windows DNS Client (Dnscache)`, `Bonjour Service` or `avahi-daemon`
+    pub name: String,
+    /// Host name it answers for, without `.local`
+    pub hostname: Option<String>,
+}
+
+/// The mDNS responders other than ours running on this host.
+///
+/// Finds the DNS Client's responder (unless `EnableMDNS` is 0) and Bonjour on
+/// Windows, which needs the `windows` feature, and avahi-daemon on Unix.
+pub fn detect() -> Vec<SystemResponder> {
+    imp::detect()
+}
+
+/// Warn about the responders found on this host, or refuse to start when
+/// `config.system_responder` is strict.
+pub(crate) fn check(config: &ServiceConfig) -> Result<()> {
+    check_against(config, &detect())
+}
+
+pub(crate) fn check_against(config: &ServiceConfig, found: &[SystemResponder]) -> Result<()> {
+    if config.system_responder == SystemResponderPolicy::Register {
+        return Ok(());
+    }
+    let ours = config
+        .hostname
+        .trim_end_matches('.')
+        .trim_end_matches(".local");
+    let conflicts: Vec<String> = found
+        .iter()
+        .map(|responder| match &responder.hostname {
+            Some(hostname) if hostname.eq_ignore_ascii_case(ours) => format!(
+                "{} also uses UDP 5353 and answers for {}.local",
+                responder.name, hostname
+            ),
+            _ => format!("{} also uses UDP 5353", responder.name),
+        })
+        .collect();
+    if conflicts.is_empty() {
+        return Ok(());
+    }
+    if config.system_responder == SystemResponderPolicy::Strict {
+        return Err(MdnsError::Coexistence(conflicts.join("; ")));
+    }
+    let hint = if cfg!(all(windows, feature = "windows")) {
+        "; set system_responder to \"register\" to publish through the DNS Client instead"
+    } else if cfg!(all(unix, feature = "avahi")) {
+        "; set system_responder to \"register\" to publish through avahi-daemon instead"
+    } else {
+        ""
+    };
+    for conflict in conflicts {
+        warn!("{}{}", conflict, hint);
+    }
+    Ok(())
+}
+
+/// Whether the Windows service named `service` is running.
+#[cfg(all(windows, feature = "windows"))]
+pub(crate) fn service_running(service: &str) -> bool {
+    imp::running(service)
+}
+
+#[cfg(unix)]
+mod imp {
+    use super::SystemResponder;
+    use std::fs;
+    use std::path::Path;
+
+    const AVAHI_PID_FILE: &str = "/run/avahi-daemon/pid";
+    const AVAHI_CONFIG: &str = "/etc/avahi/avahi-daemon.conf";
+
+    pub(super) fn detect() -> Vec<SystemResponder> {
+        let running = fs::read_to_string(AVAHI_PID_FILE)
+            .ok()
+            .and_then(|pid| pid.trim().parse::<u32>().ok())
+            .is_some_and(|pid| Path::new(&format!("/proc/{}", pid)).exists());
+        if !running {
+            return Vec::new();
+        }
+        // avahi uses the system host name unless its config sets one
+        let hostname = fs::read_to_string(AVAHI_CONFIG)
+            .ok()
+            .and_then(|config| {
+                config.lines().find_map(|line| {
+                    let (key, value) = line.split_once('=')?;
+                    (key.trim() == "host-name").then(|| value.trim().to_string())
+                })
+            })
+            .or_else(|| {
+                fs::read_to_string("/proc/sys/kernel/hostname")
+                    .ok()
+                    .map(|hostname| hostname.trim().to_string())
+            });
+        vec![SystemResponder {
+            name: "avahi-daemon".to_string(),
+            hostname,
+        }]
+    }
+}
+
+#[cfg(all(windows, feature = "windows"))]
+mod imp {
+    use super::SystemResponder;
+    use windows::Win32::Foundation::ERROR_SUCCESS;
+    use windows::Win32::System::Registry::{HKEY_LOCAL_MACHINE, RRF_RT_REG_DWORD, RegGetValueW};
+    use windows::Win32::System::SystemInformation::{ComputerNameDnsHostname, GetComputerNameExW};
+    use windows::core::{PWSTR, w};
+    use windows_service::service::{ServiceAccess, ServiceState};
+    use windows_service::service_manager::{ServiceManager, ServiceManagerAccess};
+
+    pub(super) fn detect() -> Vec<SystemResponder> {
+        let hostname = dns_hostname();
+        let mut found = Vec::new();
+        if running("Dnscache") && dnscache_mdns_enabled() {
+            found.push(SystemResponder {
+                name: "Windows DNS Client (Dnscache)".to_string(),
+                hostname: hostname.clone(),
+            });
+        }
+        if running("Bonjour Service") {
+            found.push(SystemResponder {
+                name: "Bonjour Service".to_string(),
+                hostname,
+            });
+        }
+        found
+    }
+
+    pub(super) fn running(service: &str) -> bool {
+        ServiceManager::local_computer(None::<&str>, ServiceManagerAccess::CONNECT)
+            .and_then(|manager| manager.open_service(service, ServiceAccess::QUERY_STATUS))
+            .and_then(|service| service.query_status())
+            .is_ok_and(|status| status.current_state == ServiceState::Running)
+    }
+
+    /// Whether the DNS Client answers mDNS; it does unless `EnableMDNS` is 0.
+    fn dnscache_mdns_enabled() -> bool {
+        let mut value = 0u32;
+        let mut size = size_of::<u32>() as u32;
+        // SAFETY: the buffer is a u32 and `size` says so.
+        let status = unsafe {
+            RegGetValueW(
+                HKEY_LOCAL_MACHINE,
+                w!(r"SYSTEM\CurrentControlSet\Services\Dnscache\Parameters"),
+                w!("EnableMDNS"),
+                RRF_RT_REG_DWORD,
+                None,
+                Some((&mut value as *mut u32).cast()),
+                Some(&mut size),
+            )
+        };
+        status != ERROR_SUCCESS || value != 0
+    }
+
+    fn dns_hostname() -> Option<String> {
+        let mut buffer = [0u16; 256];
+        let mut len = buffer.len() as u32;
+        // SAFETY: `len` is the buffer's length in characters.
+        unsafe {
+            GetComputerNameExW(
+                ComputerNameDnsHostname,
+                Some(PWSTR(buffer.as_mut_ptr())),
+                &mut len,
+            )
+        }
+        .ok()?;
+        Some(String::from_utf16_lossy(&buffer[..len as usize]))
+    }
+}
+
+#[cfg(all(windows, not(feature = "windows")))]
+mod imp {
+    use super::SystemResponder;
+
+    pub(super) fn detect() -> Vec<SystemResponder> {
+        Vec::new()
+    }
+}
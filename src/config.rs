@@ -1,10 +1,46 @@
 use crate::error::{MdnsError, Result};
+use crate::permissions;
+use log::warn;
+use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
 use std::fs;
-use std::path::PathBuf;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
+/// Current config schema version, written to the `version` key.
+///
+/// Files without the key predate versioning and are read as version 0.
+pub const CONFIG_VERSION: u32 = 1;
+pub const DEFAULT_SHUTDOWN_TIMEOUT_SECS: u64 = 5;
+const MAX_SHUTDOWN_TIMEOUT_SECS: u64 = 300;
+const MIN_ANNOUNCEMENTS: u32 = 2;
+const MAX_ANNOUNCEMENTS: u32 = 8;
+const MIN_REANNOUNCE_INTERVAL_SECS: u64 = 60;
+const MAX_REANNOUNCE_INTERVAL_SECS: u64 = 86_400;
+/// RFC 6762 section 10 recommendations for host and other records
+const DEFAULT_HOST_TTL_SECS: u32 = 120;
+const DEFAULT_OTHER_TTL_SECS: u32 = 4_500;
+const MIN_RECORD_TTL_SECS: u32 = 10;
+const MAX_RECORD_TTL_SECS: u32 = 86_400;
+const DEFAULT_WIDE_AREA_TTL_SECS: u32 = 3_600;
+#[cfg(feature = "wide-area")]
+const MIN_WIDE_AREA_TTL_SECS: u32 = 60;
+#[cfg(feature = "wide-area")]
+const MAX_WIDE_AREA_TTL_SECS: u32 = 86_400;
+const DEFAULT_WIDE_AREA_REFRESH_SECS: u64 = 1_800;
+const DNS_PORT: u16 = 53;
+const DEFAULT_LOG_FILE_SIZE_MB: u64 = 10;
+const DEFAULT_LOG_FILES: u32 = 5;
+
+/// One advertised service, as read from the config file.
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct ServiceConfig {
+    /// Schema version the file was written for; files without it are version 0
+    #[serde(default = "default_config_version")]
+    pub version: u32,
     pub service_name: String,
     pub instance_name: String,
     pub port: u16,
@@ -14,18 +50,315 @@ pub struct ServiceConfig {
     pub shares: Vec<ShareConfig>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub bind_address: Option<String>,
+    /// Interface names, addresses or CIDR subnets to advertise on; empty means all
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub interfaces: Vec<String>,
+    /// How long shutdown may take to send goodbyes and stop the daemon
+    #[serde(default = "default_shutdown_timeout_secs")]
+    pub shutdown_timeout_secs: u64,
+    /// Serve Prometheus metrics over HTTP on this address, e.g. `127.0.0.1:9464`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub metrics_address: Option<String>,
+    /// Let `metrics_address` be an address other hosts can reach; it must be
+    /// a loopback address otherwise
+    #[serde(default)]
+    pub metrics_allow_remote: bool,
+    #[serde(default)]
+    pub announce: AnnounceConfig,
+    /// Relay mDNS traffic between interfaces; read from the primary config only
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub reflector: Option<ReflectorConfig>,
+    /// Service types to keep browsing and cache for control-channel clients;
+    /// read from the primary config only
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub browse: Vec<String>,
+    /// Also publish the service to a unicast DNS server
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wide_area: Option<WideAreaConfig>,
+    /// What to do when another mDNS responder runs on this host; read from
+    /// the primary config at startup only
+    #[serde(default)]
+    pub system_responder: SystemResponderPolicy,
+    #[serde(default)]
+    pub logging: LoggingConfig,
+}
+
+fn default_config_version() -> u32 {
+    CONFIG_VERSION
+}
+
+fn default_shutdown_timeout_secs() -> u64 {
+    DEFAULT_SHUTDOWN_TIMEOUT_SECS
+}
+
+/// How a service claims its name and announces itself, and how long others
+/// may cache its records.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(default)]
+pub struct AnnounceConfig {
+    /// Probe for name conflicts before announcing (RFC 6762 section 8.1)
+    pub probe: bool,
+    /// Unsolicited announcements after registering, 2-8 (RFC 6762 section 8.3).
+    /// Past the first pair they go out in pairs, 3 seconds after the previous
+    /// pair with the gap doubling each time, so odd counts round up.
+    pub announcements: u32,
+    /// Announce again every this many seconds; 0 turns it off
+    pub reannounce_interval_secs: u64,
+    /// TTL of the SRV and A/AAAA records, 10-86400 seconds. Shorter values let
+    /// caches drop a roaming host's old address sooner
+    pub host_ttl_secs: u32,
+    /// TTL of the PTR and TXT records, 10-86400 seconds
+    pub other_ttl_secs: u32,
+}
+
+impl Default for AnnounceConfig {
+    fn default() -> Self {
+        Self {
+            probe: true,
+            announcements: MIN_ANNOUNCEMENTS,
+            reannounce_interval_secs: 0,
+            host_ttl_secs: DEFAULT_HOST_TTL_SECS,
+            other_ttl_secs: DEFAULT_OTHER_TTL_SECS,
+        }
+    }
+}
+
+impl AnnounceConfig {
+    /// Announcement pairs beyond the first, which `mdns-sd` sends itself.
+    pub fn extra_rounds(&self) -> u32 {
+        self.announcements.div_ceil(2).saturating_sub(1)
+    }
+
+    pub fn reannounce_interval(&self) -> Option<Duration> {
+        (self.reannounce_interval_secs > 0)
+            .then(|| Duration::from_secs(self.reannounce_interval_secs))
+    }
+}
+
+/// Reflector (gateway) mode relaying mDNS between interfaces, e.g. VLANs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct ReflectorConfig {
+    /// Interfaces to relay between, as names, addresses or CIDR subnets like
+    /// `interfaces`; they must cover at least two IPv4 subnets
+    pub interfaces: Vec<String>,
+    /// Service types to relay, e.g. `_smb._tcp.local.`; empty relays every type
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub service_types: Vec<String>,
+}
+
+/// Wide-area DNS-SD publishing (RFC 6763) through dynamic updates (RFC 2136).
+///
+/// The same PTR, SRV, TXT and address records advertised over mDNS are
+/// published under `zone` instead of `local.`, refreshed periodically and
+/// removed when the service is.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct WideAreaConfig {
+    /// Server accepting updates for `zone`, as `address` or `address:port`
+    pub server: String,
+    /// Domain to publish under, e.g. `example.com.`
+    pub zone: String,
+    #[serde(default = "default_wide_area_ttl_secs")]
+    pub ttl_secs: u32,
+    /// Publish again every this many seconds; 0 turns it off
+    #[serde(default = "default_wide_area_refresh_secs")]
+    pub refresh_interval_secs: u64,
+    /// Sign updates with this key; servers usually require it
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tsig: Option<TsigConfig>,
+}
+
+fn default_wide_area_ttl_secs() -> u32 {
+    DEFAULT_WIDE_AREA_TTL_SECS
+}
+
+fn default_wide_area_refresh_secs() -> u64 {
+    DEFAULT_WIDE_AREA_REFRESH_SECS
+}
+
+impl WideAreaConfig {
+    /// `server` with the DNS port filled in when it has none.
+    pub fn server_address(&self) -> Result<SocketAddr> {
+        self.server
+            .parse::<SocketAddr>()
+            .or_else(|_| {
+                self.server
+                    .parse::<IpAddr>()
+                    .map(|ip| SocketAddr::new(ip, DNS_PORT))
+            })
+            .map_err(|_| {
+                MdnsError::ConfigValidation(format!(
+                    "wide_area.server '{}' is not an IP address with optional port",
+                    self.server
+                ))
+            })
+    }
+
+    pub fn refresh_interval(&self) -> Option<Duration> {
+        (self.refresh_interval_secs > 0).then(|| Duration::from_secs(self.refresh_interval_secs))
+    }
+}
+
+/// TSIG key shared with the DNS server (RFC 8945), as in a BIND `key` statement.
+#[derive(Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+pub struct TsigConfig {
+    pub key_name: String,
+    /// `hmac-sha256` or `hmac-sha512`
+    #[serde(default = "default_tsig_algorithm")]
+    pub algorithm: String,
+    /// Base64-encoded shared secret
+    pub secret: String,
+}
+
+fn default_tsig_algorithm() -> String {
+    "hmac-sha256".to_string()
+}
+
+// Keeps the secret out of logged configs
+impl std::fmt::Debug for TsigConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("TsigConfig")
+            .field("key_name", &self.key_name)
+            .field("algorithm", &self.algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+/// How to share the host with another mDNS responder, such as the one in
+/// Windows' DNS Client (Dnscache) or Bonjour, which also use UDP 5353.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(rename_all = "kebab-case")]
+pub enum SystemResponderPolicy {
+    /// Log a warning for each one found and run our own daemon anyway
+    #[default]
+    Warn,
+    /// Refuse to start while another responder is running
+    Strict,
+    /// Register through the system responder instead of running a daemon;
+    /// `DnsServiceRegister` on Windows, avahi-daemon over D-Bus on Linux
+    Register,
+}
+
+/// Where the service hosts send log output. Read once at startup.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
+#[serde(default)]
+pub struct LoggingConfig {
+    /// `off`, `error`, `warn`, `info`, `debug` or `trace`; `RUST_LOG` overrides it
+    pub level: String,
+    /// Write to the Windows Event Log under the service's event source
+    pub event_log: bool,
+    /// Also append to this file, rotating it by size
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub file: Option<PathBuf>,
+    pub max_file_size_mb: u64,
+    /// Rotated files kept next to `file` as `file.1` .. `file.N`
+    pub max_files: u32,
+}
+
+impl Default for LoggingConfig {
+    fn default() -> Self {
+        Self {
+            level: "info".to_string(),
+            event_log: true,
+            file: None,
+            max_file_size_mb: DEFAULT_LOG_FILE_SIZE_MB,
+            max_files: DEFAULT_LOG_FILES,
+        }
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[cfg_attr(feature = "schema", derive(schemars::JsonSchema))]
 pub struct ShareConfig {
     pub name: String,
     pub path: String,
     pub comment: String,
 }
 
+/// One entry of [`ServiceConfig::interfaces`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum InterfaceScope {
+    /// Interface name such as `eth0` or `Ethernet 2`
+    Name(String),
+    /// Subnet such as `10.1.0.0/16`; a bare address is a single-host subnet
+    Subnet { network: IpAddr, prefix_len: u8 },
+}
+
+impl InterfaceScope {
+    pub fn parse(entry: &str) -> Result<Self> {
+        let entry = entry.trim();
+        if entry.is_empty() {
+            return Err(MdnsError::ConfigValidation(
+                "interfaces entries cannot be empty".to_string(),
+            ));
+        }
+
+        let (addr, prefix_len) = match entry.split_once('/') {
+            Some((addr, prefix_len)) => (addr, Some(prefix_len)),
+            None => (entry, None),
+        };
+        let Ok(network) = addr.parse::<IpAddr>() else {
+            if prefix_len.is_some() {
+                return Err(MdnsError::ConfigValidation(format!(
+                    "interfaces entry '{}' is not a valid CIDR subnet",
+                    entry
+                )));
+            }
+            return Ok(InterfaceScope::Name(entry.to_string()));
+        };
+
+        let max_len = if network.is_ipv4() { 32 } else { 128 };
+        let prefix_len = match prefix_len.map(str::parse::<u8>) {
+            None => max_len,
+            Some(Ok(len)) if len <= max_len => len,
+            Some(_) => {
+                return Err(MdnsError::ConfigValidation(format!(
+                    "interfaces entry '{}' has an invalid prefix length",
+                    entry
+                )));
+            }
+        };
+        Ok(InterfaceScope::Subnet {
+            network,
+            prefix_len,
+        })
+    }
+
+    /// Whether the local interface `name` with address `ip` is in scope.
+    pub fn matches(&self, name: &str, ip: IpAddr) -> bool {
+        match self {
+            InterfaceScope::Name(scope) => scope.eq_ignore_ascii_case(name),
+            InterfaceScope::Subnet {
+                network,
+                prefix_len,
+            } => match (network, ip) {
+                (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                    let mask = u32::MAX
+                        .checked_shl(32 - u32::from(*prefix_len))
+                        .unwrap_or(0);
+                    u32::from(*network) & mask == u32::from(ip) & mask
+                }
+                (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                    let mask = u128::MAX
+                        .checked_shl(128 - u32::from(*prefix_len))
+                        .unwrap_or(0);
+                    u128::from(*network) & mask == u128::from(ip) & mask
+                }
+                _ => false,
+            },
+        }
+    }
+}
+
 impl Default for ServiceConfig {
     fn default() -> Self {
         Self {
+            version: CONFIG_VERSION,
             service_name: "_smb._tcp.local.".to_string(),
             instance_name: "Windows-Share".to_string(),
             port: 445,
@@ -38,35 +371,521 @@ impl Default for ServiceConfig {
                 comment: "Public shared folder".to_string(),
             }],
             bind_address: None,
+            interfaces: Vec::new(),
+            shutdown_timeout_secs: DEFAULT_SHUTDOWN_TIMEOUT_SECS,
+            metrics_address: None,
+            metrics_allow_remote: false,
+            announce: AnnounceConfig::default(),
+            reflector: None,
+            browse: Vec::new(),
+            wide_area: None,
+            system_responder: SystemResponderPolicy::default(),
+            logging: LoggingConfig::default(),
         }
     }
 }
 
+/// Config file syntax, picked from the file extension.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Json,
+    Toml,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Format of `path`: `.json`, `.toml`, or `.yaml`/`.yml`.
+    pub fn from_path(path: &Path) -> Result<Self> {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Ok(ConfigFormat::Json),
+            Some("toml") => Ok(ConfigFormat::Toml),
+            Some("yaml" | "yml") => Ok(ConfigFormat::Yaml),
+            _ => Err(MdnsError::ConfigValidation(format!(
+                "{} must end in .json, .toml or .yaml",
+                path.display()
+            ))),
+        }
+    }
+
+    /// Parse `content` read from `path`, reporting syntax errors by line and
+    /// column. Configs written for an older schema are migrated in memory.
+    pub fn parse(self, path: &Path, content: &str) -> Result<ServiceConfig> {
+        let mut value: serde_json::Value = self.deserialize(path, content)?;
+        let version = migrate(&mut value)
+            .map_err(|e| locate_error(e, Some((path, content)), &ConfigOverrides::default()))?;
+        if version == CONFIG_VERSION {
+            // Straight from the text so type errors keep their position
+            return self.deserialize(path, content);
+        }
+        warn!(
+            "{} is config version {}; run migrate-config to update it to {}",
+            path.display(),
+            version,
+            CONFIG_VERSION
+        );
+        serde_json::from_value(value).map_err(|e| {
+            // The text usually has the same problem, and there it has a position
+            match self.deserialize::<ServiceConfig>(path, content) {
+                Err(located) => located,
+                Ok(_) => MdnsError::ConfigValidation(format!("{}: {}", path.display(), e)),
+            }
+        })
+    }
+
+    fn deserialize<T: DeserializeOwned>(self, path: &Path, content: &str) -> Result<T> {
+        let (line, column, message) = match self {
+            ConfigFormat::Json => match serde_json::from_str(content) {
+                Ok(value) => return Ok(value),
+                Err(e) => (e.line(), e.column(), without_location(&e.to_string())),
+            },
+            #[cfg(not(feature = "toml"))]
+            ConfigFormat::Toml => return Err(unsupported("TOML", "toml")),
+            #[cfg(feature = "toml")]
+            ConfigFormat::Toml => match toml::from_str(content) {
+                Ok(value) => return Ok(value),
+                Err(e) => {
+                    let (line, column) = e
+                        .span()
+                        .map(|span| line_column(content, span.start))
+                        .unwrap_or((1, 1));
+                    (line, column, e.message().to_string())
+                }
+            },
+            #[cfg(not(feature = "yaml"))]
+            ConfigFormat::Yaml => return Err(unsupported("YAML", "yaml")),
+            #[cfg(feature = "yaml")]
+            ConfigFormat::Yaml => match serde_norway::from_str(content) {
+                Ok(value) => return Ok(value),
+                Err(e) => {
+                    let (line, column) = e
+                        .location()
+                        .map(|location| (location.line(), location.column()))
+                        .unwrap_or((1, 1));
+                    (line, column, without_location(&e.to_string()))
+                }
+            },
+        };
+        Err(MdnsError::ConfigSyntax {
+            path: path.to_path_buf(),
+            line,
+            column,
+            message,
+        })
+    }
+
+    /// Keys set at the top level of `content` read from `path`.
+    fn top_level_keys(self, path: &Path, content: &str) -> Vec<String> {
+        self.deserialize::<serde_json::Value>(path, content)
+            .ok()
+            .as_ref()
+            .and_then(serde_json::Value::as_object)
+            .map(|object| object.keys().cloned().collect())
+            .unwrap_or_default()
+    }
+
+    /// `content` with a `version` line added, if that is all it takes to
+    /// turn `original` into `migrated`. JSON has no comments to keep, so it
+    /// is always rendered anew.
+    fn with_version(
+        self,
+        path: &Path,
+        content: &str,
+        original: &serde_json::Value,
+        migrated: &serde_json::Value,
+    ) -> Option<String> {
+        let version = migrated.get("version")?;
+        if original.get("version").is_some() {
+            return None;
+        }
+        let mut expected = original.clone();
+        expected
+            .as_object_mut()?
+            .insert("version".to_string(), version.clone());
+        if expected != *migrated {
+            return None;
+        }
+        let line = match self {
+            ConfigFormat::Json => return None,
+            ConfigFormat::Toml => format!("version = {}\n", version),
+            ConfigFormat::Yaml => format!("version: {}\n", version),
+        };
+
+        // After the leading comments, e.g. a schema reference, and YAML's
+        // document marker
+        let mut offset = 0;
+        for text in content.split_inclusive('\n') {
+            let trimmed = text.trim();
+            if !(trimmed.is_empty() || trimmed.starts_with('#') || trimmed == "---") {
+                break;
+            }
+            offset += text.len();
+        }
+        let mut updated = content.to_string();
+        if offset == content.len() && !content.is_empty() && !content.ends_with('\n') {
+            updated.push('\n');
+            offset += 1;
+        }
+        updated.insert_str(offset, &line);
+        // Anything unusual, such as a YAML flow mapping, is rendered instead
+        let parsed: serde_json::Value = self.deserialize(path, &updated).ok()?;
+        (parsed == *migrated).then_some(updated)
+    }
+
+    /// Serialize `config` in this format.
+    pub fn render(self, config: &ServiceConfig) -> Result<String> {
+        match self {
+            ConfigFormat::Json => Ok(serde_json::to_string_pretty(config)?),
+            #[cfg(feature = "toml")]
+            ConfigFormat::Toml => toml::to_string_pretty(config)
+                .map_err(|e| MdnsError::ConfigValidation(format!("cannot write TOML: {}", e))),
+            #[cfg(feature = "yaml")]
+            ConfigFormat::Yaml => serde_norway::to_string(config)
+                .map_err(|e| MdnsError::ConfigValidation(format!("cannot write YAML: {}", e))),
+            #[cfg(not(feature = "toml"))]
+            ConfigFormat::Toml => Err(unsupported("TOML", "toml")),
+            #[cfg(not(feature = "yaml"))]
+            ConfigFormat::Yaml => Err(unsupported("YAML", "yaml")),
+        }
+    }
+}
+
+/// Error for a config format this build can neither read nor write.
+#[cfg(not(all(feature = "toml", feature = "yaml")))]
+fn unsupported(format: &str, feature: &str) -> MdnsError {
+    MdnsError::ConfigValidation(format!(
+        "{} config files need a build with the {} feature",
+        format, feature
+    ))
+}
+
+/// Upgrades from each older schema version, indexed by that version
+const MIGRATIONS: [fn(&mut serde_json::Map<String, serde_json::Value>); CONFIG_VERSION as usize] =
+    [from_v0];
+
+/// Version 0 files predate the `version` key and otherwise match version 1.
+fn from_v0(_config: &mut serde_json::Map<String, serde_json::Value>) {}
+
+/// Upgrade a parsed config to [`CONFIG_VERSION`] in place and return the
+/// version it was written for.
+pub fn migrate(value: &mut serde_json::Value) -> Result<u32> {
+    let config = value.as_object_mut().ok_or_else(|| {
+        MdnsError::ConfigValidation("the config must be a table of settings".to_string())
+    })?;
+    let version = match config.get("version") {
+        None => 0,
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or_else(|| {
+                MdnsError::ConfigValidation(format!(
+                    "version must be a whole number, not {}",
+                    version
+                ))
+            })?,
+    };
+    if version > CONFIG_VERSION {
+        return Err(MdnsError::ConfigValidation(format!(
+            "version {} is newer than this release supports ({})",
+            version, CONFIG_VERSION
+        )));
+    }
+
+    for (from, migration) in MIGRATIONS.iter().enumerate().skip(version as usize) {
+        migration(config);
+        config.insert("version".to_string(), (from + 1).into());
+    }
+    Ok(version)
+}
+
+/// JSON Schema of the config file, for editor validation.
+#[cfg(feature = "schema")]
+pub fn json_schema() -> serde_json::Value {
+    schemars::schema_for!(ServiceConfig).to_value()
+}
+
+/// Prefix of environment variables overriding config keys, e.g. `MDNS_RESPONDER_PORT`.
+pub const ENV_PREFIX: &str = "MDNS_RESPONDER_";
+
+/// Top-level keys that environment variables and command-line flags may set.
+pub const OVERRIDABLE_KEYS: &[&str] = &[
+    "service_name",
+    "instance_name",
+    "port",
+    "hostname",
+    "workgroup",
+    "description",
+    "bind_address",
+    "shutdown_timeout_secs",
+    "metrics_address",
+    "system_responder",
+];
+
+/// Where a config value came from.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigSource {
+    Default,
+    File(PathBuf),
+    /// Environment variable name
+    Env(String),
+    /// Command-line flag
+    Cli(String),
+}
+
+impl std::fmt::Display for ConfigSource {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigSource::Default => write!(f, "default"),
+            ConfigSource::File(path) => write!(f, "file {}", path.display()),
+            ConfigSource::Env(name) => write!(f, "env {}", name),
+            ConfigSource::Cli(flag) => write!(f, "flag {}", flag),
+        }
+    }
+}
+
+impl Serialize for ConfigSource {
+    fn serialize<S: serde::Serializer>(
+        &self,
+        serializer: S,
+    ) -> std::result::Result<S::Ok, S::Error> {
+        serializer.collect_str(self)
+    }
+}
+
+/// Values layered over the config file; later ones win.
+///
+/// Numeric keys take numbers, an empty value clears an optional key such as
+/// `bind_address`.
+#[derive(Debug, Clone, Default)]
+pub struct ConfigOverrides {
+    values: Vec<(String, String, ConfigSource)>,
+}
+
+impl ConfigOverrides {
+    /// Overrides from the `MDNS_RESPONDER_*` variables in `vars`, such as
+    /// `std::env::vars()`. Unknown names under the prefix are rejected.
+    pub fn from_env(vars: impl IntoIterator<Item = (String, String)>) -> Result<Self> {
+        let mut vars: Vec<(String, String)> = vars
+            .into_iter()
+            .filter(|(name, _)| name.starts_with(ENV_PREFIX))
+            .collect();
+        vars.sort();
+
+        let mut overrides = Self::default();
+        for (name, value) in vars {
+            let key = name[ENV_PREFIX.len()..].to_ascii_lowercase();
+            overrides.set(&key, &value, ConfigSource::Env(name))?;
+        }
+        Ok(overrides)
+    }
+
+    /// Add a command-line override such as `--bind-address 10.0.0.5`.
+    pub fn set_flag(&mut self, flag: &str, value: &str) -> Result<()> {
+        let key = flag.trim_start_matches('-').replace('-', "_");
+        self.set(&key, value, ConfigSource::Cli(flag.to_string()))
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// Apply `other` after these overrides.
+    pub fn extend(&mut self, other: &ConfigOverrides) {
+        self.values.extend(other.values.iter().cloned());
+    }
+
+    fn set(&mut self, key: &str, value: &str, source: ConfigSource) -> Result<()> {
+        if !OVERRIDABLE_KEYS.contains(&key) {
+            return Err(MdnsError::ConfigValidation(format!(
+                "{}: '{}' cannot be overridden; use one of {}",
+                source,
+                key,
+                OVERRIDABLE_KEYS.join(", ")
+            )));
+        }
+        self.values
+            .push((key.to_string(), value.to_string(), source));
+        Ok(())
+    }
+
+    /// Where the last override of `key` came from.
+    fn source_of(&self, key: &str) -> Option<&ConfigSource> {
+        self.values
+            .iter()
+            .rev()
+            .find(|(overridden, _, _)| overridden == key)
+            .map(|(_, _, source)| source)
+    }
+
+    /// Layer defaults, the config file at `path` if it exists, then these
+    /// overrides, and validate the result.
+    pub fn resolve(&self, path: &Path) -> Result<ResolvedConfig> {
+        let (content, config, file_keys) = if path.exists() {
+            let format = ConfigFormat::from_path(path)?;
+            let content = read_config(path)?;
+            let config = format.parse(path, &content)?;
+            let keys = format.top_level_keys(path, &content);
+            (Some(content), config, keys)
+        } else {
+            (None, ServiceConfig::default(), Vec::new())
+        };
+
+        let mut value = serde_json::to_value(&config)?;
+        for (key, raw, source) in &self.values {
+            let current = value.get(key.as_str());
+            let new = if raw.is_empty() && current.is_none_or(|current| !current.is_number()) {
+                serde_json::Value::Null
+            } else if current.is_some_and(|current| current.is_number()) {
+                let number: u64 = raw.parse().map_err(|_| {
+                    MdnsError::ConfigValidation(format!("{}: '{}' is not a number", source, raw))
+                })?;
+                number.into()
+            } else {
+                raw.clone().into()
+            };
+            value[key.as_str()] = new;
+            // Type errors such as an out-of-range port belong to this override
+            serde_json::from_value::<ServiceConfig>(value.clone())
+                .map_err(|e| MdnsError::ConfigValidation(format!("{}: {}", source, e)))?;
+        }
+        let config: ServiceConfig = serde_json::from_value(value.clone())?;
+
+        config.validate().map_err(|e| {
+            locate_error(e, content.as_deref().map(|content| (path, content)), self)
+        })?;
+
+        let mut sources = BTreeMap::new();
+        let keys = value
+            .as_object()
+            .into_iter()
+            .flat_map(|object| object.keys())
+            .map(String::as_str)
+            .chain(OVERRIDABLE_KEYS.iter().copied());
+        for key in keys {
+            let source = match self.source_of(key) {
+                Some(source) => source.clone(),
+                None if file_keys.iter().any(|file_key| file_key == key) => {
+                    ConfigSource::File(path.to_path_buf())
+                }
+                None => ConfigSource::Default,
+            };
+            sources.insert(key.to_string(), source);
+        }
+        Ok(ResolvedConfig { config, sources })
+    }
+}
+
+/// A config merged from defaults, file, environment and command line.
+#[derive(Debug, Clone)]
+pub struct ResolvedConfig {
+    pub config: ServiceConfig,
+    /// Where each top-level key's value came from
+    pub sources: BTreeMap<String, ConfigSource>,
+}
+
 impl ServiceConfig {
-    pub fn from_file(path: &PathBuf) -> Result<Self> {
-        let content = fs::read_to_string(path)?;
-        let config: ServiceConfig = serde_json::from_str(&content)?;
-        config.validate()?;
+    /// Load and validate a JSON, TOML or YAML config, picked by extension.
+    ///
+    /// Validation errors name the line and column of the offending key when
+    /// it appears in the file.
+    pub fn from_file(path: &Path) -> Result<Self> {
+        let format = ConfigFormat::from_path(path)?;
+        let content = read_config(path)?;
+        let config = format.parse(path, &content)?;
+        config
+            .validate()
+            .map_err(|e| locate_error(e, Some((path, &content)), &ConfigOverrides::default()))?;
         Ok(config)
     }
 
+    /// The config as JSON with secrets, such as the TSIG key, replaced by
+    /// `"<redacted>"`.
+    pub fn to_redacted_value(&self) -> Result<serde_json::Value> {
+        let mut value = serde_json::to_value(self)?;
+        if let Some(secret) = value.pointer_mut("/wide_area/tsig/secret") {
+            *secret = "<redacted>".into();
+        }
+        Ok(value)
+    }
+
+    /// Rewrite the config at `path` for the current schema, keeping the
+    /// original as `<path>.v<N>.bak`.
+    ///
+    /// When only `version` changes, it is added to the text as it stands so
+    /// TOML and YAML comments survive; other migrations write the file anew.
+    ///
+    /// Returns the backup's path, or `None` when the file was already current.
+    pub fn migrate_file(path: &Path) -> Result<Option<PathBuf>> {
+        let format = ConfigFormat::from_path(path)?;
+        let content = read_config(path)?;
+        let original: serde_json::Value = format.deserialize(path, &content)?;
+        let mut value = original.clone();
+        let located = |e| locate_error(e, Some((path, &content)), &ConfigOverrides::default());
+        let version = migrate(&mut value).map_err(located)?;
+        if version == CONFIG_VERSION {
+            return Ok(None);
+        }
+        let config: ServiceConfig = serde_json::from_value(value.clone())
+            .map_err(|e| MdnsError::ConfigValidation(format!("{}: {}", path.display(), e)))?;
+        config.validate().map_err(located)?;
+
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(format!(".v{}.bak", version));
+        let backup = PathBuf::from(backup);
+        let migrated = match format.with_version(path, &content, &original, &value) {
+            Some(migrated) => migrated,
+            None => {
+                if format != ConfigFormat::Json {
+                    warn!(
+                        "{} is rewritten without its comments; {} keeps them",
+                        path.display(),
+                        backup.display()
+                    );
+                }
+                format.render(&config)?
+            }
+        };
+        fs::copy(path, &backup)?;
+        fs::write(path, migrated)?;
+        Ok(Some(backup))
+    }
+
+    /// Validate and write the config in the format its extension names.
     pub fn save_to_file(&self, path: &PathBuf) -> Result<()> {
+        let format = ConfigFormat::from_path(path)?;
         self.validate()?;
-        let content = serde_json::to_string_pretty(self)?;
-        fs::write(path, content)?;
+        fs::write(path, format.render(self)?)?;
         Ok(())
     }
 
+    /// Default config file: the first of `config.json`, `config.toml`,
+    /// `config.yaml` and `config.yml` that exists, else `config.json`.
     pub fn config_path() -> PathBuf {
-        if cfg!(target_os = "windows") {
-            PathBuf::from("C:\\ProgramData\\MDNSResponder\\config.json")
+        let dir = if cfg!(target_os = "windows") {
+            Path::new("C:\\ProgramData\\MDNSResponder")
         } else {
-            PathBuf::from("/etc/mdns-responder/config.json")
-        }
+            Path::new("/etc/mdns-responder")
+        };
+        ["config.json", "config.toml", "config.yaml", "config.yml"]
+            .iter()
+            .map(|name| dir.join(name))
+            .find(|path| path.exists())
+            .unwrap_or_else(|| dir.join("config.json"))
+    }
+
+    pub fn shutdown_timeout(&self) -> Duration {
+        Duration::from_secs(self.shutdown_timeout_secs)
+    }
+
+    /// Parsed `interfaces` entries.
+    pub fn interface_scopes(&self) -> Result<Vec<InterfaceScope>> {
+        self.interfaces
+            .iter()
+            .map(|entry| InterfaceScope::parse(entry))
+            .collect()
     }
 
     /// Validate configuration values
-    fn validate(&self) -> Result<()> {
+    pub fn validate(&self) -> Result<()> {
         // Validate service name format (must end with .local.)
         if !self.service_name.ends_with(".local.") {
             return Err(MdnsError::ConfigValidation(
@@ -100,19 +919,174 @@ impl ServiceConfig {
             ));
         }
 
-        // Validate hostname format (basic DNS compliance)
-        if !self
+        // Validate hostname format (basic DNS compliance, optional .local suffix)
+        let host_label = self
             .hostname
-            .chars()
-            .all(|c| c.is_alphanumeric() || c == '-')
-            || self.hostname.starts_with('-')
-            || self.hostname.ends_with('-')
+            .strip_suffix(".local.")
+            .or_else(|| self.hostname.strip_suffix(".local"))
+            .unwrap_or(&self.hostname);
+        if host_label.is_empty()
+            || !host_label.chars().all(|c| c.is_alphanumeric() || c == '-')
+            || host_label.starts_with('-')
+            || host_label.ends_with('-')
         {
             return Err(MdnsError::ConfigValidation(
                 "hostname must contain only alphanumeric characters and hyphens".to_string(),
             ));
         }
 
+        // Validate shutdown timeout leaves room for goodbyes without hanging the host
+        if self.shutdown_timeout_secs == 0 || self.shutdown_timeout_secs > MAX_SHUTDOWN_TIMEOUT_SECS
+        {
+            return Err(MdnsError::ConfigValidation(format!(
+                "shutdown_timeout_secs must be between 1 and {}",
+                MAX_SHUTDOWN_TIMEOUT_SECS
+            )));
+        }
+
+        // Validate metrics address is a loopback socket address unless opted out
+        if let Some(address) = &self.metrics_address {
+            match address.parse::<std::net::SocketAddr>() {
+                Err(_) => {
+                    return Err(MdnsError::ConfigValidation(format!(
+                        "metrics_address '{}' is not a valid socket address",
+                        address
+                    )));
+                }
+                Ok(address) if !address.ip().is_loopback() && !self.metrics_allow_remote => {
+                    return Err(MdnsError::ConfigValidation(format!(
+                        "metrics_address '{}' is reachable from other hosts; use a loopback \
+                         address or set metrics_allow_remote",
+                        address
+                    )));
+                }
+                Ok(_) => {}
+            }
+        }
+
+        // Validate interface scoping entries
+        self.interface_scopes()?;
+
+        // Validate announcement pacing (RFC 6762 section 8.3)
+        if !(MIN_ANNOUNCEMENTS..=MAX_ANNOUNCEMENTS).contains(&self.announce.announcements) {
+            return Err(MdnsError::ConfigValidation(format!(
+                "announce.announcements must be between {} and {}",
+                MIN_ANNOUNCEMENTS, MAX_ANNOUNCEMENTS
+            )));
+        }
+        let interval = self.announce.reannounce_interval_secs;
+        if interval != 0
+            && !(MIN_REANNOUNCE_INTERVAL_SECS..=MAX_REANNOUNCE_INTERVAL_SECS).contains(&interval)
+        {
+            return Err(MdnsError::ConfigValidation(format!(
+                "announce.reannounce_interval_secs must be 0 or between {} and {}",
+                MIN_REANNOUNCE_INTERVAL_SECS, MAX_REANNOUNCE_INTERVAL_SECS
+            )));
+        }
+        for (key, ttl) in [
+            ("host_ttl_secs", self.announce.host_ttl_secs),
+            ("other_ttl_secs", self.announce.other_ttl_secs),
+        ] {
+            if !(MIN_RECORD_TTL_SECS..=MAX_RECORD_TTL_SECS).contains(&ttl) {
+                return Err(MdnsError::ConfigValidation(format!(
+                    "announce.{} must be between {} and {}",
+                    key, MIN_RECORD_TTL_SECS, MAX_RECORD_TTL_SECS
+                )));
+            }
+        }
+
+        // Validate reflector interfaces and service types
+        #[cfg(not(feature = "reflector"))]
+        if self.reflector.is_some() {
+            return Err(MdnsError::ConfigValidation(
+                "reflector needs a build with the reflector feature".to_string(),
+            ));
+        }
+        #[cfg(feature = "reflector")]
+        if let Some(reflector) = &self.reflector {
+            if reflector.interfaces.len() < 2 {
+                return Err(MdnsError::ConfigValidation(
+                    "reflector.interfaces needs at least two entries".to_string(),
+                ));
+            }
+            for entry in &reflector.interfaces {
+                InterfaceScope::parse(entry)?;
+            }
+            for service_type in &reflector.service_types {
+                if !service_type.ends_with("._tcp.local.")
+                    && !service_type.ends_with("._udp.local.")
+                {
+                    return Err(MdnsError::ConfigValidation(format!(
+                        "reflector.service_types entry '{}' must end with '._tcp.local.' or '._udp.local.'",
+                        service_type
+                    )));
+                }
+            }
+        }
+
+        // Validate browsed service types
+        for service_type in &self.browse {
+            let service_type = service_type.trim_end_matches('.');
+            let concrete = ["._tcp", "._udp", "._tcp.local", "._udp.local"]
+                .iter()
+                .any(|suffix| service_type.ends_with(suffix))
+                && !service_type.starts_with("_services._dns-sd.");
+            if !concrete {
+                return Err(MdnsError::ConfigValidation(format!(
+                    "browse entry '{}' must be a service type such as '_ipp._tcp'",
+                    service_type
+                )));
+            }
+        }
+
+        // Validate wide-area publishing
+        #[cfg(not(feature = "wide-area"))]
+        if self.wide_area.is_some() {
+            return Err(MdnsError::ConfigValidation(
+                "wide_area needs a build with the wide-area feature".to_string(),
+            ));
+        }
+        #[cfg(feature = "wide-area")]
+        if let Some(wide_area) = &self.wide_area {
+            wide_area.server_address()?;
+            if crate::dns_update::Name::parse(&wide_area.zone)?.is_local() {
+                return Err(MdnsError::ConfigValidation(
+                    "wide_area.zone must be a unicast DNS domain, not 'local.'".to_string(),
+                ));
+            }
+            if !(MIN_WIDE_AREA_TTL_SECS..=MAX_WIDE_AREA_TTL_SECS).contains(&wide_area.ttl_secs) {
+                return Err(MdnsError::ConfigValidation(format!(
+                    "wide_area.ttl_secs must be between {} and {}",
+                    MIN_WIDE_AREA_TTL_SECS, MAX_WIDE_AREA_TTL_SECS
+                )));
+            }
+            let refresh = wide_area.refresh_interval_secs;
+            if refresh != 0
+                && !(MIN_REANNOUNCE_INTERVAL_SECS..=MAX_REANNOUNCE_INTERVAL_SECS).contains(&refresh)
+            {
+                return Err(MdnsError::ConfigValidation(format!(
+                    "wide_area.refresh_interval_secs must be 0 or between {} and {}",
+                    MIN_REANNOUNCE_INTERVAL_SECS, MAX_REANNOUNCE_INTERVAL_SECS
+                )));
+            }
+            if let Some(tsig) = &wide_area.tsig {
+                crate::dns_update::TsigKey::new(&tsig.key_name, &tsig.algorithm, &tsig.secret)?;
+            }
+        }
+
+        // Validate logging settings
+        if self.logging.level.parse::<log::LevelFilter>().is_err() {
+            return Err(MdnsError::ConfigValidation(format!(
+                "logging.level '{}' is not one of off, error, warn, info, debug, trace",
+                self.logging.level
+            )));
+        }
+        if self.logging.max_file_size_mb == 0 || self.logging.max_files == 0 {
+            return Err(MdnsError::ConfigValidation(
+                "logging.max_file_size_mb and logging.max_files must be at least 1".to_string(),
+            ));
+        }
+
         // Validate at least one share is configured
         if self.shares.is_empty() {
             return Err(MdnsError::ConfigValidation(
@@ -124,13 +1098,13 @@ impl ServiceConfig {
         for (i, share) in self.shares.iter().enumerate() {
             if share.name.is_empty() {
                 return Err(MdnsError::ConfigValidation(format!(
-                    "share[{}]: name cannot be empty",
+                    "shares[{}].name cannot be empty",
                     i
                 )));
             }
             if share.path.is_empty() {
                 return Err(MdnsError::ConfigValidation(format!(
-                    "share[{}]: path cannot be empty",
+                    "shares[{}].path cannot be empty",
                     i
                 )));
             }
@@ -139,3 +1113,102 @@ impl ServiceConfig {
         Ok(())
     }
 }
+
+/// Read a config file once only administrators can change it.
+fn read_config(path: &Path) -> Result<String> {
+    permissions::check_config_permissions(path)?;
+    Ok(fs::read_to_string(path)?)
+}
+
+/// Strip the " at line L column C" suffix `serde_json` and YAML errors carry.
+fn without_location(message: &str) -> String {
+    match message.rfind(" at line ") {
+        Some(at) => message[..at].to_string(),
+        None => message.to_string(),
+    }
+}
+
+/// 1-based line and column of byte `offset` in `content`.
+fn line_column(content: &str, offset: usize) -> (usize, usize) {
+    let before = &content[..offset.min(content.len())];
+    let line_start = before.rfind('\n').map_or(0, |newline| newline + 1);
+    (
+        before.matches('\n').count() + 1,
+        before[line_start..].chars().count() + 1,
+    )
+}
+
+/// Point a validation error at the override that set the key it names, or
+/// at the key's line and column in the config file.
+fn locate_error(
+    error: MdnsError,
+    file: Option<(&Path, &str)>,
+    overrides: &ConfigOverrides,
+) -> MdnsError {
+    let MdnsError::ConfigValidation(message) = error else {
+        return error;
+    };
+    let key = message
+        .split([' ', ':', '.', '['])
+        .next()
+        .unwrap_or_default();
+    if let Some(source) = overrides.source_of(key) {
+        return MdnsError::ConfigValidation(format!("{}: {}", source, message));
+    }
+    let Some((path, content)) = file else {
+        return MdnsError::ConfigValidation(message);
+    };
+    match locate_key(content, &message) {
+        Some((line, column)) => MdnsError::ConfigValidation(format!(
+            "{}:{}:{}: {}",
+            path.display(),
+            line,
+            column,
+            message
+        )),
+        None => MdnsError::ConfigValidation(format!("{}: {}", path.display(), message)),
+    }
+}
+
+/// Find the key a validation message starts with, e.g. `announce.announcements`
+/// or `shares[1].name`, and return its line and column.
+///
+/// Each part of the path is searched after the previous one, as a key in
+/// JSON (`"key":`), YAML (`key:`) or TOML (`key =`, `[key]`, `key.sub`) form.
+/// After `key[i]` the next key is its `i + 1`th occurrence, which relies on
+/// every array element carrying that key, as all `shares` fields do.
+fn locate_key(content: &str, message: &str) -> Option<(usize, usize)> {
+    let path = message
+        .split([' ', ':'])
+        .next()
+        .filter(|path| !path.is_empty())?;
+
+    let mut offset = 0;
+    let mut skip = 0;
+    for part in path.split('.') {
+        let (key, index) = match part.split_once('[') {
+            Some((key, index)) => (key, index.strip_suffix(']')?.parse().ok()?),
+            None => (part, 0),
+        };
+        offset = find_key(content, key, offset)?;
+        for _ in 0..skip {
+            offset = find_key(content, key, offset + key.len())?;
+        }
+        skip = index;
+    }
+    Some(line_column(content, offset))
+}
+
+fn find_key(content: &str, key: &str, from: usize) -> Option<usize> {
+    let is_word = |c: char| c.is_ascii_alphanumeric() || c == '_' || c == '-';
+    content[from..]
+        .match_indices(key)
+        .map(|(index, _)| from + index)
+        .find(|&start| {
+            let preceded = content[..start].chars().next_back().is_some_and(is_word);
+            let rest = content[start + key.len()..]
+                .trim_start_matches(['"', '\''])
+                .trim_start_matches([' ', '\t']);
+            !preceded && rest.starts_with([':', '=', '.', ']'])
+        })
+}
@@ -0,0 +1,475 @@
+#[cfg(feature = "discovery")]
+use crate::discovery::{DiscoveredService, DiscoveryEvent};
+use crate::error::{MdnsError, Result};
+use crate::mdns_service;
+use crate::responder::{Responder, ResponderStatus};
+use crate::slot::Slot;
+use interprocess::local_socket::{
+    GenericFilePath, ListenerNonblockingMode, ListenerOptions, Stream, prelude::*,
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const ACCEPT_POLL_MILLIS: u64 = 100;
+/// Time a client gets to send its request line before being dropped
+const READ_TIMEOUT_SECS: u64 = 5;
+const READ_POLL_MILLIS: u64 = 20;
+/// Longest request line accepted, newline included
+const MAX_REQUEST_BYTES: usize = 4096;
+/// Connections served at once; further clients are turned away
+const MAX_CONNECTIONS: usize = 16;
+/// Open `watch` connections, kept below [`MAX_CONNECTIONS`] so requests
+/// still get through
+#[cfg(feature = "discovery")]
+const MAX_WATCHERS: usize = 8;
+/// Quiet time after which a `watch` writes an empty line, to notice clients
+/// that hung up
+#[cfg(feature = "discovery")]
+const WATCH_KEEPALIVE_SECS: u64 = 30;
+
+/// Local control endpoint of the installed service.
+pub fn default_endpoint() -> &'static str {
+    if cfg!(target_os = "windows") {
+        r"\\.\pipe\MDNSResponder"
+    } else {
+        "/run/mdns-responder/control.sock"
+    }
+}
+
+/// A control request, sent as one JSON line such as `{"command":"status"}`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "command", rename_all = "kebab-case")]
+pub enum Request {
+    /// Daemon health, uptime and advertised services
+    Status,
+    /// Re-read the config file and replace the primary service
+    Reload,
+    /// Announce every registered service again
+    Reannounce,
+    /// Services currently advertised
+    ListServices,
+    /// Start caching instances of a service type and return those known so far
+    #[cfg(feature = "discovery")]
+    Browse { service_type: String },
+    /// Cached remote instances, optionally of one service type
+    #[cfg(feature = "discovery")]
+    Discovered {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        service_type: Option<String>,
+    },
+    /// Like `discovered`, then keep the connection open and stream one
+    /// [`DiscoveryEvent`] line per change, with an empty line after quiet
+    /// spells; also browses the type if given
+    #[cfg(feature = "discovery")]
+    Watch {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        service_type: Option<String>,
+    },
+}
+
+/// Reply to a [`Request`], sent back as one JSON line.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Response {
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    /// [`MdnsError::code`] of the failure
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<u32>,
+}
+
+impl Response {
+    fn success(result: Value) -> Self {
+        Self {
+            ok: true,
+            result: Some(result),
+            error: None,
+            code: None,
+        }
+    }
+
+    fn failure(error: &MdnsError) -> Self {
+        Self {
+            ok: false,
+            result: None,
+            error: Some(error.to_string()),
+            code: Some(error.code()),
+        }
+    }
+}
+
+/// Result of a `status` request.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct HostStatus {
+    pub pid: u32,
+    pub uptime_secs: u64,
+    #[serde(flatten)]
+    pub responder: ResponderStatus,
+}
+
+/// Listener answering control requests for a shared [`Responder`].
+///
+/// Stops accepting connections when dropped.
+pub struct ControlServer {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+/// Counts of connections and watchers being served
+#[derive(Default)]
+struct Load {
+    connections: Arc<AtomicUsize>,
+    #[cfg(feature = "discovery")]
+    watchers: Arc<AtomicUsize>,
+}
+
+impl ControlServer {
+    /// Listen on `endpoint`: a Unix socket path, or a `\\.\pipe\` name on Windows.
+    ///
+    /// Fails if another process is already answering on `endpoint`; a stale
+    /// socket file left by one that died is replaced.
+    pub fn start(endpoint: &str, responder: Arc<Mutex<Responder>>) -> Result<Self> {
+        if Stream::connect(endpoint.to_fs_name::<GenericFilePath>()?).is_ok() {
+            return Err(MdnsError::Service(format!(
+                "control channel {} is in use by another instance",
+                endpoint
+            )));
+        }
+        #[cfg(unix)]
+        if let Some(dir) = std::path::Path::new(endpoint).parent() {
+            std::fs::create_dir_all(dir)?;
+        }
+
+        let options = ListenerOptions::new()
+            .name(endpoint.to_fs_name::<GenericFilePath>()?)
+            .nonblocking(ListenerNonblockingMode::Accept)
+            .try_overwrite(true);
+        #[cfg(unix)]
+        let options = {
+            use interprocess::os::unix::local_socket::ListenerOptionsExt;
+            options.mode(0o660)
+        };
+        let listener = options.create_sync()?;
+        info!("Control channel listening on {}", endpoint);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let started = Instant::now();
+        let load = Arc::new(Load::default());
+        let thread = thread::spawn({
+            let stop = Arc::clone(&stop);
+            move || {
+                while !stop.load(Ordering::Relaxed) {
+                    match listener.accept() {
+                        Ok(mut stream) => {
+                            let Some(slot) = Slot::take(&load.connections, MAX_CONNECTIONS) else {
+                                let busy =
+                                    MdnsError::Service("too many control connections".to_string());
+                                write_line(&mut stream, &Response::failure(&busy)).ok();
+                                continue;
+                            };
+                            // One thread per client so a stalled client cannot block shutdown
+                            let responder = Arc::clone(&responder);
+                            let load = Arc::clone(&load);
+                            thread::spawn(move || {
+                                let _slot = slot;
+                                if let Err(e) = serve_connection(stream, &responder, &load, started)
+                                {
+                                    warn!("Control connection failed: {}", e);
+                                }
+                            });
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            thread::sleep(Duration::from_millis(ACCEPT_POLL_MILLIS));
+                        }
+                        Err(e) => {
+                            warn!("Control channel accept failed: {}", e);
+                            thread::sleep(Duration::from_millis(ACCEPT_POLL_MILLIS));
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+#[cfg_attr(not(feature = "discovery"), expect(unused_variables))]
+fn serve_connection(
+    mut stream: Stream,
+    responder: &Mutex<Responder>,
+    load: &Load,
+    started: Instant,
+) -> Result<()> {
+    let request = read_request(&mut stream).and_then(|line| Ok(serde_json::from_str(&line)?));
+    let request = match request {
+        Ok(request) => request,
+        Err(e) => return write_line(&mut stream, &Response::failure(&e)),
+    };
+    #[cfg(feature = "discovery")]
+    if let Request::Watch { service_type } = &request {
+        let Some(_slot) = Slot::take(&load.watchers, MAX_WATCHERS) else {
+            let busy = MdnsError::Service("too many watch connections".to_string());
+            return write_line(&mut stream, &Response::failure(&busy));
+        };
+        return serve_watch(&mut stream, responder, service_type.as_deref());
+    }
+
+    let response = match handle(request, responder, started) {
+        Ok(result) => Response::success(result),
+        Err(e) => Response::failure(&e),
+    };
+    write_line(&mut stream, &response)
+}
+
+/// Read one request line of at most [`MAX_REQUEST_BYTES`], giving up after
+/// [`READ_TIMEOUT_SECS`] so idle clients cannot hold a connection slot.
+///
+/// Named pipes have no read timeout, so the stream is polled instead.
+fn read_request(stream: &mut Stream) -> Result<String> {
+    stream.set_nonblocking(true)?;
+    let mut reader = BufReader::new(Deadline {
+        stream: &mut *stream,
+        deadline: Instant::now() + Duration::from_secs(READ_TIMEOUT_SECS),
+    })
+    .take(MAX_REQUEST_BYTES as u64);
+    let mut line = String::new();
+    let read = reader.read_line(&mut line);
+    drop(reader);
+    stream.set_nonblocking(false)?;
+
+    match read {
+        Err(e) if e.kind() == io::ErrorKind::TimedOut => Err(MdnsError::Timeout(format!(
+            "no control request within {} seconds",
+            READ_TIMEOUT_SECS
+        ))),
+        Err(e) => Err(e.into()),
+        Ok(MAX_REQUEST_BYTES) if !line.ends_with('\n') => Err(MdnsError::Service(format!(
+            "control request longer than {} bytes",
+            MAX_REQUEST_BYTES
+        ))),
+        Ok(_) => Ok(line),
+    }
+}
+
+/// Nonblocking stream whose reads wait until data arrives or `deadline` passes.
+struct Deadline<'a> {
+    stream: &'a mut Stream,
+    deadline: Instant,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            match self.stream.read(buf) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    if Instant::now() >= self.deadline {
+                        return Err(io::ErrorKind::TimedOut.into());
+                    }
+                    thread::sleep(Duration::from_millis(READ_POLL_MILLIS));
+                }
+                read => return read,
+            }
+        }
+    }
+}
+
+/// Send the cached instances, then stream changes until the client hangs up
+/// or the responder stops browsing.
+///
+/// The responder lock is only held while the snapshot is taken. A client
+/// that hung up is noticed by the next write, which is an empty line when
+/// nothing changes for [`WATCH_KEEPALIVE_SECS`].
+#[cfg(feature = "discovery")]
+fn serve_watch(
+    stream: &mut Stream,
+    responder: &Mutex<Responder>,
+    service_type: Option<&str>,
+) -> Result<()> {
+    let watch = {
+        let mut responder = lock(responder);
+        responder.browse_cache().and_then(|cache| {
+            if let Some(service_type) = service_type {
+                cache.browse(service_type)?;
+            }
+            Ok(cache.watch(service_type))
+        })
+    };
+    let (services, events) = match watch {
+        Ok(watch) => watch,
+        Err(e) => return write_line(stream, &Response::failure(&e)),
+    };
+
+    write_line(stream, &Response::success(serde_json::to_value(services)?))?;
+    loop {
+        let written = match events.recv_timeout(Duration::from_secs(WATCH_KEEPALIVE_SECS)) {
+            Ok(event) => write_line(stream, &event),
+            Err(std::sync::mpsc::RecvTimeoutError::Timeout) => {
+                stream.write_all(b"\n").map_err(MdnsError::from)
+            }
+            Err(std::sync::mpsc::RecvTimeoutError::Disconnected) => return Ok(()),
+        };
+        if written.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+fn write_line<T: Serialize>(stream: &mut Stream, value: &T) -> Result<()> {
+    let mut line = serde_json::to_string(value)?;
+    line.push('\n');
+    stream.write_all(line.as_bytes())?;
+    Ok(())
+}
+
+/// Lock a responder shared with a [`ControlServer`], ignoring poisoning.
+pub fn lock(responder: &Mutex<Responder>) -> MutexGuard<'_, Responder> {
+    responder.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+fn handle(request: Request, responder: &Mutex<Responder>, started: Instant) -> Result<Value> {
+    let mut responder = lock(responder);
+    let result = match request {
+        Request::Status => serde_json::to_value(HostStatus {
+            pid: std::process::id(),
+            uptime_secs: started.elapsed().as_secs(),
+            responder: responder.status(),
+        })?,
+        Request::Reload => {
+            info!("Reload requested over control channel");
+            mdns_service::reload(&mut responder)?;
+            serde_json::to_value(responder.status())?
+        }
+        Request::Reannounce => {
+            info!("Re-announce requested over control channel");
+            responder.reannounce()?;
+            Value::Null
+        }
+        Request::ListServices => serde_json::to_value(responder.status().services)?,
+        #[cfg(feature = "discovery")]
+        Request::Browse { service_type } => {
+            let cache = responder.browse_cache()?;
+            let service_type = cache.browse(&service_type)?;
+            serde_json::to_value(cache.services(Some(&service_type)))?
+        }
+        #[cfg(feature = "discovery")]
+        Request::Discovered { service_type } => {
+            serde_json::to_value(responder.browse_cache()?.services(service_type.as_deref()))?
+        }
+        #[cfg(feature = "discovery")]
+        Request::Watch { .. } => {
+            return Err(MdnsError::Service(
+                "watch needs a streaming connection".to_string(),
+            ));
+        }
+    };
+    Ok(result)
+}
+
+/// Send `request` to the service listening on `endpoint` and wait for its reply.
+pub fn request(endpoint: &str, request: Request) -> Result<Response> {
+    let mut reader = send(endpoint, &request)?;
+    let mut reply = String::new();
+    reader.read_line(&mut reply)?;
+    Ok(serde_json::from_str(&reply)?)
+}
+
+/// Poll the service on `endpoint` until it reports its mDNS daemon running,
+/// which means it bound UDP 5353, or `timeout` passes.
+pub fn wait_for_daemon(endpoint: &str, timeout: Duration) -> Result<HostStatus> {
+    let deadline = Instant::now() + timeout;
+    loop {
+        let problem = match request(endpoint, Request::Status) {
+            Ok(Response {
+                ok: true, result, ..
+            }) => {
+                let status: HostStatus = serde_json::from_value(result.unwrap_or_default())?;
+                if status.responder.daemon_running {
+                    return Ok(status);
+                }
+                "mDNS daemon not running".to_string()
+            }
+            Ok(response) => response.error.unwrap_or_default(),
+            Err(e) => e.to_string(),
+        };
+        if Instant::now() >= deadline {
+            return Err(MdnsError::Timeout(format!(
+                "service on {} not up after {:?}: {}",
+                endpoint, timeout, problem
+            )));
+        }
+        thread::sleep(Duration::from_millis(250));
+    }
+}
+
+/// Report the instances cached by the service on `endpoint`, then every
+/// change, to `on_event` until the service stops.
+///
+/// Cached instances arrive as [`DiscoveryEvent::Added`]. Without
+/// `service_type` every cached type is watched.
+#[cfg(feature = "discovery")]
+pub fn watch<F>(endpoint: &str, service_type: Option<&str>, mut on_event: F) -> Result<()>
+where
+    F: FnMut(DiscoveryEvent),
+{
+    let mut reader = send(
+        endpoint,
+        &Request::Watch {
+            service_type: service_type.map(str::to_string),
+        },
+    )?;
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let response: Response = serde_json::from_str(&line)?;
+    if !response.ok {
+        return Err(MdnsError::Service(response.error.unwrap_or_default()));
+    }
+    let services: Vec<DiscoveredService> =
+        serde_json::from_value(response.result.unwrap_or_default())?;
+    services
+        .into_iter()
+        .for_each(|service| on_event(DiscoveryEvent::Added(service)));
+
+    loop {
+        line.clear();
+        if reader.read_line(&mut line)? == 0 {
+            return Ok(());
+        }
+        // Empty lines only keep the connection alive
+        if !line.trim().is_empty() {
+            on_event(serde_json::from_str(&line)?);
+        }
+    }
+}
+
+fn send(endpoint: &str, request: &Request) -> Result<BufReader<Stream>> {
+    let stream = Stream::connect(endpoint.to_fs_name::<GenericFilePath>()?).map_err(|e| {
+        MdnsError::Service(format!(
+            "cannot reach control channel at {}: {}",
+            endpoint, e
+        ))
+    })?;
+    let mut reader = BufReader::new(stream);
+    write_line(reader.get_mut(), request)?;
+    Ok(reader)
+}
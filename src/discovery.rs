@@ -1,36 +1,394 @@
-#![allow(unused_imports)]
+use crate::error::{MdnsError, Result};
+use log::{debug, info};
+use mdns_sd::{ResolvedService, ServiceDaemon, ServiceEvent, ServiceInfo};
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, BTreeSet, HashSet};
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, mpsc};
+use std::thread;
+use std::time::{Duration, Instant};
 
-use crate::error::Result;
-use log::info;
-use mdns_sd::{ServiceDaemon, ServiceEvent};
-use std::time::Duration;
+/// DNS-SD meta query that enumerates every service type on the link
+pub const SERVICE_TYPE_ENUMERATION: &str = "_services._dns-sd._udp.local.";
 
-/// Simple test to verify service discovery works (debug-only)
-#[cfg(debug_assertions)]
-pub fn test_discovery() -> Result<()> {
-    info!("Starting service discovery test...");
+/// A resolved service instance.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct DiscoveredService {
+    pub fullname: String,
+    pub service_type: String,
+    pub hostname: String,
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+    pub txt: BTreeMap<String, String>,
+}
 
-    let daemon = ServiceDaemon::new()
-        .map_err(|e| crate::error::MdnsError::Service(format!("Failed to create daemon: {}", e)))?;
-    let receiver = daemon
-        .browse("_smb._tcp.local.")
-        .map_err(|e| crate::error::MdnsError::Service(format!("Failed to browse: {}", e)))?;
+impl From<&ResolvedService> for DiscoveredService {
+    fn from(service: &ResolvedService) -> Self {
+        let mut addresses: Vec<IpAddr> =
+            service.addresses.iter().map(|ip| ip.to_ip_addr()).collect();
+        addresses.sort();
+
+        Self {
+            fullname: service.fullname.clone(),
+            service_type: service.ty_domain.clone(),
+            hostname: service.host.clone(),
+            addresses,
+            port: service.port,
+            txt: service
+                .txt_properties
+                .iter()
+                .map(|prop| (prop.key().to_string(), prop.val_str().to_string()))
+                .collect(),
+        }
+    }
+}
+
+impl From<&ServiceInfo> for DiscoveredService {
+    fn from(info: &ServiceInfo) -> Self {
+        let mut addresses: Vec<IpAddr> = info.get_addresses().iter().copied().collect();
+        addresses.sort();
+
+        Self {
+            fullname: info.get_fullname().to_string(),
+            service_type: info.get_type().to_string(),
+            hostname: info.get_hostname().to_string(),
+            addresses,
+            port: info.get_port(),
+            txt: info
+                .get_properties()
+                .iter()
+                .map(|prop| (prop.key().to_string(), prop.val_str().to_string()))
+                .collect(),
+        }
+    }
+}
+
+/// Something observed while browsing.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum DiscoveryEvent {
+    /// A service type answered the enumeration query
+    TypeFound { service_type: String },
+    /// An instance resolved for the first time
+    Added(DiscoveredService),
+    /// A known instance resolved again with new records
+    Updated(DiscoveredService),
+    /// An instance sent a goodbye or expired
+    Removed {
+        service_type: String,
+        fullname: String,
+    },
+}
+
+impl DiscoveryEvent {
+    /// Service type the event is about.
+    pub fn service_type(&self) -> &str {
+        match self {
+            DiscoveryEvent::TypeFound { service_type }
+            | DiscoveryEvent::Removed { service_type, .. } => service_type,
+            DiscoveryEvent::Added(service) | DiscoveryEvent::Updated(service) => {
+                &service.service_type
+            }
+        }
+    }
+}
+
+/// Normalize a user-supplied service type to `_svc._proto.local.` form.
+pub fn normalize_service_type(service_type: &str) -> String {
+    if service_type.ends_with(".local.") {
+        service_type.to_string()
+    } else if service_type.ends_with(".local") {
+        format!("{}.", service_type)
+    } else {
+        format!("{}.local.", service_type.trim_end_matches('.'))
+    }
+}
 
-    info!("Browsing for SMB services for 10 seconds...");
-    let mut found_count = 0;
+/// Browse `service_type` and report every event to `on_event`.
+///
+/// Browsing [`SERVICE_TYPE_ENUMERATION`] reports each type found and then
+/// browses its instances as well. Returns after `timeout`, or never when
+/// `timeout` is `None`.
+pub fn discover<F>(service_type: &str, timeout: Option<Duration>, mut on_event: F) -> Result<()>
+where
+    F: FnMut(DiscoveryEvent),
+{
+    let service_type = normalize_service_type(service_type);
+    let enumerate = service_type == SERVICE_TYPE_ENUMERATION;
 
-    for _ in 0..10 {
-        match receiver.recv_timeout(Duration::from_secs(1)) {
-            Ok(ServiceEvent::ServiceResolved(info)) => {
-                found_count += 1;
-                let hostname = info.get_hostname();
-                info!("Found: {} at {}", info.get_fullname(), hostname);
+    let daemon = ServiceDaemon::new().map_err(MdnsError::daemon("start", None))?;
+    let (event_tx, event_rx) = mpsc::channel();
+    browse_into(&daemon, &service_type, event_tx.clone())?;
+
+    info!("Browsing for {}...", service_type);
+    let deadline = timeout.map(|timeout| Instant::now() + timeout);
+    let mut browsed_types = HashSet::new();
+    let mut known = HashSet::new();
+
+    loop {
+        let event = match deadline {
+            Some(deadline) => {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                match event_rx.recv_timeout(remaining) {
+                    Ok(event) => event,
+                    Err(_) => break,
+                }
+            }
+            None => match event_rx.recv() {
+                Ok(event) => event,
+                Err(_) => break,
+            },
+        };
+
+        match event {
+            ServiceEvent::ServiceFound(ty, name) if enumerate && ty == SERVICE_TYPE_ENUMERATION => {
+                // Enumeration answers carry the service type as the instance name
+                let found_type = normalize_service_type(&name);
+                if browsed_types.insert(found_type.clone()) {
+                    on_event(DiscoveryEvent::TypeFound {
+                        service_type: found_type.clone(),
+                    });
+                    browse_into(&daemon, &found_type, event_tx.clone())?;
+                }
+            }
+            ServiceEvent::ServiceResolved(service) => {
+                let discovered = DiscoveredService::from(service.as_ref());
+                if known.insert(discovered.fullname.clone()) {
+                    on_event(DiscoveryEvent::Added(discovered));
+                } else {
+                    on_event(DiscoveryEvent::Updated(discovered));
+                }
+            }
+            ServiceEvent::ServiceRemoved(ty, fullname) if ty != SERVICE_TYPE_ENUMERATION => {
+                known.remove(&fullname);
+                on_event(DiscoveryEvent::Removed {
+                    service_type: ty,
+                    fullname,
+                });
             }
-            Ok(_) => {}
-            Err(_) => {}
+            _ => {}
         }
     }
 
-    info!("Discovery test complete. Found {} services.", found_count);
+    daemon.shutdown().ok();
     Ok(())
 }
+
+/// Browse `service_type` and forward its events to `event_tx` from a helper thread.
+fn browse_into(
+    daemon: &ServiceDaemon,
+    service_type: &str,
+    event_tx: mpsc::Sender<ServiceEvent>,
+) -> Result<()> {
+    let receiver = daemon
+        .browse(service_type)
+        .map_err(MdnsError::daemon("browse", Some(service_type)))?;
+
+    // Ends once the daemon shuts down and drops its sender
+    thread::spawn(move || {
+        while let Ok(event) = receiver.recv() {
+            if event_tx.send(event).is_err() {
+                break;
+            }
+        }
+    });
+
+    Ok(())
+}
+
+/// Long-running browse cache kept by a running responder.
+///
+/// Browses on the daemon it shares with its owner, so clients of the service
+/// never open multicast sockets of their own. Instances stay cached until
+/// they send a goodbye or the daemon expires their records by TTL, and every
+/// change is passed on to watchers. Stops browsing when dropped.
+pub struct BrowseCache {
+    daemon: ServiceDaemon,
+    state: Arc<Mutex<CacheState>>,
+}
+
+#[derive(Default)]
+struct CacheState {
+    service_types: BTreeSet<String>,
+    services: BTreeMap<String, DiscoveredService>,
+    /// Watchers with the service type they asked for, if any
+    watchers: Vec<(Option<String>, mpsc::Sender<DiscoveryEvent>)>,
+}
+
+impl CacheState {
+    fn apply(&mut self, event: ServiceEvent) {
+        let event = match event {
+            ServiceEvent::ServiceResolved(service) => {
+                let service = DiscoveredService::from(service.as_ref());
+                match self
+                    .services
+                    .insert(service.fullname.clone(), service.clone())
+                {
+                    None => DiscoveryEvent::Added(service),
+                    Some(previous) if previous != service => DiscoveryEvent::Updated(service),
+                    Some(_) => return,
+                }
+            }
+            ServiceEvent::ServiceRemoved(service_type, fullname) => {
+                if self.services.remove(&fullname).is_none() {
+                    return;
+                }
+                DiscoveryEvent::Removed {
+                    service_type,
+                    fullname,
+                }
+            }
+            _ => return,
+        };
+        debug!("Browse cache: {:?}", event);
+
+        // Watchers that hung up are dropped here
+        self.watchers.retain(|(service_type, watcher)| {
+            service_type
+                .as_deref()
+                .is_some_and(|service_type| service_type != event.service_type())
+                || watcher.send(event.clone()).is_ok()
+        });
+    }
+
+    fn services(&self, service_type: Option<&str>) -> Vec<DiscoveredService> {
+        self.services
+            .values()
+            .filter(|service| service_type.is_none_or(|ty| service.service_type == ty))
+            .cloned()
+            .collect()
+    }
+}
+
+impl BrowseCache {
+    pub fn new(daemon: ServiceDaemon) -> Self {
+        Self {
+            daemon,
+            state: Arc::default(),
+        }
+    }
+
+    /// Start caching instances of `service_type`; browsing a type twice is a no-op.
+    ///
+    /// Returns the normalized type.
+    pub fn browse(&self, service_type: &str) -> Result<String> {
+        let service_type = normalize_service_type(service_type);
+        if service_type == SERVICE_TYPE_ENUMERATION {
+            return Err(MdnsError::Service(
+                "the browse cache needs concrete service types, not the enumeration query"
+                    .to_string(),
+            ));
+        }
+        let mut state = self.lock();
+        if state.service_types.contains(&service_type) {
+            return Ok(service_type);
+        }
+
+        let receiver = self
+            .daemon
+            .browse(&service_type)
+            .map_err(MdnsError::daemon("browse", Some(&service_type)))?;
+        state.service_types.insert(service_type.clone());
+        info!("Caching {} instances", service_type);
+
+        // Ends once browsing stops and the daemon drops its sender
+        let cache = Arc::clone(&self.state);
+        thread::spawn(move || {
+            while let Ok(event) = receiver.recv() {
+                cache
+                    .lock()
+                    .unwrap_or_else(PoisonError::into_inner)
+                    .apply(event);
+            }
+        });
+        Ok(service_type)
+    }
+
+    /// Service types being cached.
+    pub fn service_types(&self) -> Vec<String> {
+        self.lock().service_types.iter().cloned().collect()
+    }
+
+    /// Cached instances, optionally of one service type.
+    pub fn services(&self, service_type: Option<&str>) -> Vec<DiscoveredService> {
+        let service_type = service_type.map(normalize_service_type);
+        self.lock().services(service_type.as_deref())
+    }
+
+    /// Cached instances plus a channel receiving every later change, taken
+    /// together so no change falls between them.
+    pub fn watch(
+        &self,
+        service_type: Option<&str>,
+    ) -> (Vec<DiscoveredService>, mpsc::Receiver<DiscoveryEvent>) {
+        let service_type = service_type.map(normalize_service_type);
+        let (tx, rx) = mpsc::channel();
+        let mut state = self.lock();
+        let services = state.services(service_type.as_deref());
+        state.watchers.push((service_type, tx));
+        (services, rx)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, CacheState> {
+        self.state.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Drop for BrowseCache {
+    fn drop(&mut self) {
+        let mut state = self.lock();
+        for service_type in &state.service_types {
+            self.daemon.stop_browse(service_type).ok();
+        }
+        // Ends every watch
+        state.watchers.clear();
+    }
+}
+
+/// Async stream of discovery events for one service type.
+///
+/// Owns its own daemon; dropping the stream stops browsing and shuts the
+/// daemon down.
+#[cfg(feature = "tokio")]
+pub struct DiscoveryStream {
+    daemon: ServiceDaemon,
+    service_type: String,
+    events: std::pin::Pin<Box<dyn futures_core::Stream<Item = ServiceEvent> + Send>>,
+}
+
+#[cfg(feature = "tokio")]
+impl DiscoveryStream {
+    /// Start browsing for `service_type`, e.g. `_smb._tcp.local.`.
+    pub fn browse(service_type: &str) -> Result<Self> {
+        let daemon = ServiceDaemon::new().map_err(MdnsError::daemon("start", None))?;
+        let receiver = daemon
+            .browse(service_type)
+            .map_err(MdnsError::daemon("browse", Some(service_type)))?;
+
+        Ok(Self {
+            daemon,
+            service_type: service_type.to_string(),
+            events: Box::pin(receiver.into_stream()),
+        })
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl futures_core::Stream for DiscoveryStream {
+    type Item = ServiceEvent;
+
+    fn poll_next(
+        mut self: std::pin::Pin<&mut Self>,
+        cx: &mut std::task::Context<'_>,
+    ) -> std::task::Poll<Option<Self::Item>> {
+        self.events.as_mut().poll_next(cx)
+    }
+}
+
+#[cfg(feature = "tokio")]
+impl Drop for DiscoveryStream {
+    fn drop(&mut self) {
+        self.daemon.stop_browse(&self.service_type).ok();
+        self.daemon.shutdown().ok();
+    }
+}
@@ -0,0 +1,239 @@
+use crate::error::{MdnsError, Result};
+use crate::publisher::Publisher;
+use log::{info, warn};
+use mdns_sd::ServiceInfo;
+use std::collections::BTreeMap;
+use std::ffi::c_void;
+use std::sync::mpsc::{self, Sender};
+use std::time::Duration;
+use windows::Win32::Foundation::{DNS_REQUEST_PENDING, ERROR_SUCCESS, HANDLE, WIN32_ERROR};
+use windows::Win32::NetworkManagement::Dns::{
+    DNS_QUERY_REQUEST_VERSION1, DNS_SERVICE_CANCEL, DNS_SERVICE_INSTANCE,
+    DNS_SERVICE_REGISTER_REQUEST, DnsServiceConstructInstance, DnsServiceDeRegister,
+    DnsServiceFreeInstance, DnsServiceRegister, IP6_ADDRESS,
+};
+use windows::core::{HSTRING, PCWSTR};
+
+/// How long registering waits for the DNS Client to confirm
+const REGISTER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Publishes through the mDNS responder in Windows' DNS Client (Dnscache)
+/// with `DnsServiceRegister`, instead of our own daemon.
+#[derive(Default)]
+pub(crate) struct DnsServicePublisher {
+    registrations: BTreeMap<String, Registration>,
+}
+
+impl Publisher for DnsServicePublisher {
+    fn name(&self) -> &'static str {
+        "DNS Client"
+    }
+
+    fn register(&mut self, service_info: &ServiceInfo) -> Result<()> {
+        let fullname = service_info.get_fullname().to_string();
+        let registration = Registration::new(service_info)?;
+        let registration = settle(
+            registration.call("register", DnsServiceRegister, REGISTER_TIMEOUT),
+            registration,
+        )?;
+        info!("Registered {} with the DNS Client", fullname);
+        self.registrations.insert(fullname, registration);
+        Ok(())
+    }
+
+    fn unregister(&mut self, fullname: &str, timeout: Duration) -> Result<()> {
+        let registration = self.registrations.remove(fullname).ok_or_else(|| {
+            MdnsError::Service(format!(
+                "{} is not registered with the DNS Client",
+                fullname
+            ))
+        })?;
+        settle(
+            registration.call("deregister", DnsServiceDeRegister, timeout),
+            registration,
+        )
+        .map(drop)
+    }
+
+    /// Registrations would fail without a running DNS Client.
+    fn is_running(&self) -> bool {
+        crate::coexistence::service_running("Dnscache")
+    }
+
+    fn shutdown(&mut self, _timeout: Duration) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Hand back `registration` if its call succeeded, and leak it if the call
+/// may still be pending, as dnsapi could yet read the instance.
+fn settle(result: Result<()>, registration: Registration) -> Result<Registration> {
+    match result {
+        Ok(()) => Ok(registration),
+        Err(e @ MdnsError::Timeout(_)) => {
+            warn!(
+                "Leaking the DNS Client request for {}",
+                registration.fullname
+            );
+            std::mem::forget(registration);
+            Err(e)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// A service instance built by `DnsServiceConstructInstance`.
+struct Registration {
+    fullname: String,
+    instance: *mut DNS_SERVICE_INSTANCE,
+}
+
+// SAFETY: the instance is owned by this handle and only read by dnsapi.
+unsafe impl Send for Registration {}
+
+/// Request and reply channel of a pending call, freed by [`completed`]
+struct Pending {
+    request: DNS_SERVICE_REGISTER_REQUEST,
+    sender: Sender<u32>,
+}
+
+impl Registration {
+    fn new(service_info: &ServiceInfo) -> Result<Self> {
+        let fullname = service_info.get_fullname().to_string();
+        let name = HSTRING::from(fullname.trim_end_matches('.'));
+        let hostname = HSTRING::from(service_info.get_hostname().trim_end_matches('.'));
+        let ip4 = service_info
+            .get_addresses_v4()
+            .into_iter()
+            .min()
+            .map(|ip| u32::from_ne_bytes(ip.octets()));
+        let ip6 = service_info
+            .get_addresses()
+            .iter()
+            .filter_map(|ip| match ip {
+                std::net::IpAddr::V6(ip) => Some(ip),
+                std::net::IpAddr::V4(_) => None,
+            })
+            .min()
+            .map(|ip| IP6_ADDRESS {
+                IP6Byte: ip.octets(),
+            });
+        let properties: Vec<(HSTRING, HSTRING)> = service_info
+            .get_properties()
+            .iter()
+            .map(|property| (property.key().into(), property.val_str().into()))
+            .collect();
+        let keys: Vec<PCWSTR> = properties
+            .iter()
+            .map(|(key, _)| PCWSTR(key.as_ptr()))
+            .collect();
+        let values: Vec<PCWSTR> = properties
+            .iter()
+            .map(|(_, value)| PCWSTR(value.as_ptr()))
+            .collect();
+
+        // SAFETY: every pointer is valid for the call, which copies what it needs.
+        let instance = unsafe {
+            DnsServiceConstructInstance(
+                &name,
+                &hostname,
+                ip4.as_ref().map(|ip| ip as *const u32),
+                ip6.as_ref().map(|ip| ip as *const IP6_ADDRESS),
+                service_info.get_port(),
+                0,
+                0,
+                keys.len() as u32,
+                keys.as_ptr(),
+                values.as_ptr(),
+            )
+        };
+        if instance.is_null() {
+            return Err(MdnsError::Service(format!(
+                "DnsServiceConstructInstance failed for {}",
+                fullname
+            )));
+        }
+        Ok(Self { fullname, instance })
+    }
+
+    /// Run `DnsServiceRegister` or `DnsServiceDeRegister` for this instance
+    /// and wait for its completion callback.
+    ///
+    /// After a timeout the call may still complete, so the instance must
+    /// not be freed.
+    fn call(
+        &self,
+        operation: &'static str,
+        function: unsafe fn(
+            *const DNS_SERVICE_REGISTER_REQUEST,
+            Option<*mut DNS_SERVICE_CANCEL>,
+        ) -> u32,
+        timeout: Duration,
+    ) -> Result<()> {
+        let (sender, receiver) = mpsc::channel();
+        let pending = Box::into_raw(Box::new(Pending {
+            request: DNS_SERVICE_REGISTER_REQUEST {
+                Version: DNS_QUERY_REQUEST_VERSION1.0,
+                InterfaceIndex: 0,
+                pServiceInstance: self.instance,
+                pRegisterCompletionCallback: Some(completed),
+                pQueryContext: std::ptr::null_mut(),
+                hCredentials: HANDLE::default(),
+                unicastEnabled: false.into(),
+            },
+            sender,
+        }));
+
+        // SAFETY: `pending` stays allocated until `completed` frees it, which
+        // dnsapi calls exactly once when it reports the request pending.
+        let status = unsafe {
+            (*pending).request.pQueryContext = pending.cast();
+            function(&(*pending).request, None)
+        };
+        if status != DNS_REQUEST_PENDING as u32 {
+            // SAFETY: no callback is coming, so the request is still ours.
+            drop(unsafe { Box::from_raw(pending) });
+            return Err(self.failed(operation, status));
+        }
+
+        match receiver.recv_timeout(timeout) {
+            Ok(status) if status == ERROR_SUCCESS.0 => Ok(()),
+            Ok(status) => Err(self.failed(operation, status)),
+            Err(_) => Err(MdnsError::Timeout(format!(
+                "DNS Client did not {} {} within {:?}",
+                operation, self.fullname, timeout
+            ))),
+        }
+    }
+
+    fn failed(&self, operation: &str, status: u32) -> MdnsError {
+        MdnsError::Service(format!(
+            "DNS Client failed to {} {}: {}",
+            operation,
+            self.fullname,
+            windows::core::Error::from(WIN32_ERROR(status).to_hresult())
+        ))
+    }
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        // SAFETY: built by DnsServiceConstructInstance and no longer in use.
+        unsafe { DnsServiceFreeInstance(self.instance) }
+    }
+}
+
+/// Completion callback of [`Registration::call`].
+unsafe extern "system" fn completed(
+    status: u32,
+    context: *const c_void,
+    instance: *const DNS_SERVICE_INSTANCE,
+) {
+    // SAFETY: `context` is the `Pending` leaked by `call`, handed back once.
+    let pending = unsafe { Box::from_raw(context as *mut Pending) };
+    pending.sender.send(status).ok();
+    if !instance.is_null() {
+        // SAFETY: the instance passed back is a copy the callback owns.
+        unsafe { DnsServiceFreeInstance(instance) }
+    }
+}
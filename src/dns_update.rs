@@ -0,0 +1,668 @@
+use crate::config::WideAreaConfig;
+use crate::error::{MdnsError, Result};
+use base64::Engine as _;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use hmac::{Hmac, Mac};
+use sha2::{Sha256, Sha512};
+use std::fmt;
+use std::io::{self, Read, Write};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, UdpSocket};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+const OPCODE_UPDATE: u16 = 5;
+const FLAG_QR: u16 = 0x8000;
+const FLAG_TC: u16 = 0x0200;
+const HEADER_LEN: usize = 12;
+/// Largest message sent over UDP; anything bigger goes straight to TCP
+const MAX_UDP_MESSAGE: usize = 512;
+const TSIG_FUDGE_SECS: u16 = 300;
+
+pub(crate) const TYPE_A: u16 = 1;
+const TYPE_SOA: u16 = 6;
+const TYPE_PTR: u16 = 12;
+pub(crate) const TYPE_TXT: u16 = 16;
+pub(crate) const TYPE_AAAA: u16 = 28;
+pub(crate) const TYPE_SRV: u16 = 33;
+const TYPE_TSIG: u16 = 250;
+const TYPE_ANY: u16 = 255;
+const CLASS_IN: u16 = 1;
+const CLASS_NONE: u16 = 254;
+const CLASS_ANY: u16 = 255;
+
+/// Domain name as a list of labels; labels may themselves contain dots.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Name(Vec<String>);
+
+impl Name {
+    /// Parse a dotted name such as `example.com.`; the trailing dot is optional.
+    pub(crate) fn parse(name: &str) -> Result<Self> {
+        let trimmed = name.strip_suffix('.').unwrap_or(name);
+        if trimmed.is_empty() {
+            return Err(MdnsError::ConfigValidation(format!(
+                "'{}' is not a valid domain name",
+                name
+            )));
+        }
+        Self::from_labels(trimmed.split('.').map(str::to_string).collect())
+            .map_err(|reason| MdnsError::ConfigValidation(format!("'{}' {}", name, reason)))
+    }
+
+    /// Name made of the single label `label`, e.g. a service instance name.
+    pub(crate) fn label(label: &str) -> Result<Self> {
+        Self::from_labels(vec![label.to_string()])
+            .map_err(|reason| MdnsError::Service(format!("'{}' {}", label, reason)))
+    }
+
+    pub(crate) fn join(&self, suffix: &Name) -> Result<Self> {
+        Self::from_labels(self.0.iter().chain(&suffix.0).cloned().collect())
+            .map_err(|reason| MdnsError::Service(format!("'{}.{}' {}", self, suffix, reason)))
+    }
+
+    pub(crate) fn is_local(&self) -> bool {
+        self.0.len() == 1 && self.0[0].eq_ignore_ascii_case("local")
+    }
+
+    fn from_labels(labels: Vec<String>) -> std::result::Result<Self, &'static str> {
+        if labels
+            .iter()
+            .any(|label| label.is_empty() || label.len() > 63)
+        {
+            return Err("has an empty label or one longer than 63 bytes");
+        }
+        if labels.iter().map(|label| label.len() + 1).sum::<usize>() + 1 > 255 {
+            return Err("is longer than 255 bytes");
+        }
+        Ok(Name(labels))
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        for label in &self.0 {
+            out.push(label.len() as u8);
+            out.extend_from_slice(label.as_bytes());
+        }
+        out.push(0);
+    }
+
+    /// Lowercase, uncompressed form used in TSIG digests.
+    fn write_canonical(&self, out: &mut Vec<u8>) {
+        Name(
+            self.0
+                .iter()
+                .map(|label| label.to_ascii_lowercase())
+                .collect(),
+        )
+        .write(out);
+    }
+}
+
+impl fmt::Display for Name {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}.", self.0.join("."))
+    }
+}
+
+/// Record data for the record types DNS-SD needs.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum RData {
+    A(Ipv4Addr),
+    Aaaa(Ipv6Addr),
+    Ptr(Name),
+    Srv {
+        port: u16,
+        target: Name,
+    },
+    /// Character strings; an empty list is sent as one empty string (RFC 6763 section 6.1)
+    Txt(Vec<Vec<u8>>),
+}
+
+impl RData {
+    pub(crate) fn address(ip: IpAddr) -> Self {
+        match ip {
+            IpAddr::V4(ip) => RData::A(ip),
+            IpAddr::V6(ip) => RData::Aaaa(ip),
+        }
+    }
+
+    fn rtype(&self) -> u16 {
+        match self {
+            RData::A(_) => TYPE_A,
+            RData::Aaaa(_) => TYPE_AAAA,
+            RData::Ptr(_) => TYPE_PTR,
+            RData::Srv { .. } => TYPE_SRV,
+            RData::Txt(_) => TYPE_TXT,
+        }
+    }
+
+    fn write(&self, out: &mut Vec<u8>) {
+        match self {
+            RData::A(ip) => out.extend_from_slice(&ip.octets()),
+            RData::Aaaa(ip) => out.extend_from_slice(&ip.octets()),
+            RData::Ptr(name) => name.write(out),
+            RData::Srv { port, target } => {
+                // Priority and weight
+                out.extend_from_slice(&[0, 0, 0, 0]);
+                out.extend_from_slice(&port.to_be_bytes());
+                target.write(out);
+            }
+            RData::Txt(strings) if strings.is_empty() => out.push(0),
+            RData::Txt(strings) => {
+                for string in strings {
+                    let string = &string[..string.len().min(255)];
+                    out.push(string.len() as u8);
+                    out.extend_from_slice(string);
+                }
+            }
+        }
+    }
+}
+
+/// One entry of the update section (RFC 2136 section 2.5).
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Update {
+    Add {
+        name: Name,
+        ttl: u32,
+        rdata: RData,
+    },
+    /// Delete every record of type `rtype` at `name`
+    DeleteRrset {
+        name: Name,
+        rtype: u16,
+    },
+    /// Delete the one record matching `rdata`
+    DeleteRecord {
+        name: Name,
+        rdata: RData,
+    },
+    /// Delete every record at `name`
+    DeleteName {
+        name: Name,
+    },
+}
+
+impl Update {
+    fn write(&self, out: &mut Vec<u8>) {
+        let (name, rtype, class, ttl, rdata) = match self {
+            Update::Add { name, ttl, rdata } => (name, rdata.rtype(), CLASS_IN, *ttl, Some(rdata)),
+            Update::DeleteRrset { name, rtype } => (name, *rtype, CLASS_ANY, 0, None),
+            Update::DeleteRecord { name, rdata } => {
+                (name, rdata.rtype(), CLASS_NONE, 0, Some(rdata))
+            }
+            Update::DeleteName { name } => (name, TYPE_ANY, CLASS_ANY, 0, None),
+        };
+        name.write(out);
+        out.extend_from_slice(&rtype.to_be_bytes());
+        out.extend_from_slice(&class.to_be_bytes());
+        out.extend_from_slice(&ttl.to_be_bytes());
+        with_length(out, |out| {
+            if let Some(rdata) = rdata {
+                rdata.write(out);
+            }
+        });
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TsigAlgorithm {
+    HmacSha256,
+    HmacSha512,
+}
+
+impl TsigAlgorithm {
+    fn name(self) -> Name {
+        match self {
+            TsigAlgorithm::HmacSha256 => Name(vec!["hmac-sha256".to_string()]),
+            TsigAlgorithm::HmacSha512 => Name(vec!["hmac-sha512".to_string()]),
+        }
+    }
+
+    fn mac(self, secret: &[u8], data: &[u8]) -> Vec<u8> {
+        // HMAC accepts keys of any length
+        match self {
+            TsigAlgorithm::HmacSha256 => Hmac::<Sha256>::new_from_slice(secret)
+                .expect("HMAC key of any length")
+                .chain_update(data)
+                .finalize()
+                .into_bytes()
+                .to_vec(),
+            TsigAlgorithm::HmacSha512 => Hmac::<Sha512>::new_from_slice(secret)
+                .expect("HMAC key of any length")
+                .chain_update(data)
+                .finalize()
+                .into_bytes()
+                .to_vec(),
+        }
+    }
+}
+
+/// Shared secret used to sign updates (RFC 8945).
+#[derive(Clone)]
+pub(crate) struct TsigKey {
+    name: Name,
+    algorithm: TsigAlgorithm,
+    secret: Vec<u8>,
+}
+
+impl TsigKey {
+    /// `algorithm` is `hmac-sha256` or `hmac-sha512`; `secret` is base64.
+    pub(crate) fn new(name: &str, algorithm: &str, secret: &str) -> Result<Self> {
+        let name = Name::parse(name)?;
+        let algorithm = match algorithm
+            .trim_end_matches('.')
+            .to_ascii_lowercase()
+            .as_str()
+        {
+            "hmac-sha256" => TsigAlgorithm::HmacSha256,
+            "hmac-sha512" => TsigAlgorithm::HmacSha512,
+            _ => {
+                return Err(MdnsError::ConfigValidation(format!(
+                    "TSIG algorithm '{}' is not one of hmac-sha256, hmac-sha512",
+                    algorithm
+                )));
+            }
+        };
+        let secret = BASE64
+            .decode(secret.trim())
+            .ok()
+            .filter(|secret| !secret.is_empty())
+            .ok_or_else(|| {
+                MdnsError::ConfigValidation("TSIG secret must be non-empty base64".to_string())
+            })?;
+
+        Ok(Self {
+            name,
+            algorithm,
+            secret,
+        })
+    }
+
+    fn mac(&self, data: &[u8]) -> Vec<u8> {
+        self.algorithm.mac(&self.secret, data)
+    }
+
+    /// TSIG variables appended to the signed data (RFC 8945 section 4.3.3).
+    fn write_variables(&self, out: &mut Vec<u8>, fields: &TsigFields) {
+        self.name.write_canonical(out);
+        out.extend_from_slice(&CLASS_ANY.to_be_bytes());
+        out.extend_from_slice(&0u32.to_be_bytes());
+        self.algorithm.name().write_canonical(out);
+        out.extend_from_slice(&fields.time_signed.to_be_bytes()[2..]);
+        out.extend_from_slice(&fields.fudge.to_be_bytes());
+        out.extend_from_slice(&fields.error.to_be_bytes());
+        out.extend_from_slice(&(fields.other.len() as u16).to_be_bytes());
+        out.extend_from_slice(&fields.other);
+    }
+
+    /// Append a TSIG record to `message` and return its MAC, which the
+    /// reply's signature covers.
+    fn sign(&self, message: &mut Vec<u8>, time_signed: u64) -> Vec<u8> {
+        let fields = TsigFields {
+            time_signed,
+            fudge: TSIG_FUDGE_SECS,
+            mac: Vec::new(),
+            error: 0,
+            other: Vec::new(),
+        };
+        let mut data = message.clone();
+        self.write_variables(&mut data, &fields);
+        let mac = self.mac(&data);
+
+        self.name.write(message);
+        message.extend_from_slice(&TYPE_TSIG.to_be_bytes());
+        message.extend_from_slice(&CLASS_ANY.to_be_bytes());
+        message.extend_from_slice(&0u32.to_be_bytes());
+        let id = [message[0], message[1]];
+        with_length(message, |out| {
+            self.algorithm.name().write(out);
+            out.extend_from_slice(&time_signed.to_be_bytes()[2..]);
+            out.extend_from_slice(&fields.fudge.to_be_bytes());
+            out.extend_from_slice(&(mac.len() as u16).to_be_bytes());
+            out.extend_from_slice(&mac);
+            out.extend_from_slice(&id);
+            // Error and other length
+            out.extend_from_slice(&[0, 0, 0, 0]);
+        });
+        let additional = get_u16(message, 10).unwrap_or(0);
+        set_count(message, 10, additional + 1);
+        mac
+    }
+
+    /// Check the signature of a reply to a request signed with `request_mac`.
+    fn verify(&self, response: &[u8], tsig: &Tsig, request_mac: &[u8]) -> bool {
+        let mut data = Vec::new();
+        data.extend_from_slice(&(request_mac.len() as u16).to_be_bytes());
+        data.extend_from_slice(request_mac);
+        let mut unsigned = response[..tsig.start].to_vec();
+        unsigned[..2].copy_from_slice(&tsig.original_id.to_be_bytes());
+        set_count(&mut unsigned, 10, get_u16(response, 10).unwrap_or(1) - 1);
+        data.extend_from_slice(&unsigned);
+        self.write_variables(&mut data, &tsig.fields);
+
+        let expected = self.mac(&data);
+        // Compare in constant time
+        expected.len() == tsig.fields.mac.len()
+            && expected
+                .iter()
+                .zip(&tsig.fields.mac)
+                .fold(0u8, |diff, (a, b)| diff | (a ^ b))
+                == 0
+    }
+}
+
+impl fmt::Debug for TsigKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("TsigKey")
+            .field("name", &self.name)
+            .field("algorithm", &self.algorithm)
+            .finish_non_exhaustive()
+    }
+}
+
+struct TsigFields {
+    time_signed: u64,
+    fudge: u16,
+    mac: Vec<u8>,
+    error: u16,
+    other: Vec<u8>,
+}
+
+/// TSIG record found at the end of a reply.
+struct Tsig {
+    /// Offset of the record within the message
+    start: usize,
+    original_id: u16,
+    fields: TsigFields,
+}
+
+/// Sends dynamic updates for one zone to one server.
+#[derive(Debug, Clone)]
+pub(crate) struct UpdateClient {
+    server: SocketAddr,
+    zone: Name,
+    key: Option<TsigKey>,
+}
+
+impl UpdateClient {
+    pub(crate) fn new(config: &WideAreaConfig) -> Result<Self> {
+        let key = config
+            .tsig
+            .as_ref()
+            .map(|tsig| TsigKey::new(&tsig.key_name, &tsig.algorithm, &tsig.secret))
+            .transpose()?;
+        Ok(Self {
+            server: config.server_address()?,
+            zone: Name::parse(&config.zone)?,
+            key,
+        })
+    }
+
+    pub(crate) fn server(&self) -> SocketAddr {
+        self.server
+    }
+
+    pub(crate) fn zone(&self) -> &Name {
+        &self.zone
+    }
+
+    /// Send `updates` as one atomic UPDATE and wait up to `timeout` for the reply.
+    pub(crate) fn send(&self, updates: &[Update], timeout: Duration) -> Result<()> {
+        let id = message_id();
+        let mut message = Vec::new();
+        message.extend_from_slice(&id.to_be_bytes());
+        message.extend_from_slice(&(OPCODE_UPDATE << 11).to_be_bytes());
+        // One zone, no prerequisites, the updates, no additional records yet
+        for count in [1, 0, updates.len() as u16, 0] {
+            message.extend_from_slice(&count.to_be_bytes());
+        }
+        self.zone.write(&mut message);
+        message.extend_from_slice(&TYPE_SOA.to_be_bytes());
+        message.extend_from_slice(&CLASS_IN.to_be_bytes());
+        for update in updates {
+            update.write(&mut message);
+        }
+
+        let request_mac = self
+            .key
+            .as_ref()
+            .map(|key| key.sign(&mut message, unix_time()));
+        let response = self
+            .exchange(id, &message, timeout)
+            .map_err(|e| self.error(e.to_string()))?;
+        self.check_response(&response, id, request_mac.as_deref())
+            .map_err(|reason| self.error(reason))
+    }
+
+    fn exchange(&self, id: u16, message: &[u8], timeout: Duration) -> io::Result<Vec<u8>> {
+        if message.len() <= MAX_UDP_MESSAGE {
+            let response = self.exchange_udp(id, message, timeout)?;
+            if get_u16(&response, 2).unwrap_or(0) & FLAG_TC == 0 {
+                return Ok(response);
+            }
+        }
+        self.exchange_tcp(message, timeout)
+    }
+
+    fn exchange_udp(&self, id: u16, message: &[u8], timeout: Duration) -> io::Result<Vec<u8>> {
+        let local: SocketAddr = match self.server {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(self.server)?;
+        socket.send(message)?;
+
+        let deadline = Instant::now() + timeout;
+        let mut buf = vec![0; 65_535];
+        loop {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            if remaining.is_zero() {
+                return Err(no_reply(timeout));
+            }
+            socket.set_read_timeout(Some(remaining))?;
+            let len = match socket.recv(&mut buf) {
+                Ok(len) => len,
+                Err(e)
+                    if matches!(
+                        e.kind(),
+                        io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                    ) =>
+                {
+                    return Err(no_reply(timeout));
+                }
+                Err(e) => return Err(e),
+            };
+            // Ignore anything that is not the reply to this request
+            if len >= HEADER_LEN && get_u16(&buf, 0) == Some(id) {
+                buf.truncate(len);
+                return Ok(buf);
+            }
+        }
+    }
+
+    fn exchange_tcp(&self, message: &[u8], timeout: Duration) -> io::Result<Vec<u8>> {
+        let mut stream = TcpStream::connect_timeout(&self.server, timeout)?;
+        stream.set_read_timeout(Some(timeout))?;
+        stream.set_write_timeout(Some(timeout))?;
+
+        let mut framed = (message.len() as u16).to_be_bytes().to_vec();
+        framed.extend_from_slice(message);
+        stream.write_all(&framed)?;
+
+        let mut len = [0; 2];
+        stream.read_exact(&mut len)?;
+        let mut response = vec![0; u16::from_be_bytes(len).into()];
+        stream.read_exact(&mut response)?;
+        Ok(response)
+    }
+
+    fn check_response(
+        &self,
+        response: &[u8],
+        id: u16,
+        request_mac: Option<&[u8]>,
+    ) -> std::result::Result<(), String> {
+        let flags = get_u16(response, 2).ok_or("truncated reply")?;
+        if get_u16(response, 0) != Some(id)
+            || flags & FLAG_QR == 0
+            || (flags >> 11) & 0xF != OPCODE_UPDATE
+        {
+            return Err("reply does not match the update".to_string());
+        }
+        let tsig = find_tsig(response);
+
+        let rcode = flags & 0xF;
+        if rcode != 0 {
+            let tsig_error = tsig
+                .as_ref()
+                .map(|tsig| tsig.fields.error)
+                .filter(|&error| error != 0)
+                .map(|error| format!(" (TSIG {})", rcode_name(error)))
+                .unwrap_or_default();
+            return Err(format!(
+                "server answered {}{}",
+                rcode_name(rcode),
+                tsig_error
+            ));
+        }
+
+        if let (Some(key), Some(request_mac)) = (&self.key, request_mac) {
+            let tsig = tsig.ok_or("reply is not signed")?;
+            if !key.verify(response, &tsig, request_mac) {
+                return Err("reply signature does not verify".to_string());
+            }
+            if unix_time().abs_diff(tsig.fields.time_signed) > u64::from(tsig.fields.fudge) {
+                return Err("reply signature is outside the allowed time window".to_string());
+            }
+        }
+        Ok(())
+    }
+
+    fn error(&self, reason: String) -> MdnsError {
+        MdnsError::DnsUpdate {
+            server: self.server,
+            zone: self.zone.to_string(),
+            reason,
+        }
+    }
+}
+
+/// Locate and parse the TSIG record, which must be the last additional record.
+fn find_tsig(message: &[u8]) -> Option<Tsig> {
+    let additional = get_u16(message, 10)?;
+    if additional == 0 {
+        return None;
+    }
+    let mut pos = HEADER_LEN;
+    for _ in 0..get_u16(message, 4)? {
+        pos = skip_name(message, pos)? + 4;
+    }
+    let records = get_u16(message, 6)? as usize + get_u16(message, 8)? as usize;
+    for _ in 0..records + additional as usize - 1 {
+        pos = skip_name(message, pos)? + 8;
+        pos += get_u16(message, pos)? as usize + 2;
+    }
+
+    let start = pos;
+    pos = skip_name(message, pos)?;
+    if get_u16(message, pos)? != TYPE_TSIG {
+        return None;
+    }
+    pos = skip_name(message, pos + 10)?;
+    let time_signed = (u64::from(get_u16(message, pos)?) << 32)
+        | u64::from(u32::from_be_bytes(
+            message.get(pos + 2..pos + 6)?.try_into().ok()?,
+        ));
+    let fudge = get_u16(message, pos + 6)?;
+    let mac_len = get_u16(message, pos + 8)? as usize;
+    pos += 10;
+    let mac = message.get(pos..pos + mac_len)?.to_vec();
+    pos += mac_len;
+    let original_id = get_u16(message, pos)?;
+    let error = get_u16(message, pos + 2)?;
+    let other_len = get_u16(message, pos + 4)? as usize;
+    let other = message.get(pos + 6..pos + 6 + other_len)?.to_vec();
+
+    Some(Tsig {
+        start,
+        original_id,
+        fields: TsigFields {
+            time_signed,
+            fudge,
+            mac,
+            error,
+            other,
+        },
+    })
+}
+
+fn skip_name(message: &[u8], mut pos: usize) -> Option<usize> {
+    loop {
+        let len = *message.get(pos)?;
+        match len {
+            0 => return Some(pos + 1),
+            // Compression pointer ends the name
+            len if len & 0xC0 == 0xC0 => return Some(pos + 2),
+            len => pos += len as usize + 1,
+        }
+    }
+}
+
+fn rcode_name(rcode: u16) -> String {
+    match rcode {
+        1 => "FORMERR".to_string(),
+        2 => "SERVFAIL".to_string(),
+        3 => "NXDOMAIN".to_string(),
+        4 => "NOTIMP".to_string(),
+        5 => "REFUSED".to_string(),
+        6 => "YXDOMAIN".to_string(),
+        7 => "YXRRSET".to_string(),
+        8 => "NXRRSET".to_string(),
+        9 => "NOTAUTH".to_string(),
+        10 => "NOTZONE".to_string(),
+        16 => "BADSIG".to_string(),
+        17 => "BADKEY".to_string(),
+        18 => "BADTIME".to_string(),
+        rcode => format!("rcode {}", rcode),
+    }
+}
+
+fn with_length(out: &mut Vec<u8>, write: impl FnOnce(&mut Vec<u8>)) {
+    let at = out.len();
+    out.extend_from_slice(&[0, 0]);
+    write(out);
+    let len = (out.len() - at - 2) as u16;
+    out[at..at + 2].copy_from_slice(&len.to_be_bytes());
+}
+
+fn get_u16(message: &[u8], at: usize) -> Option<u16> {
+    Some(u16::from_be_bytes(
+        message.get(at..at + 2)?.try_into().ok()?,
+    ))
+}
+
+fn set_count(message: &mut [u8], at: usize, count: u16) {
+    message[at..at + 2].copy_from_slice(&count.to_be_bytes());
+}
+
+fn no_reply(timeout: Duration) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::TimedOut,
+        format!("no reply within {:?}", timeout),
+    )
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0)
+}
+
+/// Message ID that is hard to guess without pulling in a random number generator.
+fn message_id() -> u16 {
+    let nanos = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.subsec_nanos())
+        .unwrap_or(0);
+    (nanos ^ (nanos >> 16) ^ std::process::id()) as u16
+}
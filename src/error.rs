@@ -1,5 +1,6 @@
 use std::io;
 use thiserror::Error;
+#[cfg(all(windows, feature = "windows"))]
 use windows::core::Error as WinError;
 
 #[derive(Error, Debug)]
@@ -13,32 +14,157 @@ pub enum MdnsError {
     #[error("JSON error: {0}")]
     Json(#[from] serde_json::Error),
 
+    /// A config file is not well-formed JSON, TOML or YAML
+    #[error("Configuration syntax error in {}:{line}:{column}: {message}", path.display())]
+    ConfigSyntax {
+        path: std::path::PathBuf,
+        line: usize,
+        column: usize,
+        message: String,
+    },
+
+    /// A config file or its directory can be changed by non-administrators
+    #[error(
+        "{} is writable by {writer}; restrict it to administrators, e.g. with `mdns_responder secure-config`",
+        path.display()
+    )]
+    ConfigPermissions {
+        path: std::path::PathBuf,
+        writer: String,
+    },
+
     #[error("mDNS service error: {0}")]
     Service(String),
 
+    /// An `mdns-sd` call failed
+    #[error("mDNS daemon failed to {operation}{}: {source}", for_service(.service))]
+    Daemon {
+        /// What was being attempted, e.g. `register` or `browse`
+        operation: &'static str,
+        /// Full service name or type the operation was for, if any
+        service: Option<String>,
+        #[source]
+        source: mdns_sd::Error,
+    },
+
+    #[cfg(all(windows, feature = "windows"))]
     #[error("Windows error: {0}")]
     Windows(#[from] WinError),
 
+    /// A unicast DNS update was refused or went unanswered
+    #[error("DNS update of {zone} via {server} failed: {reason}")]
+    DnsUpdate {
+        server: std::net::SocketAddr,
+        zone: String,
+        reason: String,
+    },
+
+    #[error("Timed out: {0}")]
+    Timeout(String),
+
     #[error("Thread error: {0}")]
     Thread(String),
 
-    #[error("Service error: {0}")]
-    ServiceDispatcher(String),
+    /// Another mDNS responder runs on this host and the config is strict about it
+    #[error("Another mDNS responder is running: {0}")]
+    Coexistence(String),
+
+    /// An avahi-daemon D-Bus call failed
+    #[cfg(all(unix, feature = "avahi"))]
+    #[error("avahi-daemon failed to {operation}{}: {source}", for_service(.service))]
+    Avahi {
+        /// What was being attempted, e.g. `add service` or `commit`
+        operation: &'static str,
+        /// Full service name the operation was for, if any
+        service: Option<String>,
+        #[source]
+        source: zbus::Error,
+    },
+
+    /// A Service Control Manager call failed
+    #[cfg(all(windows, feature = "windows"))]
+    #[error("Service control manager failed to {operation}: {source}")]
+    ServiceDispatcher {
+        operation: &'static str,
+        #[source]
+        source: windows_service::Error,
+    },
 
+    #[cfg(windows)]
     #[error("Network adapter error: {0}")]
-    IpConfig(String),
+    IpConfig(#[from] ipconfig::error::Error),
 }
 
 pub type Result<T> = std::result::Result<T, MdnsError>;
 
-impl From<windows_service::Error> for MdnsError {
-    fn from(err: windows_service::Error) -> Self {
-        MdnsError::ServiceDispatcher(err.to_string())
+impl MdnsError {
+    /// Stable numeric code for this kind of error.
+    ///
+    /// Used as the service-specific exit code of the Windows service and in
+    /// control channel replies. Codes are never reused or renumbered.
+    pub fn code(&self) -> u32 {
+        match self {
+            MdnsError::ConfigValidation(_) => 10,
+            MdnsError::Io(_) => 11,
+            MdnsError::Json(_) => 12,
+            MdnsError::ConfigSyntax { .. } => 13,
+            MdnsError::ConfigPermissions { .. } => 14,
+            MdnsError::Service(_) => 20,
+            MdnsError::Daemon { .. } => 21,
+            MdnsError::Timeout(_) => 22,
+            MdnsError::Thread(_) => 23,
+            MdnsError::DnsUpdate { .. } => 24,
+            MdnsError::Coexistence(_) => 25,
+            #[cfg(all(unix, feature = "avahi"))]
+            MdnsError::Avahi { .. } => 26,
+            #[cfg(all(windows, feature = "windows"))]
+            MdnsError::Windows(_) => 30,
+            #[cfg(all(windows, feature = "windows"))]
+            MdnsError::ServiceDispatcher { .. } => 31,
+            #[cfg(windows)]
+            MdnsError::IpConfig(_) => 32,
+        }
+    }
+
+    /// `map_err` adapter recording which daemon operation failed and for which service.
+    pub(crate) fn daemon(
+        operation: &'static str,
+        service: Option<&str>,
+    ) -> impl FnOnce(mdns_sd::Error) -> Self {
+        let service = service.map(str::to_string);
+        move |source| MdnsError::Daemon {
+            operation,
+            service,
+            source,
+        }
     }
-}
 
-impl From<ipconfig::error::Error> for MdnsError {
-    fn from(err: ipconfig::error::Error) -> Self {
-        MdnsError::IpConfig(err.to_string())
+    /// `map_err` adapter recording which avahi-daemon call failed and for which service.
+    #[cfg(all(unix, feature = "avahi"))]
+    pub(crate) fn avahi(
+        operation: &'static str,
+        service: Option<&str>,
+    ) -> impl FnOnce(zbus::Error) -> Self {
+        let service = service.map(str::to_string);
+        move |source| MdnsError::Avahi {
+            operation,
+            service,
+            source,
+        }
     }
+
+    /// `map_err` adapter recording which Service Control Manager call failed.
+    #[cfg(all(windows, feature = "service-host"))]
+    pub(crate) fn dispatcher(
+        operation: &'static str,
+    ) -> impl FnOnce(windows_service::Error) -> Self {
+        move |source| MdnsError::ServiceDispatcher { operation, source }
+    }
+}
+
+fn for_service(service: &Option<String>) -> String {
+    service
+        .as_deref()
+        .map(|service| format!(" for {}", service))
+        .unwrap_or_default()
 }
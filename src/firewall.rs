@@ -0,0 +1,111 @@
+use crate::error::{MdnsError, Result};
+use log::{error, info};
+use std::os::windows::process::CommandExt;
+use std::path::Path;
+use std::process::Command;
+use std::str::FromStr;
+
+/// Display name of the inbound rule created by `install`
+pub const RULE_NAME: &str = "mDNS Responder (UDP 5353)";
+
+/// Windows Firewall profile a rule applies in.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    Domain,
+    Private,
+    Public,
+}
+
+impl Profile {
+    /// Profiles covered unless others are chosen; public networks stay closed
+    pub const DEFAULT: [Profile; 2] = [Profile::Domain, Profile::Private];
+
+    fn as_str(&self) -> &'static str {
+        match self {
+            Profile::Domain => "domain",
+            Profile::Private => "private",
+            Profile::Public => "public",
+        }
+    }
+
+    /// Parse a comma-separated list such as `domain,private`.
+    pub fn parse_list(list: &str) -> Result<Vec<Profile>> {
+        list.split(',').map(str::trim).map(str::parse).collect()
+    }
+}
+
+impl FromStr for Profile {
+    type Err = MdnsError;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name.to_ascii_lowercase().as_str() {
+            "domain" => Ok(Profile::Domain),
+            "private" => Ok(Profile::Private),
+            "public" => Ok(Profile::Public),
+            _ => Err(MdnsError::Service(format!(
+                "unknown firewall profile '{}', expected domain, private or public",
+                name
+            ))),
+        }
+    }
+}
+
+/// Allow inbound UDP 5353 to `program` in `profiles`, replacing the rule
+/// from an earlier install.
+pub fn add_rule(program: &Path, profiles: &[Profile]) -> Result<()> {
+    remove_rule()?;
+    let profiles: Vec<&str> = profiles.iter().map(Profile::as_str).collect();
+    info!(
+        "Adding firewall rule '{}' for {:?} ({})",
+        RULE_NAME,
+        program,
+        profiles.join(",")
+    );
+    let output = netsh(&[
+        "add",
+        "rule",
+        &format!("name=\"{}\"", RULE_NAME),
+        "dir=in",
+        "action=allow",
+        &format!("program=\"{}\"", program.display()),
+        "protocol=UDP",
+        "localport=5353",
+        &format!("profile={}", profiles.join(",")),
+    ])?;
+    if !output.status.success() {
+        // netsh reports its errors on stdout
+        error!(
+            "Failed to add firewall rule: {}",
+            String::from_utf8_lossy(&output.stdout)
+        );
+        return Err(MdnsError::Service(
+            "Firewall rule creation failed".to_string(),
+        ));
+    }
+    Ok(())
+}
+
+/// Delete the rule, returning whether there was one.
+pub fn remove_rule() -> Result<bool> {
+    // netsh fails when no rule matches, so there is nothing to report
+    let output = netsh(&["delete", "rule", &format!("name=\"{}\"", RULE_NAME)])?;
+    Ok(output.status.success())
+}
+
+/// Whether the rule exists; its output is localised, so only the exit
+/// status is looked at.
+pub fn rule_exists() -> Result<bool> {
+    let output = netsh(&["show", "rule", &format!("name=\"{}\"", RULE_NAME)])?;
+    Ok(output.status.success())
+}
+
+/// Run `netsh advfirewall firewall` with `args` passed through verbatim,
+/// since netsh wants `key="value with spaces"` rather than quoted arguments.
+fn netsh(args: &[&str]) -> Result<std::process::Output> {
+    let mut command = Command::new("netsh");
+    command.args(["advfirewall", "firewall"]);
+    for arg in args {
+        command.raw_arg(arg);
+    }
+    Ok(command.output()?)
+}
@@ -1,10 +1,44 @@
+mod announcer;
+#[cfg(feature = "tokio")]
+pub mod async_responder;
+#[cfg(all(unix, feature = "avahi"))]
+mod avahi;
+pub mod coexistence;
 pub mod config;
+#[cfg(feature = "service-host")]
+pub mod control;
+#[cfg(feature = "discovery")]
 pub mod discovery;
+#[cfg(all(windows, feature = "windows"))]
+mod dns_service;
+#[cfg(feature = "wide-area")]
+mod dns_update;
 pub mod error;
+#[cfg(all(windows, feature = "service-host"))]
+pub mod firewall;
+#[cfg(feature = "service-host")]
+pub mod logging;
 pub mod mdns_service;
+pub mod metrics;
+pub mod permissions;
+pub mod publisher;
+#[cfg(feature = "reflector")]
+mod reflector;
+pub mod responder;
+#[cfg(feature = "discovery")]
+pub mod selfcheck;
+mod slot;
+#[cfg(all(unix, feature = "service-host"))]
+pub mod systemd_service;
+#[cfg(feature = "wide-area")]
+mod wide_area;
+#[cfg(all(windows, feature = "service-host"))]
 pub mod windows_service;
 
+#[cfg(feature = "tokio")]
+pub use async_responder::AsyncResponder;
 pub use error::{MdnsError, Result};
+pub use responder::Responder;
 
 #[cfg(test)]
 mod tests {
@@ -33,7 +67,7 @@ mod tests {
         let (shutdown_tx, shutdown_rx) = std::sync::mpsc::channel();
 
         let service_thread = std::thread::spawn(move || {
-            run(Some(shutdown_rx), Some(test_config)).unwrap();
+            run(shutdown_rx, Some(test_config)).unwrap();
         });
 
         std::thread::sleep(Duration::from_secs(3)); // Give more time
@@ -68,4 +102,1675 @@ mod tests {
             unique_instance, expected_fullname
         );
     }
+
+    #[test]
+    fn test_responder_lifecycle() {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let config = crate::config::ServiceConfig {
+            instance_name: format!("Responder-{}", timestamp & 0xFFFF),
+            service_name: "_test._tcp.local.".to_string(),
+            bind_address: Some("127.0.0.1".to_string()),
+            ..Default::default()
+        };
+
+        let mut responder = crate::Responder::start(config.clone()).unwrap();
+        let primary = responder.status().primary.unwrap();
+
+        // Adding the same instance twice is rejected
+        assert!(responder.add_service(config.clone()).is_err());
+
+        let extra = responder
+            .add_service(crate::config::ServiceConfig {
+                instance_name: format!("{}-extra", config.instance_name),
+                port: 8445,
+                ..config.clone()
+            })
+            .unwrap();
+
+        let status = responder.status();
+        assert!(status.daemon_running);
+        assert_eq!(status.services.len(), 2);
+        assert!(
+            status
+                .services
+                .iter()
+                .any(|s| s.fullname == extra && s.port == 8445)
+        );
+
+        responder.remove_service(&extra).unwrap();
+        assert!(responder.remove_service(&extra).is_err());
+
+        responder
+            .update(crate::config::ServiceConfig {
+                port: 9445,
+                ..config
+            })
+            .unwrap();
+        let status = responder.status();
+        assert_eq!(status.primary.as_deref(), Some(primary.as_str()));
+        assert_eq!(status.services.len(), 1);
+        assert_eq!(status.services[0].port, 9445);
+
+        responder.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[test]
+    fn test_responder_calls_publisher() {
+        use crate::publisher::{FakePublisher, PublisherCall::*};
+
+        let config = crate::config::ServiceConfig {
+            instance_name: "Fake".to_string(),
+            service_name: "_test._tcp.local.".to_string(),
+            bind_address: Some("127.0.0.1".to_string()),
+            ..Default::default()
+        };
+        let primary = "Fake._test._tcp.local.".to_string();
+        let extra = "Fake-extra._test._tcp.local.".to_string();
+        let fake = FakePublisher::default();
+        let mut responder =
+            crate::Responder::with_publisher(config.clone(), Box::new(fake.clone())).unwrap();
+        assert!(responder.status().daemon_running);
+
+        responder
+            .add_service(crate::config::ServiceConfig {
+                instance_name: "Fake-extra".to_string(),
+                ..config.clone()
+            })
+            .unwrap();
+        responder.reannounce().unwrap();
+        responder.remove_service(&extra).unwrap();
+        responder
+            .update(crate::config::ServiceConfig {
+                port: 9445,
+                ..config.clone()
+            })
+            .unwrap();
+        // A bad reload keeps the current service advertised
+        assert!(
+            responder
+                .update(crate::config::ServiceConfig {
+                    instance_name: String::new(),
+                    ..config
+                })
+                .is_err()
+        );
+        assert_eq!(
+            responder.status().primary.as_deref(),
+            Some(primary.as_str())
+        );
+        assert_eq!(responder.status().services[0].port, 9445);
+        // Without a daemon there is nothing to browse with
+        #[cfg(feature = "discovery")]
+        assert!(responder.browse_cache().is_err());
+
+        responder.shutdown(Duration::from_secs(1)).unwrap();
+        responder.shutdown(Duration::from_secs(1)).unwrap();
+        assert!(!responder.status().daemon_running);
+        assert!(responder.reannounce().is_err());
+        assert_eq!(
+            fake.calls(),
+            [
+                Register(primary.clone()),
+                Register(extra.clone()),
+                Reannounce(extra.clone()),
+                Reannounce(primary.clone()),
+                Unregister(extra.clone()),
+                Unregister(primary.clone()),
+                Register(primary.clone()),
+                Unregister(primary),
+                Shutdown,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_failed_unregister_keeps_service_tracked() {
+        use crate::publisher::FakePublisher;
+
+        let config = crate::config::ServiceConfig {
+            instance_name: "Stuck".to_string(),
+            service_name: "_test._tcp.local.".to_string(),
+            bind_address: Some("127.0.0.1".to_string()),
+            ..Default::default()
+        };
+        let primary = "Stuck._test._tcp.local.";
+        let fake = FakePublisher::default();
+        let mut responder =
+            crate::Responder::with_publisher(config.clone(), Box::new(fake.clone())).unwrap();
+
+        fake.fail_unregister(true);
+        assert!(responder.remove_service(primary).is_err());
+        assert!(
+            responder
+                .update(crate::config::ServiceConfig {
+                    port: 9447,
+                    ..config
+                })
+                .is_err()
+        );
+        let status = responder.status();
+        assert_eq!(status.primary.as_deref(), Some(primary));
+        assert_eq!(status.services.len(), 1);
+        assert_eq!(status.services[0].port, 445);
+
+        fake.fail_unregister(false);
+        responder.remove_service(primary).unwrap();
+        assert!(responder.status().services.is_empty());
+    }
+
+    #[test]
+    fn test_reload_ignores_default_fallback() {
+        use crate::mdns_service::{self, ConfigFallback};
+        use crate::publisher::FakePublisher;
+
+        mdns_service::set_config_fallback(ConfigFallback::Defaults);
+        let config = crate::config::ServiceConfig {
+            instance_name: "Reload".to_string(),
+            service_name: "_test._tcp.local.".to_string(),
+            bind_address: Some("127.0.0.1".to_string()),
+            ..Default::default()
+        };
+        let mut responder =
+            crate::Responder::with_publisher(config, Box::new(FakePublisher::default())).unwrap();
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        std::fs::write(&path, "{ \"instance_name\": ").unwrap();
+        assert!(mdns_service::reload_from(&mut responder, &path).is_err());
+
+        let status = responder.status();
+        assert_eq!(status.primary.as_deref(), Some("Reload._test._tcp.local."));
+        assert_eq!(status.services.len(), 1);
+    }
+
+    #[test]
+    fn test_shutdown_sends_goodbye() {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let unique_instance = format!("Goodbye-Instance-{}", timestamp & 0xFFFF);
+        let service_name = "_test._tcp.local.".to_string();
+        let expected_fullname = format!("{}.{}", unique_instance, service_name);
+
+        let mut responder = crate::Responder::start(crate::config::ServiceConfig {
+            instance_name: unique_instance,
+            service_name: service_name.clone(),
+            ..Default::default()
+        })
+        .unwrap();
+
+        let mdns = ServiceDaemon::new().unwrap();
+        let receiver = mdns.browse(&service_name).unwrap();
+
+        let mut resolved = false;
+        for _ in 0..50 {
+            if let Ok(ServiceEvent::ServiceResolved(info)) =
+                receiver.recv_timeout(Duration::from_millis(100))
+            {
+                if info.get_fullname() == expected_fullname {
+                    resolved = true;
+                    break;
+                }
+            }
+        }
+        assert!(resolved, "'{}' was not resolved", expected_fullname);
+
+        responder.shutdown(Duration::from_secs(5)).unwrap();
+
+        // Goodbye packets remove the record long before its TTL expires
+        let mut removed = false;
+        for _ in 0..50 {
+            if let Ok(ServiceEvent::ServiceRemoved(_, fullname)) =
+                receiver.recv_timeout(Duration::from_millis(100))
+            {
+                if fullname == expected_fullname {
+                    removed = true;
+                    break;
+                }
+            }
+        }
+
+        mdns.shutdown().ok();
+
+        assert!(removed, "no goodbye seen for '{}'", expected_fullname);
+    }
+
+    #[test]
+    fn test_metrics_endpoint() {
+        use crate::metrics::MetricsServer;
+        use std::io::{Read, Write};
+        use std::sync::{Arc, Mutex};
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let config = crate::config::ServiceConfig {
+            instance_name: format!("Metrics-{}", timestamp & 0xFFFF),
+            service_name: "_test._tcp.local.".to_string(),
+            bind_address: Some("127.0.0.1".to_string()),
+            metrics_address: Some("127.0.0.1:0".to_string()),
+            ..Default::default()
+        };
+        assert!(
+            crate::config::ServiceConfig {
+                metrics_address: Some("localhost".to_string()),
+                ..config.clone()
+            }
+            .validate()
+            .is_err()
+        );
+        // Only loopback unless remote scraping is asked for
+        let public = crate::config::ServiceConfig {
+            metrics_address: Some("0.0.0.0:9464".to_string()),
+            ..config.clone()
+        };
+        assert!(public.validate().is_err());
+        crate::config::ServiceConfig {
+            metrics_allow_remote: true,
+            ..public
+        }
+        .validate()
+        .unwrap();
+
+        let responder = Arc::new(Mutex::new(crate::Responder::start(config).unwrap()));
+        responder.lock().unwrap().reannounce().unwrap();
+        let server = MetricsServer::start("127.0.0.1:0", Arc::clone(&responder)).unwrap();
+
+        let get = |path: &str| {
+            let mut stream = std::net::TcpStream::connect(server.local_addr()).unwrap();
+            write!(stream, "GET {} HTTP/1.1\r\nHost: localhost\r\n\r\n", path).unwrap();
+            let mut response = String::new();
+            stream.read_to_string(&mut response).unwrap();
+            response
+        };
+
+        let response = get("/metrics");
+        assert!(response.starts_with("HTTP/1.1 200 OK"), "{}", response);
+        assert!(response.contains("\nmdns_responder_up 1\n"));
+        assert!(response.contains("\nmdns_responder_services 1\n"));
+        assert!(response.contains("\nmdns_responder_registrations_total 1\n"));
+        assert!(response.contains("\nmdns_responder_reannounces_total 1\n"));
+        assert!(response.contains("mdns_responder_daemon{counter=\"register\"}"));
+        assert!(get("/").starts_with("HTTP/1.1 404"));
+
+        // Oversized and slowly trickled requests are both cut off
+        let mut oversized = std::net::TcpStream::connect(server.local_addr()).unwrap();
+        write!(oversized, "GET /{} HTTP/1.1\r\n", "x".repeat(16_384)).unwrap();
+        let mut response = String::new();
+        if oversized.read_to_string(&mut response).is_ok() {
+            assert!(response.starts_with("HTTP/1.1 431"), "{}", response);
+        }
+        let mut slow = std::net::TcpStream::connect(server.local_addr()).unwrap();
+        slow.set_read_timeout(Some(Duration::from_secs(15)))
+            .unwrap();
+        let mut writer = slow.try_clone().unwrap();
+        std::thread::spawn(move || {
+            for byte in b"GET /metrics HTTP/1.1\r\nX-Slow: ".iter().cycle() {
+                if writer.write_all(&[*byte]).is_err() {
+                    break;
+                }
+                std::thread::sleep(Duration::from_millis(500));
+            }
+        });
+        let started = std::time::Instant::now();
+        let mut response = String::new();
+        let read = slow.read_to_string(&mut response);
+        assert!(started.elapsed() < Duration::from_secs(10));
+        assert!(read.is_err() || response.is_empty(), "{}", response);
+
+        drop(server);
+        responder
+            .lock()
+            .unwrap()
+            .shutdown(Duration::from_secs(5))
+            .unwrap();
+    }
+
+    #[test]
+    fn test_interface_scoping() {
+        use crate::config::InterfaceScope;
+        use std::net::IpAddr;
+
+        let ip = |s: &str| s.parse::<IpAddr>().unwrap();
+        let office = InterfaceScope::parse("10.1.0.0/16").unwrap();
+        assert!(office.matches("eth1", ip("10.1.200.7")));
+        assert!(!office.matches("wlan0", ip("10.2.0.7")));
+        assert!(!office.matches("eth1", ip("fe80::1")));
+        assert!(
+            InterfaceScope::parse("0.0.0.0/0")
+                .unwrap()
+                .matches("x", ip("8.8.8.8"))
+        );
+        assert!(
+            InterfaceScope::parse("fd00::/8")
+                .unwrap()
+                .matches("x", ip("fd12::1"))
+        );
+        assert!(
+            InterfaceScope::parse("ETH0")
+                .unwrap()
+                .matches("eth0", ip("10.0.0.1"))
+        );
+        assert!(InterfaceScope::parse("10.1.0.0/33").is_err());
+        assert!(InterfaceScope::parse("office/16").is_err());
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let config = crate::config::ServiceConfig {
+            instance_name: format!("Scoped-{}", timestamp & 0xFFFF),
+            service_name: "_test._tcp.local.".to_string(),
+            interfaces: vec!["127.0.0.0/8".to_string()],
+            ..Default::default()
+        };
+        let mut responder = crate::Responder::start(config.clone()).unwrap();
+        assert_eq!(
+            responder.status().services[0].addresses,
+            vec![ip("127.0.0.1")]
+        );
+        responder.shutdown(Duration::from_secs(5)).unwrap();
+
+        // A scope matching no local interface is a configuration error
+        let err = crate::Responder::start(crate::config::ServiceConfig {
+            interfaces: vec!["203.0.113.0/24".to_string()],
+            ..config
+        })
+        .err()
+        .unwrap();
+        assert_eq!(err.code(), 10);
+    }
+
+    #[test]
+    fn test_extra_announcements() {
+        use crate::config::{AnnounceConfig, ServiceConfig};
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let config = ServiceConfig {
+            instance_name: format!("Announce-{}", timestamp & 0xFFFF),
+            service_name: "_test._tcp.local.".to_string(),
+            bind_address: Some("127.0.0.1".to_string()),
+            announce: AnnounceConfig {
+                probe: false,
+                announcements: 4,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        assert_eq!(config.announce.extra_rounds(), 1);
+        for (announcements, interval) in [(1, 0), (9, 0), (2, 30), (2, 100_000)] {
+            let invalid = ServiceConfig {
+                announce: AnnounceConfig {
+                    announcements,
+                    reannounce_interval_secs: interval,
+                    ..Default::default()
+                },
+                ..config.clone()
+            };
+            assert!(invalid.validate().is_err());
+        }
+
+        let mut responder = crate::Responder::start(config).unwrap();
+        let registers = |responder: &crate::Responder| {
+            responder
+                .metrics()
+                .lines()
+                .find_map(|line| line.strip_prefix("mdns_responder_daemon{counter=\"register\"} "))
+                .map(|count| count.parse::<u32>().unwrap())
+        };
+        assert_eq!(registers(&responder), Some(1));
+        std::thread::sleep(Duration::from_millis(3500));
+        // The second announcement pair re-registers once, then stops
+        assert_eq!(registers(&responder), Some(2));
+        responder.shutdown(Duration::from_secs(5)).unwrap();
+    }
+
+    #[cfg(feature = "reflector")]
+    #[test]
+    fn test_record_ttls() {
+        use crate::config::{AnnounceConfig, ServiceConfig};
+        use crate::reflector::Message;
+        use socket2::{Domain, Protocol, Socket, Type};
+        use std::collections::BTreeMap;
+        use std::net::{Ipv4Addr, SocketAddrV4, UdpSocket};
+        use std::time::Instant;
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let instance = format!("Ttl-{}", timestamp & 0xFFFF);
+        let hostname = format!("ttl-host-{}", timestamp & 0xFFFF);
+        let config = ServiceConfig {
+            instance_name: instance.clone(),
+            hostname: hostname.clone(),
+            service_name: "_test._tcp.local.".to_string(),
+            bind_address: Some("127.0.0.1".to_string()),
+            announce: AnnounceConfig {
+                probe: false,
+                host_ttl_secs: 30,
+                other_ttl_secs: 600,
+                ..Default::default()
+            },
+            ..Default::default()
+        };
+        for (host_ttl_secs, other_ttl_secs) in [(5, 600), (30, 100_000)] {
+            let invalid = ServiceConfig {
+                announce: AnnounceConfig {
+                    host_ttl_secs,
+                    other_ttl_secs,
+                    ..config.announce.clone()
+                },
+                ..config.clone()
+            };
+            assert!(invalid.validate().is_err());
+        }
+
+        // Listen to the announcements alongside the daemon
+        let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP)).unwrap();
+        socket.set_reuse_address(true).unwrap();
+        #[cfg(unix)]
+        socket.set_reuse_port(true).unwrap();
+        socket
+            .bind(&SocketAddrV4::new(Ipv4Addr::UNSPECIFIED, 5353).into())
+            .unwrap();
+        let socket: UdpSocket = socket.into();
+        socket
+            .join_multicast_v4(&Ipv4Addr::new(224, 0, 0, 251), &Ipv4Addr::UNSPECIFIED)
+            .unwrap();
+        socket
+            .set_read_timeout(Some(Duration::from_millis(200)))
+            .unwrap();
+
+        let mut responder = crate::Responder::start(config).unwrap();
+        let owner = |labels: &[Vec<u8>]| {
+            labels
+                .iter()
+                .map(|label| String::from_utf8_lossy(label).to_lowercase())
+                .collect::<Vec<_>>()
+                .join(".")
+        };
+        let fullname = format!("{}._test._tcp.local", instance).to_lowercase();
+        let host = format!("{}.local", hostname);
+        // TTL seen per record type: SRV, TXT, A
+        let mut seen = BTreeMap::new();
+        let deadline = Instant::now() + Duration::from_secs(5);
+        let mut buf = [0u8; 9000];
+        while seen.len() < 3 && Instant::now() < deadline {
+            let Ok(len) = socket.recv(&mut buf) else {
+                continue;
+            };
+            let Some(message) = Message::parse(&buf[..len]) else {
+                continue;
+            };
+            for record in message.sections.iter().flatten() {
+                let name = owner(&record.name);
+                if (name == fullname && matches!(record.rtype, 33 | 16))
+                    || (name == host && record.rtype == 1)
+                {
+                    seen.insert(record.rtype, record.ttl);
+                }
+            }
+        }
+        responder.shutdown(Duration::from_secs(5)).unwrap();
+
+        assert_eq!(seen.get(&33), Some(&30), "SRV TTL in {:?}", seen);
+        assert_eq!(seen.get(&1), Some(&30), "A TTL in {:?}", seen);
+        assert_eq!(seen.get(&16), Some(&600), "TXT TTL in {:?}", seen);
+    }
+
+    #[cfg(feature = "reflector")]
+    #[test]
+    fn test_reflector_relay() {
+        use crate::config::{ReflectorConfig, ServiceConfig};
+        use crate::mdns_service::LocalInterface;
+        use crate::reflector::{Labels, Message, Question, RData, Record, Relay};
+        use std::net::SocketAddr;
+        use std::time::Instant;
+
+        let name = |name: &str| -> Labels {
+            name.split('.')
+                .filter(|label| !label.is_empty())
+                .map(|label| label.as_bytes().to_vec())
+                .collect()
+        };
+        let record = |owner: &str, rtype: u16, rdata: RData| Record {
+            name: name(owner),
+            rtype,
+            class: 0x8001,
+            ttl: 120,
+            rdata,
+        };
+        let link = |name: &str, ip: &str| LocalInterface {
+            name: name.to_string(),
+            ip: ip.parse().unwrap(),
+            prefix_len: 24,
+        };
+        let config = ReflectorConfig {
+            interfaces: vec!["lan-a".to_string(), "lan-b".to_string()],
+            service_types: vec!["_smb._tcp.local.".to_string()],
+        };
+        assert!(
+            ServiceConfig {
+                reflector: Some(ReflectorConfig {
+                    interfaces: vec!["lan-a".to_string()],
+                    ..config.clone()
+                }),
+                ..Default::default()
+            }
+            .validate()
+            .is_err()
+        );
+
+        let mut relay = Relay::new(
+            &config,
+            vec![link("lan-a", "10.1.0.1"), link("lan-b", "10.2.0.1")],
+            ["10.1.0.1".parse().unwrap(), "10.2.0.1".parse().unwrap()]
+                .into_iter()
+                .collect(),
+        );
+        let from_a: SocketAddr = "10.1.0.5:5353".parse().unwrap();
+        let now = Instant::now();
+
+        let response = Message {
+            id: 0,
+            flags: 0x8400,
+            questions: Vec::new(),
+            sections: [
+                vec![
+                    record(
+                        "_smb._tcp.local",
+                        12,
+                        RData::Name(name("Nas._smb._tcp.local")),
+                    ),
+                    record(
+                        "_http._tcp.local",
+                        12,
+                        RData::Name(name("Printer._http._tcp.local")),
+                    ),
+                ],
+                Vec::new(),
+                vec![
+                    record(
+                        "Nas._smb._tcp.local",
+                        33,
+                        RData::Srv {
+                            priority: 0,
+                            weight: 0,
+                            port: 445,
+                            target: name("nas.local"),
+                        },
+                    ),
+                    record("nas.local", 1, RData::Raw(vec![10, 1, 0, 5])),
+                    record("nas.local", 1, RData::Raw(vec![169, 254, 7, 7])),
+                    record("printer.local", 1, RData::Raw(vec![10, 1, 0, 9])),
+                ],
+            ],
+        };
+        let packet = response.encode();
+        assert_eq!(Message::parse(&packet).as_ref(), Some(&response));
+
+        let (from, relayed) = relay.relay(&packet, from_a, now).unwrap();
+        assert_eq!(from, 0);
+        let relayed = Message::parse(&relayed).unwrap();
+        assert_eq!(relayed.sections[0], vec![response.sections[0][0].clone()]);
+        // SRV and the routable address of its host; no link-local or unrelated host
+        assert_eq!(
+            relayed.sections[2],
+            response.sections[2][..2].to_vec(),
+            "{:?}",
+            relayed
+        );
+
+        // The same packet again, from the other side or from ourselves, is a loop
+        assert!(relay.relay(&packet, from_a, now).is_none());
+        let mut repeat = response.clone();
+        repeat.sections[2].truncate(2);
+        let echoed = repeat.encode();
+        assert!(
+            relay
+                .relay(&echoed, "10.2.0.7:5353".parse().unwrap(), now)
+                .is_none()
+        );
+        let later = now + Duration::from_secs(1);
+        assert!(
+            relay
+                .relay(&packet, "10.2.0.1:5353".parse().unwrap(), later)
+                .is_none()
+        );
+        // Legacy unicast and off-link senders are not relayed
+        assert!(
+            relay
+                .relay(&packet, "10.1.0.5:40000".parse().unwrap(), later)
+                .is_none()
+        );
+        assert!(
+            relay
+                .relay(&packet, "192.168.9.9:5353".parse().unwrap(), later)
+                .is_none()
+        );
+
+        let query = |owner: &str, qtype: u16| {
+            Message {
+                id: 0,
+                flags: 0,
+                questions: vec![Question {
+                    name: name(owner),
+                    qtype,
+                    qclass: 0x8001,
+                }],
+                sections: Default::default(),
+            }
+            .encode()
+        };
+        let from_b: SocketAddr = "10.2.0.7:5353".parse().unwrap();
+        let (from, relayed) = relay
+            .relay(&query("_smb._tcp.local", 12), from_b, later)
+            .unwrap();
+        assert_eq!(from, 1);
+        // The unicast-response bit is cleared so the answer comes back multicast
+        assert_eq!(Message::parse(&relayed).unwrap().questions[0].qclass, 1);
+        assert!(relay.relay(&query("nas.local", 1), from_b, later).is_some());
+        assert!(
+            relay
+                .relay(&query("_http._tcp.local", 12), from_b, later)
+                .is_none()
+        );
+    }
+
+    /// Stub DNS server answering each UPDATE with `rcode`. With a secret it
+    /// checks the request's hmac-sha256 TSIG from key `test-key.` and signs
+    /// its reply. Requests are passed on through the returned channel.
+    #[cfg(feature = "wide-area")]
+    fn stub_dns_server(
+        rcode: u8,
+        secret: Option<&'static [u8]>,
+    ) -> (std::net::SocketAddr, std::sync::mpsc::Receiver<Vec<u8>>) {
+        use hmac::{Hmac, Mac};
+        use sha2::Sha256;
+
+        const KEY_NAME: &[u8] = b"\x08test-key\x00";
+        const ALGORITHM: &[u8] = b"\x0bhmac-sha256\x00";
+        // Key name, fixed fields, algorithm, time, fudge, MAC, ID, error, other
+        const TSIG_LEN: usize = 10 + 10 + 13 + 6 + 2 + 2 + 32 + 2 + 2 + 2;
+        let mac = move |data: &[u8]| {
+            Hmac::<Sha256>::new_from_slice(secret.unwrap())
+                .unwrap()
+                .chain_update(data)
+                .finalize()
+                .into_bytes()
+                .to_vec()
+        };
+
+        let answer = move |request: &[u8]| {
+            let len = request.len();
+            let mut reply = vec![request[0], request[1], 0xA8, rcode];
+            reply.extend_from_slice(&[0; 8]);
+
+            if secret.is_some() {
+                let start = len - TSIG_LEN;
+                let tsig = &request[start..];
+                let mut signed = request[..start].to_vec();
+                signed[11] -= 1;
+                signed.extend_from_slice(&tsig[..10]);
+                signed.extend_from_slice(&tsig[12..18]);
+                signed.extend_from_slice(&tsig[20..41]);
+                signed.extend_from_slice(&tsig[TSIG_LEN - 4..]);
+                let request_mac = &tsig[43..75];
+                assert_eq!(mac(&signed), request_mac, "request signature");
+
+                let mut variables = KEY_NAME.to_vec();
+                variables.extend_from_slice(&[0, 255, 0, 0, 0, 0]);
+                variables.extend_from_slice(ALGORITHM);
+                let now = SystemTime::now()
+                    .duration_since(UNIX_EPOCH)
+                    .unwrap()
+                    .as_secs();
+                variables.extend_from_slice(&now.to_be_bytes()[2..]);
+                variables.extend_from_slice(&300u16.to_be_bytes());
+                let mut signed = vec![0, 32];
+                signed.extend_from_slice(request_mac);
+                signed.extend_from_slice(&reply);
+                signed.extend_from_slice(&variables);
+                signed.extend_from_slice(&[0, 0, 0, 0]);
+                let reply_mac = mac(&signed);
+
+                reply.extend_from_slice(KEY_NAME);
+                reply.extend_from_slice(&[0, 250, 0, 255, 0, 0, 0, 0, 0, 61]);
+                reply.extend_from_slice(ALGORITHM);
+                reply.extend_from_slice(&now.to_be_bytes()[2..]);
+                reply.extend_from_slice(&[1, 44, 0, 32]);
+                reply.extend_from_slice(&reply_mac);
+                reply.extend_from_slice(&[request[0], request[1], 0, 0, 0, 0]);
+                reply[11] = 1;
+            }
+
+            reply
+        };
+
+        // Large updates arrive over TCP on the same port
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let socket = std::net::UdpSocket::bind(address).unwrap();
+        let (tx, rx) = std::sync::mpsc::channel();
+        std::thread::spawn({
+            let tx = tx.clone();
+            move || {
+                use std::io::{Read, Write};
+                for mut stream in listener.incoming().flatten() {
+                    let mut len = [0; 2];
+                    stream.read_exact(&mut len).unwrap();
+                    let mut request = vec![0; u16::from_be_bytes(len).into()];
+                    stream.read_exact(&mut request).unwrap();
+                    let reply = answer(&request);
+                    stream
+                        .write_all(&(reply.len() as u16).to_be_bytes())
+                        .unwrap();
+                    stream.write_all(&reply).unwrap();
+                    if tx.send(request).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        std::thread::spawn(move || {
+            let mut buf = [0; 4096];
+            while let Ok((len, peer)) = socket.recv_from(&mut buf) {
+                let request = buf[..len].to_vec();
+                socket.send_to(&answer(&request), peer).unwrap();
+                if tx.send(request).is_err() {
+                    break;
+                }
+            }
+        });
+        (address, rx)
+    }
+
+    #[cfg(feature = "wide-area")]
+    #[test]
+    fn test_wide_area_publishing() {
+        use crate::config::{ServiceConfig, TsigConfig, WideAreaConfig};
+        use crate::dns_update::UpdateClient;
+        use base64::Engine as _;
+
+        const SECRET: &[u8] = b"stub dns server secret";
+        let contains = |message: &[u8], needle: &[u8]| {
+            message.windows(needle.len()).any(|window| window == needle)
+        };
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let instance_name = format!("WideArea-{:04x}", timestamp & 0xFFFF);
+
+        let (server, requests) = stub_dns_server(0, Some(SECRET));
+        let wide_area = WideAreaConfig {
+            server: server.to_string(),
+            zone: "example.test.".to_string(),
+            ttl_secs: 600,
+            refresh_interval_secs: 0,
+            tsig: Some(TsigConfig {
+                key_name: "test-key".to_string(),
+                algorithm: "hmac-sha256".to_string(),
+                secret: base64::engine::general_purpose::STANDARD.encode(SECRET),
+            }),
+        };
+        let config = ServiceConfig {
+            instance_name: instance_name.clone(),
+            service_name: "_test._tcp.local.".to_string(),
+            hostname: "wide-area-host.local.".to_string(),
+            bind_address: Some("127.0.0.1".to_string()),
+            wide_area: Some(wide_area.clone()),
+            ..Default::default()
+        };
+
+        for invalid in [
+            WideAreaConfig {
+                zone: "local.".to_string(),
+                ..wide_area.clone()
+            },
+            WideAreaConfig {
+                server: "dns.example.test".to_string(),
+                ..wide_area.clone()
+            },
+            WideAreaConfig {
+                tsig: Some(TsigConfig {
+                    secret: "not base64!".to_string(),
+                    ..wide_area.tsig.clone().unwrap()
+                }),
+                ..wide_area.clone()
+            },
+        ] {
+            let config = ServiceConfig {
+                wide_area: Some(invalid),
+                ..config.clone()
+            };
+            assert!(config.validate().is_err());
+        }
+
+        let mut responder = crate::Responder::start(config.clone()).unwrap();
+        let publish = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert_eq!((publish[2] >> 3) & 0xF, 5, "UPDATE opcode");
+        let mut instance = vec![instance_name.len() as u8];
+        instance.extend_from_slice(instance_name.as_bytes());
+        instance.extend_from_slice(b"\x05_test\x04_tcp\x07example\x04test\x00");
+        assert!(contains(&publish, &instance));
+        assert!(contains(
+            &publish,
+            b"\x0ewide-area-host\x07example\x04test\x00"
+        ));
+        // A record for the bound address with the configured TTL
+        assert!(contains(
+            &publish,
+            &[0, 1, 0, 1, 0, 0, 2, 88, 0, 4, 127, 0, 0, 1]
+        ));
+
+        let deadline = std::time::Instant::now() + Duration::from_secs(2);
+        while !responder
+            .metrics()
+            .contains("mdns_responder_dns_updates_total 1")
+        {
+            assert!(std::time::Instant::now() < deadline, "update not counted");
+            std::thread::sleep(Duration::from_millis(50));
+        }
+
+        // A second service on the same host with another address extends the
+        // host's A RRset instead of replacing it, and leaves it on removal
+        let second = responder
+            .add_service(ServiceConfig {
+                instance_name: format!("{}-2", instance_name),
+                bind_address: Some("127.0.0.2".to_string()),
+                ..config.clone()
+            })
+            .unwrap();
+        let publish = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(contains(&publish, &[0, 4, 127, 0, 0, 1]));
+        assert!(contains(&publish, &[0, 4, 127, 0, 0, 2]));
+        responder.remove_service(&second).unwrap();
+        let withdraw = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(contains(
+            &withdraw,
+            &[0, 1, 0, 1, 0, 0, 2, 88, 0, 4, 127, 0, 0, 1]
+        ));
+        assert!(!contains(&withdraw, &[0, 4, 127, 0, 0, 2]));
+
+        responder.shutdown(Duration::from_secs(5)).unwrap();
+        let withdraw = requests.recv_timeout(Duration::from_secs(5)).unwrap();
+        // PTR deleted as a single record (class NONE), instance name entirely
+        assert!(contains(&withdraw, &[0, 12, 0, 254, 0, 0, 0, 0]));
+        assert!(contains(&withdraw, &instance));
+
+        let (server, _requests) = stub_dns_server(5, None);
+        let err = UpdateClient::new(&WideAreaConfig {
+            server: server.to_string(),
+            tsig: None,
+            ..wide_area.clone()
+        })
+        .unwrap()
+        .send(&[], Duration::from_secs(2))
+        .unwrap_err();
+        assert_eq!(err.code(), 24);
+        assert!(err.to_string().contains("REFUSED"), "{}", err);
+
+        let (server, _requests) = stub_dns_server(0, None);
+        let err = UpdateClient::new(&WideAreaConfig {
+            server: server.to_string(),
+            ..wide_area
+        })
+        .unwrap()
+        .send(&[], Duration::from_secs(2))
+        .unwrap_err();
+        assert!(err.to_string().contains("not signed"), "{}", err);
+    }
+
+    #[test]
+    fn test_error_codes_and_sources() {
+        use crate::MdnsError;
+        use std::error::Error;
+
+        let err = MdnsError::daemon("register", Some("Box._smb._tcp.local."))(mdns_sd::Error::Msg(
+            "socket closed".to_string(),
+        ));
+        assert_eq!(err.code(), 21);
+        assert_eq!(
+            err.to_string(),
+            "mDNS daemon failed to register for Box._smb._tcp.local.: socket closed"
+        );
+        assert_eq!(err.source().unwrap().to_string(), "socket closed");
+
+        let err = MdnsError::daemon("start", None)(mdns_sd::Error::Again);
+        assert_eq!(err.to_string(), "mDNS daemon failed to start: try again");
+
+        let err = crate::config::ServiceConfig {
+            port: 0,
+            ..Default::default()
+        }
+        .validate()
+        .unwrap_err();
+        assert_eq!(err.code(), 10);
+    }
+
+    #[cfg(all(feature = "toml", feature = "yaml"))]
+    #[test]
+    fn test_config_file_formats() {
+        use crate::MdnsError;
+        use crate::config::ServiceConfig;
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = ServiceConfig {
+            instance_name: "Formats".to_string(),
+            interfaces: vec!["10.0.0.0/24".to_string()],
+            ..Default::default()
+        };
+        for name in ["config.json", "config.toml", "config.yaml"] {
+            let path = dir.path().join(name);
+            config.save_to_file(&path).unwrap();
+            let loaded = ServiceConfig::from_file(&path).unwrap();
+            assert_eq!(loaded.instance_name, "Formats");
+            assert_eq!(loaded.interfaces, config.interfaces);
+            assert_eq!(loaded.shares.len(), config.shares.len());
+        }
+        assert!(config.save_to_file(&dir.path().join("config.ini")).is_err());
+
+        // Comments are allowed, and syntax errors point at the bad value
+        let path = dir.path().join("broken.toml");
+        std::fs::write(
+            &path,
+            "# hand-edited\nservice_name = \"_smb._tcp.local.\"\nport = \"445\"\n",
+        )
+        .unwrap();
+        match ServiceConfig::from_file(&path).unwrap_err() {
+            MdnsError::ConfigSyntax { line, column, .. } => assert_eq!((line, column), (3, 8)),
+            e => panic!("unexpected error: {}", e),
+        }
+
+        // Validation errors point at the key they are about
+        let path = dir.path().join("invalid.yaml");
+        let yaml = std::fs::read_to_string(dir.path().join("config.yaml"))
+            .unwrap()
+            .replace("announcements: 2", "announcements: 1");
+        std::fs::write(&path, &yaml).unwrap();
+        let err = ServiceConfig::from_file(&path).unwrap_err();
+        assert_eq!(err.code(), 10);
+        let line = yaml
+            .lines()
+            .position(|line| line.trim_start().starts_with("announcements:"))
+            .unwrap()
+            + 1;
+        assert!(
+            err.to_string()
+                .contains(&format!("invalid.yaml:{}:3: announce.announcements", line)),
+            "{}",
+            err
+        );
+
+        // ...including the right element of an array
+        let path = dir.path().join("shares.json");
+        let mut shares = serde_json::to_value(&config).unwrap();
+        shares["shares"] = serde_json::json!([
+            { "name": "Public", "path": "C:\\Public", "comment": "" },
+            { "name": "", "path": "C:\\Empty", "comment": "" },
+        ]);
+        let json = serde_json::to_string_pretty(&shares).unwrap();
+        std::fs::write(&path, &json).unwrap();
+        let err = ServiceConfig::from_file(&path).unwrap_err();
+        let line = json
+            .lines()
+            .position(|line| line.contains("\"name\": \"\""))
+            .unwrap()
+            + 1;
+        assert!(
+            err.to_string().contains(&format!("shares.json:{}:", line)),
+            "{}",
+            err
+        );
+    }
+
+    #[cfg(feature = "toml")]
+    #[test]
+    fn test_config_overrides() {
+        use crate::config::{
+            ConfigOverrides, ConfigSource, ServiceConfig, TsigConfig, WideAreaConfig,
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        ServiceConfig {
+            instance_name: "From-File".to_string(),
+            hostname: "file-host".to_string(),
+            ..Default::default()
+        }
+        .save_to_file(&path)
+        .unwrap();
+
+        let env = [
+            ("MDNS_RESPONDER_PORT", "8445"),
+            ("MDNS_RESPONDER_HOSTNAME", "env-host"),
+            ("PATH", "/usr/bin"),
+        ]
+        .map(|(name, value)| (name.to_string(), value.to_string()));
+        let mut overrides = ConfigOverrides::from_env(env).unwrap();
+        overrides.set_flag("--hostname", "cli-host").unwrap();
+        overrides.set_flag("--bind-address", "127.0.0.1").unwrap();
+
+        let resolved = overrides.resolve(&path).unwrap();
+        assert_eq!(resolved.config.port, 8445);
+        assert_eq!(resolved.config.hostname, "cli-host");
+        assert_eq!(resolved.config.instance_name, "From-File");
+        assert_eq!(resolved.config.bind_address.as_deref(), Some("127.0.0.1"));
+        assert_eq!(
+            resolved.sources["port"],
+            ConfigSource::Env("MDNS_RESPONDER_PORT".to_string())
+        );
+        assert_eq!(
+            resolved.sources["hostname"],
+            ConfigSource::Cli("--hostname".to_string())
+        );
+        assert_eq!(
+            resolved.sources["instance_name"],
+            ConfigSource::File(path.clone())
+        );
+        assert_eq!(resolved.sources["metrics_address"], ConfigSource::Default);
+
+        // Without a file the overrides apply to the defaults
+        let resolved = overrides.resolve(&dir.path().join("missing.json")).unwrap();
+        assert_eq!(resolved.config.port, 8445);
+        assert_eq!(resolved.sources["instance_name"], ConfigSource::Default);
+
+        // Bad overrides are reported against their source
+        let mut overrides = ConfigOverrides::default();
+        overrides.set_flag("--port", "0").unwrap();
+        let err = overrides.resolve(&path).unwrap_err();
+        assert!(
+            err.to_string().contains("flag --port: port cannot be 0"),
+            "{}",
+            err
+        );
+        assert!(overrides.set_flag("--shares", "x").is_err());
+        assert!(
+            ConfigOverrides::from_env([("MDNS_RESPONDER_PROT".to_string(), "1".to_string())])
+                .is_err()
+        );
+
+        let config = ServiceConfig {
+            wide_area: Some(WideAreaConfig {
+                tsig: Some(TsigConfig {
+                    key_name: "key.".to_string(),
+                    algorithm: "hmac-sha256".to_string(),
+                    secret: "c2VjcmV0".to_string(),
+                }),
+                ..serde_json::from_str(r#"{"server":"127.0.0.1","zone":"example.com."}"#).unwrap()
+            }),
+            ..Default::default()
+        };
+        let value = config.to_redacted_value().unwrap();
+        assert_eq!(value["wide_area"]["tsig"]["secret"], "<redacted>");
+        assert!(!value.to_string().contains("c2VjcmV0"));
+    }
+
+    #[test]
+    fn test_config_migration() {
+        use crate::config::{CONFIG_VERSION, ServiceConfig};
+
+        // Shape written by install() before configs were versioned
+        let unversioned = r#"{
+  "service_name": "_smb._tcp.local.",
+  "instance_name": "Old-Share",
+  "port": 445,
+  "hostname": "old-pc.local",
+  "workgroup": "WORKGROUP",
+  "description": "Windows SMB Share via mDNS",
+  "shares": [{ "name": "Public", "path": "C:\\Public", "comment": "" }]
+}"#;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        std::fs::write(&path, unversioned).unwrap();
+
+        let config = ServiceConfig::from_file(&path).unwrap();
+        assert_eq!(config.version, CONFIG_VERSION);
+        assert_eq!(config.instance_name, "Old-Share");
+
+        let backup = ServiceConfig::migrate_file(&path).unwrap().unwrap();
+        assert_eq!(backup, dir.path().join("config.json.v0.bak"));
+        assert_eq!(std::fs::read_to_string(&backup).unwrap(), unversioned);
+        let migrated: serde_json::Value =
+            serde_json::from_str(&std::fs::read_to_string(&path).unwrap()).unwrap();
+        assert_eq!(migrated["version"], CONFIG_VERSION);
+        assert_eq!(migrated["instance_name"], "Old-Share");
+        assert!(ServiceConfig::migrate_file(&path).unwrap().is_none());
+
+        // Adding the version keeps hand-written comments
+        #[cfg(feature = "toml")]
+        {
+            let path = dir.path().join("config.toml");
+            let commented = r#"#:schema ./config.schema.json
+# Office share
+service_name = "_smb._tcp.local."
+instance_name = "Old-Share" # shown in Finder
+port = 445
+hostname = "old-pc.local"
+workgroup = "WORKGROUP"
+description = "Windows SMB Share via mDNS"
+
+[[shares]]
+name = "Public"
+path = 'C:\Public'
+comment = ""
+"#;
+            std::fs::write(&path, commented).unwrap();
+            ServiceConfig::migrate_file(&path).unwrap().unwrap();
+            let migrated = std::fs::read_to_string(&path).unwrap();
+            assert_eq!(
+                migrated,
+                commented.replace(
+                    "# Office share\n",
+                    &format!("# Office share\nversion = {}\n", CONFIG_VERSION)
+                )
+            );
+            assert_eq!(
+                ServiceConfig::from_file(&path).unwrap().instance_name,
+                "Old-Share"
+            );
+        }
+
+        // A file from a newer release is refused, pointing at its version
+        std::fs::write(&path, "{\n  \"version\": 99\n}").unwrap();
+        let err = ServiceConfig::from_file(&path).unwrap_err();
+        assert!(
+            err.to_string()
+                .contains("config.json:2:4: version 99 is newer"),
+            "{}",
+            err
+        );
+    }
+
+    #[test]
+    #[cfg(all(windows, feature = "service-host"))]
+    fn test_firewall_profiles() {
+        use crate::firewall::Profile;
+
+        assert_eq!(
+            Profile::parse_list("Domain, private").unwrap(),
+            vec![Profile::Domain, Profile::Private]
+        );
+        assert!(Profile::parse_list("domain,home").is_err());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn test_config_permissions() {
+        use crate::config::ServiceConfig;
+        use std::fs::{self, Permissions};
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.json");
+        ServiceConfig::default().save_to_file(&path).unwrap();
+        fs::set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+        ServiceConfig::from_file(&path).unwrap();
+
+        fs::set_permissions(&path, Permissions::from_mode(0o666)).unwrap();
+        let err = ServiceConfig::from_file(&path).unwrap_err();
+        assert_eq!(err.code(), 14);
+        assert!(err.to_string().contains("writable by all users"), "{}", err);
+        fs::set_permissions(&path, Permissions::from_mode(0o644)).unwrap();
+
+        // A directory others can write lets them swap the file out
+        fs::set_permissions(dir.path(), Permissions::from_mode(0o777)).unwrap();
+        let err = ServiceConfig::from_file(&path).unwrap_err();
+        assert_eq!(err.code(), 14);
+        fs::set_permissions(dir.path(), Permissions::from_mode(0o1777)).unwrap();
+        ServiceConfig::from_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_system_responder_policy() {
+        use crate::coexistence::{SystemResponder, check_against};
+        use crate::config::{ServiceConfig, SystemResponderPolicy};
+
+        let found = [SystemResponder {
+            name: "Bonjour Service".to_string(),
+            hostname: Some("MyHost".to_string()),
+        }];
+        let mut config = ServiceConfig {
+            hostname: "myhost.local.".to_string(),
+            ..ServiceConfig::default()
+        };
+        check_against(&config, &found).unwrap();
+        check_against(&config, &[]).unwrap();
+
+        config.system_responder = SystemResponderPolicy::Strict;
+        let err = check_against(&config, &found).unwrap_err();
+        assert_eq!(err.code(), 25);
+        assert!(
+            err.to_string().contains("answers for MyHost.local"),
+            "{}",
+            err
+        );
+        check_against(&config, &[]).unwrap();
+
+        config.system_responder = SystemResponderPolicy::Register;
+        check_against(&config, &found).unwrap();
+        assert_eq!(
+            serde_json::to_value(config.system_responder).unwrap(),
+            "register"
+        );
+    }
+
+    #[cfg(feature = "schema")]
+    #[test]
+    fn test_published_config_schema() {
+        let published: serde_json::Value =
+            serde_json::from_str(include_str!("../config.schema.json")).unwrap();
+        assert_eq!(
+            published,
+            crate::config::json_schema(),
+            "config.schema.json is stale; regenerate it with `mdns_responder config-schema`"
+        );
+    }
+
+    #[cfg(feature = "service-host")]
+    #[test]
+    fn test_rotating_log_file() {
+        use crate::logging::RotatingFile;
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("logs").join("mdns.log");
+        let mut file = RotatingFile::open(&path, 64, 2).unwrap();
+        for i in 0..20 {
+            file.write_line(&format!("line {:02} padded to a fixed width", i))
+                .unwrap();
+        }
+
+        let numbered = |n: u32| dir.path().join("logs").join(format!("mdns.log.{}", n));
+        assert!(
+            std::fs::read_to_string(&path)
+                .unwrap()
+                .ends_with("line 19 padded to a fixed width\n")
+        );
+        assert!(
+            std::fs::read_to_string(numbered(1))
+                .unwrap()
+                .starts_with("line 16")
+        );
+        assert!(
+            std::fs::read_to_string(numbered(2))
+                .unwrap()
+                .starts_with("line 14")
+        );
+        assert!(!numbered(3).exists());
+        assert!(std::fs::metadata(&path).unwrap().len() <= 64);
+
+        // Missing logging section falls back to defaults; bad levels are rejected
+        let mut config: crate::config::ServiceConfig = serde_json::from_str(
+            r#"{"service_name":"_smb._tcp.local.","instance_name":"Log","port":445,
+                "hostname":"log.local","workgroup":"W","description":"D",
+                "shares":[{"name":"S","path":"/srv","comment":""}]}"#,
+        )
+        .unwrap();
+        assert_eq!(config.logging.level, "info");
+        assert!(config.validate().is_ok());
+        config.logging.level = "loud".to_string();
+        assert!(config.validate().is_err());
+    }
+
+    #[cfg(feature = "service-host")]
+    #[test]
+    fn test_control_channel() {
+        use crate::control::{self, ControlServer, HostStatus, Request};
+        use std::io::{BufRead, BufReader, Write};
+        use std::sync::{Arc, Mutex};
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = tempfile::tempdir().unwrap();
+        let endpoint = if cfg!(windows) {
+            format!(r"\\.\pipe\mdns-responder-test-{}", timestamp)
+        } else {
+            dir.path().join("control.sock").display().to_string()
+        };
+        let config = crate::config::ServiceConfig {
+            instance_name: format!("Control-{}", timestamp & 0xFFFF),
+            service_name: "_test._tcp.local.".to_string(),
+            bind_address: Some("127.0.0.1".to_string()),
+            ..Default::default()
+        };
+
+        let responder = Arc::new(Mutex::new(crate::Responder::start(config).unwrap()));
+        let server = ControlServer::start(&endpoint, Arc::clone(&responder)).unwrap();
+        // A second instance must not take over a live endpoint
+        assert!(ControlServer::start(&endpoint, Arc::clone(&responder)).is_err());
+
+        let response = control::request(&endpoint, Request::Status).unwrap();
+        assert!(response.ok, "{:?}", response.error);
+        let status: HostStatus = serde_json::from_value(response.result.unwrap()).unwrap();
+        assert_eq!(status.pid, std::process::id());
+        assert!(status.responder.daemon_running);
+        assert_eq!(status.responder.services.len(), 1);
+
+        let response = control::request(&endpoint, Request::ListServices).unwrap();
+        assert_eq!(response.result.unwrap().as_array().unwrap().len(), 1);
+
+        assert!(control::request(&endpoint, Request::Reannounce).unwrap().ok);
+
+        // What `install` waits for before trusting a reduced-privilege account
+        let status = control::wait_for_daemon(&endpoint, Duration::from_secs(5)).unwrap();
+        assert_eq!(status.responder.services.len(), 1);
+
+        let connect = || {
+            use interprocess::local_socket::{GenericFilePath, Stream, prelude::*};
+            BufReader::new(
+                Stream::connect(endpoint.as_str().to_fs_name::<GenericFilePath>().unwrap())
+                    .unwrap(),
+            )
+        };
+        let reply = |reader: &mut BufReader<_>| {
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            serde_json::from_str::<control::Response>(&line).unwrap()
+        };
+        // Idle clients are dropped, freeing their slots for real requests
+        let mut idle: Vec<_> = (0..16).map(|_| connect()).collect();
+        for reader in &mut idle {
+            assert!(!reply(reader).ok);
+        }
+        control::wait_for_daemon(&endpoint, Duration::from_secs(5)).unwrap();
+        // An endless request line is cut off instead of buffered
+        let mut oversized = connect();
+        oversized.get_mut().write_all(&[b'x'; 8192]).unwrap();
+        assert!(!reply(&mut oversized).ok);
+
+        drop(server);
+        assert!(control::request(&endpoint, Request::Status).is_err());
+        let err = control::wait_for_daemon(&endpoint, Duration::from_millis(300)).unwrap_err();
+        assert_eq!(err.code(), 22);
+        control::lock(&responder)
+            .shutdown(Duration::from_secs(5))
+            .unwrap();
+    }
+
+    #[cfg(all(feature = "service-host", feature = "discovery"))]
+    #[test]
+    fn test_browse_cache_over_control_channel() {
+        use crate::control::{self, ControlServer, Request};
+        use crate::discovery::{DiscoveredService, DiscoveryEvent};
+        use std::sync::{Arc, Mutex};
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let dir = tempfile::tempdir().unwrap();
+        let endpoint = if cfg!(windows) {
+            format!(r"\\.\pipe\mdns-responder-cache-test-{}", timestamp)
+        } else {
+            dir.path().join("control.sock").display().to_string()
+        };
+        let service_type = format!("_cache{:04x}._tcp.local.", timestamp & 0xFFFF);
+        let expected_fullname = format!("Remote._cache{:04x}._tcp.local.", timestamp & 0xFFFF);
+
+        let responder = Arc::new(Mutex::new(
+            crate::Responder::start(crate::config::ServiceConfig {
+                instance_name: format!("Cache-{}", timestamp & 0xFFFF),
+                service_name: "_test._tcp.local.".to_string(),
+                browse: vec![service_type.trim_end_matches(".local.").to_string()],
+                ..Default::default()
+            })
+            .unwrap(),
+        ));
+        let server = ControlServer::start(&endpoint, Arc::clone(&responder)).unwrap();
+
+        let (event_tx, event_rx) = std::sync::mpsc::channel();
+        let watch_thread = std::thread::spawn({
+            let endpoint = endpoint.clone();
+            let service_type = service_type.clone();
+            move || {
+                control::watch(&endpoint, Some(&service_type), |event| {
+                    event_tx.send(event).ok();
+                })
+            }
+        });
+
+        let mut remote = crate::Responder::start(crate::config::ServiceConfig {
+            instance_name: "Remote".to_string(),
+            service_name: service_type.clone(),
+            port: 4451,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let added = event_rx
+            .recv_timeout(Duration::from_secs(8))
+            .expect("no change reported by watch");
+        assert!(
+            matches!(&added, DiscoveryEvent::Added(service) if service.fullname == expected_fullname),
+            "{:?}",
+            added
+        );
+
+        let response =
+            control::request(&endpoint, Request::Discovered { service_type: None }).unwrap();
+        assert!(response.ok, "{:?}", response.error);
+        let cached: Vec<DiscoveredService> =
+            serde_json::from_value(response.result.unwrap()).unwrap();
+        assert_eq!(cached.len(), 1);
+        assert_eq!(cached[0].port, 4451);
+
+        remote.shutdown(Duration::from_secs(5)).unwrap();
+        let removed = std::iter::from_fn(|| event_rx.recv_timeout(Duration::from_secs(8)).ok())
+            .any(|event| {
+            matches!(event, DiscoveryEvent::Removed { ref fullname, .. } if *fullname == expected_fullname)
+        });
+        assert!(
+            removed,
+            "removal of '{}' was not reported",
+            expected_fullname
+        );
+        let response =
+            control::request(&endpoint, Request::Discovered { service_type: None }).unwrap();
+        assert_eq!(response.result.unwrap().as_array().unwrap().len(), 0);
+
+        // Stopping the responder ends the watch
+        control::lock(&responder)
+            .shutdown(Duration::from_secs(5))
+            .unwrap();
+        watch_thread.join().unwrap().unwrap();
+        drop(server);
+    }
+
+    #[cfg(feature = "discovery")]
+    #[test]
+    fn test_discover_reports_added_and_removed() {
+        use crate::discovery::{self, DiscoveryEvent};
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let unique_instance = format!("Discover-Instance-{}", timestamp & 0xFFFF);
+        let expected_fullname = format!("{}._test._tcp.local.", unique_instance);
+
+        let (event_tx, event_rx) = std::sync::mpsc::channel();
+        let discover_thread = std::thread::spawn(move || {
+            discovery::discover("_test._tcp", Some(Duration::from_secs(8)), |event| {
+                event_tx.send(event).ok();
+            })
+            .unwrap();
+        });
+
+        let mut responder = crate::Responder::start(crate::config::ServiceConfig {
+            instance_name: unique_instance,
+            service_name: "_test._tcp.local.".to_string(),
+            port: 4450,
+            ..Default::default()
+        })
+        .unwrap();
+
+        let added = event_rx.iter().find_map(|event| match event {
+            DiscoveryEvent::Added(service) if service.fullname == expected_fullname => {
+                Some(service)
+            }
+            _ => None,
+        });
+        responder.shutdown(Duration::from_secs(5)).unwrap();
+        let removed = event_rx.iter().any(|event| {
+            matches!(event, DiscoveryEvent::Removed { ref fullname, .. } if *fullname == expected_fullname)
+        });
+        discover_thread.join().unwrap();
+
+        let added = added.expect("service was not discovered");
+        assert_eq!(added.port, 4450);
+        assert_eq!(
+            added.txt.get("workgroup").map(String::as_str),
+            Some("WORKGROUP")
+        );
+        assert!(
+            removed,
+            "removal of '{}' was not reported",
+            expected_fullname
+        );
+    }
+
+    #[cfg(feature = "discovery")]
+    #[test]
+    fn test_selfcheck_compare_reports_diff() {
+        use crate::discovery::DiscoveredService;
+        use crate::selfcheck::compare;
+
+        let expected = DiscoveredService {
+            fullname: "Share._smb._tcp.local.".to_string(),
+            service_type: "_smb._tcp.local.".to_string(),
+            hostname: "host.local.".to_string(),
+            addresses: vec!["192.168.1.10".parse().unwrap()],
+            port: 445,
+            txt: [("vers", "3.0"), ("workgroup", "WORKGROUP")]
+                .into_iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect(),
+        };
+        let mut actual = expected.clone();
+        actual.hostname = "HOST.local.".to_string();
+        assert!(compare(&expected, &actual).is_empty());
+
+        actual.port = 139;
+        actual.txt.remove("workgroup");
+        actual.txt.insert("extra".to_string(), "1".to_string());
+        let fields: Vec<String> = compare(&expected, &actual)
+            .into_iter()
+            .map(|m| m.field)
+            .collect();
+        assert_eq!(fields, ["port", "txt.extra", "txt.workgroup"]);
+    }
+
+    #[cfg(feature = "tokio")]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_async_responder_survives_cancellation() {
+        use crate::publisher::{FakePublisher, PublisherCall::*};
+
+        let config = crate::config::ServiceConfig {
+            instance_name: "Async-Fake".to_string(),
+            service_name: "_test._tcp.local.".to_string(),
+            bind_address: Some("127.0.0.1".to_string()),
+            ..Default::default()
+        };
+        let fake = FakePublisher::default();
+        let responder = crate::AsyncResponder::from(
+            crate::Responder::with_publisher(config.clone(), Box::new(fake.clone())).unwrap(),
+        );
+
+        // Dropped after its first poll, as a lost select! branch would be
+        let _ = tokio::time::timeout(
+            Duration::ZERO,
+            responder.add_service(crate::config::ServiceConfig {
+                instance_name: "Async-Fake-extra".to_string(),
+                ..config.clone()
+            }),
+        )
+        .await;
+        let _ = tokio::time::timeout(
+            Duration::ZERO,
+            responder.update(crate::config::ServiceConfig {
+                port: 9446,
+                ..config
+            }),
+        )
+        .await;
+
+        let status = responder.status().await.unwrap();
+        assert!(status.daemon_running);
+        assert_eq!(
+            status.primary.as_deref(),
+            Some("Async-Fake._test._tcp.local.")
+        );
+
+        drop(responder);
+        let shut_down = tokio::time::timeout(Duration::from_secs(5), async {
+            while !fake.calls().contains(&Shutdown) {
+                tokio::time::sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await;
+        assert!(shut_down.is_ok(), "dropping the handle did not shut down");
+    }
+
+    #[cfg(all(feature = "tokio", feature = "discovery"))]
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_async_run_and_discovery_stream() {
+        use crate::discovery::DiscoveryStream;
+        use futures_core::Stream;
+        use std::pin::Pin;
+        use tokio_util::sync::CancellationToken;
+
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap()
+            .as_nanos();
+        let unique_instance = format!("Async-Instance-{}", timestamp & 0xFFFF);
+        let service_name = "_test._tcp.local.".to_string();
+        let test_config = crate::config::ServiceConfig {
+            instance_name: unique_instance.clone(),
+            service_name: service_name.clone(),
+            ..Default::default()
+        };
+
+        let token = CancellationToken::new();
+        let service_task = tokio::spawn(crate::async_responder::run(
+            token.clone(),
+            Some(test_config),
+        ));
+
+        let mut stream = DiscoveryStream::browse(&service_name).unwrap();
+        let expected_fullname = format!("{}.{}", unique_instance, service_name);
+
+        let found = tokio::time::timeout(Duration::from_secs(8), async {
+            while let Some(event) =
+                std::future::poll_fn(|cx| Pin::new(&mut stream).poll_next(cx)).await
+            {
+                if let ServiceEvent::ServiceResolved(info) = event {
+                    if info.get_fullname() == expected_fullname {
+                        return true;
+                    }
+                }
+            }
+            false
+        })
+        .await
+        .unwrap_or(false);
+
+        token.cancel();
+        service_task.await.unwrap().unwrap();
+        drop(stream);
+
+        assert!(found, "mDNS service '{}' was not found", expected_fullname);
+    }
 }
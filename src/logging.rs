@@ -0,0 +1,237 @@
+use crate::config::LoggingConfig;
+use crate::error::{MdnsError, Result};
+use log::{LevelFilter, Log, Metadata, Record, warn};
+use std::ffi::OsString;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Mutex, PoisonError};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Event source the Windows service logs under, registered by `install`
+pub const EVENT_SOURCE: &str = "MDNSResponder";
+
+/// Install the process-wide logger described by `config`.
+///
+/// `console` adds stderr output, which systemd and foreground runs want and
+/// the Windows service does not. A destination that cannot be opened is
+/// skipped and reported through the others, so this only fails when a logger
+/// is already installed.
+pub fn init(config: &LoggingConfig, console: bool) -> Result<()> {
+    let level = std::env::var("RUST_LOG")
+        .ok()
+        .and_then(|level| level.parse().ok())
+        .or_else(|| config.level.parse().ok())
+        .unwrap_or(LevelFilter::Info);
+    let mut problems = Vec::new();
+
+    let file = config.file.as_ref().and_then(|path| {
+        RotatingFile::open(
+            path,
+            config.max_file_size_mb * 1024 * 1024,
+            config.max_files,
+        )
+        .map(Mutex::new)
+        .inspect_err(|e| problems.push(format!("Cannot open log file {:?}: {}", path, e)))
+        .ok()
+    });
+
+    let logger = Logger {
+        level,
+        console,
+        file,
+        #[cfg(windows)]
+        event_log: config.event_log,
+    };
+    log::set_boxed_logger(Box::new(logger))
+        .map_err(|e| MdnsError::Service(format!("Logger already installed: {}", e)))?;
+    log::set_max_level(level);
+
+    for problem in problems {
+        warn!("{}", problem);
+    }
+    Ok(())
+}
+
+struct Logger {
+    level: LevelFilter,
+    console: bool,
+    file: Option<Mutex<RotatingFile>>,
+    #[cfg(windows)]
+    event_log: bool,
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let line = format!(
+            "{} {:<5} {}: {}",
+            timestamp(),
+            record.level(),
+            record.target(),
+            record.args()
+        );
+        if self.console {
+            eprintln!("{}", line);
+        }
+        if let Some(file) = &self.file {
+            let mut file = file.lock().unwrap_or_else(PoisonError::into_inner);
+            // Nowhere left to report a failing log file
+            file.write_line(&line).ok();
+        }
+        #[cfg(windows)]
+        if self.event_log {
+            // Nor for an unreachable Event Log
+            event_log::report(record.level(), &record.args().to_string()).ok();
+        }
+    }
+
+    fn flush(&self) {
+        if let Some(file) = &self.file {
+            let mut file = file.lock().unwrap_or_else(PoisonError::into_inner);
+            file.file.flush().ok();
+        }
+    }
+}
+
+#[cfg(windows)]
+mod event_log {
+    use super::EVENT_SOURCE;
+    use log::Level;
+    use windows::Win32::System::EventLog::{
+        DeregisterEventSource, EVENTLOG_ERROR_TYPE, EVENTLOG_INFORMATION_TYPE,
+        EVENTLOG_WARNING_TYPE, RegisterEventSourceW, ReportEventW,
+    };
+    use windows::core::{HSTRING, PCWSTR};
+
+    /// Write `message` to the Application log under [`EVENT_SOURCE`].
+    ///
+    /// The source is opened per event so no handle has to be shared between
+    /// threads; services log rarely enough for that not to matter.
+    pub(super) fn report(level: Level, message: &str) -> windows::core::Result<()> {
+        let (event_type, event_id) = match level {
+            Level::Error => (EVENTLOG_ERROR_TYPE, 1),
+            Level::Warn => (EVENTLOG_WARNING_TYPE, 2),
+            Level::Info => (EVENTLOG_INFORMATION_TYPE, 3),
+            Level::Debug | Level::Trace => (EVENTLOG_INFORMATION_TYPE, 4),
+        };
+        let source = HSTRING::from(EVENT_SOURCE);
+        let message = HSTRING::from(message);
+
+        // SAFETY: both strings are NUL-terminated and outlive the calls, and
+        // the handle is only used between registering and deregistering it.
+        unsafe {
+            let handle = RegisterEventSourceW(PCWSTR::null(), &source)?;
+            let result = ReportEventW(
+                handle,
+                event_type,
+                0,
+                event_id,
+                None,
+                0,
+                Some(&[PCWSTR(message.as_ptr())]),
+                None,
+            );
+            DeregisterEventSource(handle).ok();
+            result
+        }
+    }
+}
+
+/// Append-only log file that moves to `path.1` .. `path.N` once it would
+/// grow past `max_bytes`, dropping the oldest.
+pub(crate) struct RotatingFile {
+    path: PathBuf,
+    max_bytes: u64,
+    max_files: u32,
+    file: File,
+    size: u64,
+}
+
+impl RotatingFile {
+    pub(crate) fn open(path: &Path, max_bytes: u64, max_files: u32) -> io::Result<Self> {
+        if let Some(dir) = path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            fs::create_dir_all(dir)?;
+        }
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        let size = file.metadata()?.len();
+
+        Ok(Self {
+            path: path.to_path_buf(),
+            max_bytes,
+            max_files,
+            file,
+            size,
+        })
+    }
+
+    pub(crate) fn write_line(&mut self, line: &str) -> io::Result<()> {
+        let len = line.len() as u64 + 1;
+        if self.size > 0 && self.size + len > self.max_bytes {
+            self.rotate()?;
+        }
+        writeln!(self.file, "{}", line)?;
+        self.size += len;
+        Ok(())
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for n in (1..self.max_files).rev() {
+            let from = numbered(&self.path, n);
+            if from.exists() {
+                fs::rename(&from, numbered(&self.path, n + 1))?;
+            }
+        }
+        fs::rename(&self.path, numbered(&self.path, 1))?;
+
+        self.file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+}
+
+fn numbered(path: &Path, n: u32) -> PathBuf {
+    let mut name = OsString::from(path.as_os_str());
+    name.push(format!(".{}", n));
+    PathBuf::from(name)
+}
+
+/// Current UTC time as RFC 3339, e.g. `2024-05-01T12:00:00Z`.
+fn timestamp() -> String {
+    let secs = SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs())
+        .unwrap_or(0);
+    let (days, secs_of_day) = ((secs / 86_400) as i64, secs % 86_400);
+
+    // Civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+
+    format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+        year,
+        month,
+        day,
+        secs_of_day / 3_600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60
+    )
+}
@@ -1,16 +1,32 @@
-use crate::config::ServiceConfig;
-use crate::error::Result;
+use crate::config::{ConfigOverrides, ENV_PREFIX, InterfaceScope, ResolvedConfig, ServiceConfig};
+use crate::error::{MdnsError, Result};
+use crate::responder::Responder;
 use log::{info, warn};
-use mdns_sd::{ServiceDaemon, ServiceInfo};
-use std::collections::HashMap;
-use std::net::{IpAddr, UdpSocket};
+use mdns_sd::{IfKind, ServiceInfo};
+use std::collections::{BTreeSet, HashMap};
+use std::net::{IpAddr, Ipv4Addr, UdpSocket};
+use std::path::Path;
+use std::sync::OnceLock;
 use std::sync::mpsc::Receiver;
-use std::sync::{Arc, Mutex};
-use std::thread;
-use std::time::Duration;
 
-const SHUTDOWN_TIMEOUT_SECS: u64 = 5;
+/// Interface name prefixes for virtual/VPN links on Unix hosts
+#[cfg(unix)]
+const VIRTUAL_INTERFACE_PREFIXES: &[&str] = &[
+    "docker", "veth", "virbr", "br-", "tun", "tap", "wg", "vboxnet", "vmnet", "zt",
+];
 
+/// Check if it's a private address (10.x.x.x, 172.16-31.x.x, 192.168.x.x)
+fn is_private_ipv4(ipv4: &Ipv4Addr) -> bool {
+    let octets = ipv4.octets();
+    match octets[0] {
+        10 => true,
+        172 if octets[1] >= 16 && octets[1] <= 31 => true,
+        192 if octets[1] == 168 => true,
+        _ => false,
+    }
+}
+
+#[cfg(windows)]
 fn get_local_ip() -> Result<String> {
     use ipconfig::get_adapters;
 
@@ -37,16 +53,7 @@ fn get_local_ip() -> Result<String> {
         // Find first valid private IPv4 address
         for ip_addr in adapter.ip_addresses() {
             if let IpAddr::V4(ipv4) = ip_addr {
-                let octets = ipv4.octets();
-                // Check if it's a private address (10.x.x.x, 172.16-31.x.x, 192.168.x.x)
-                let is_private = match octets[0] {
-                    10 => true,
-                    172 if octets[1] >= 16 && octets[1] <= 31 => true,
-                    192 if octets[1] == 168 => true,
-                    _ => false,
-                };
-
-                if is_private {
+                if is_private_ipv4(ipv4) {
                     info!("Selected IP from adapter '{}': {}", adapter_desc, ipv4);
                     return Ok(ipv4.to_string());
                 }
@@ -54,6 +61,132 @@ fn get_local_ip() -> Result<String> {
         }
     }
 
+    fallback_local_ip()
+}
+
+#[cfg(unix)]
+fn get_local_ip() -> Result<String> {
+    // Strategy 1: Find the first operational, non-virtual interface with a private IPv4
+    for intf in if_addrs::get_if_addrs()? {
+        if intf.is_loopback() || !intf.is_oper_up() {
+            continue;
+        }
+
+        // Skip virtual/VPN interfaces
+        if VIRTUAL_INTERFACE_PREFIXES
+            .iter()
+            .any(|prefix| intf.name.starts_with(prefix))
+        {
+            continue;
+        }
+
+        if let if_addrs::IfAddr::V4(v4) = &intf.addr {
+            if is_private_ipv4(&v4.ip) {
+                info!("Selected IP from interface '{}': {}", intf.name, v4.ip);
+                return Ok(v4.ip.to_string());
+            }
+        }
+    }
+
+    fallback_local_ip()
+}
+
+/// An address of an operational local interface.
+#[derive(Debug, Clone)]
+pub(crate) struct LocalInterface {
+    pub(crate) name: String,
+    pub(crate) ip: IpAddr,
+    /// Prefix length of the subnet `ip` is on
+    #[cfg_attr(not(feature = "reflector"), expect(dead_code))]
+    pub(crate) prefix_len: u8,
+}
+
+#[cfg(feature = "reflector")]
+impl LocalInterface {
+    /// Whether `ip` is on the same subnet as this interface.
+    pub(crate) fn on_link(&self, ip: IpAddr) -> bool {
+        InterfaceScope::Subnet {
+            network: self.ip,
+            prefix_len: self.prefix_len,
+        }
+        .matches(&self.name, ip)
+    }
+}
+
+/// Addresses of the operational local interfaces.
+#[cfg(windows)]
+pub(crate) fn local_interfaces() -> Result<Vec<LocalInterface>> {
+    let mut interfaces = Vec::new();
+    for adapter in ipconfig::get_adapters()? {
+        if adapter.oper_status() != ipconfig::OperStatus::IfOperStatusUp {
+            continue;
+        }
+        for ip in adapter.ip_addresses() {
+            // The adapter lists on-link prefixes next to host and multicast
+            // routes; the longest one shorter than a host route holding `ip`
+            // is its subnet
+            let max_len = if ip.is_ipv4() { 32 } else { 128 };
+            let prefix_len = adapter
+                .prefixes()
+                .iter()
+                .filter(|(network, len)| {
+                    *len < max_len
+                        && InterfaceScope::Subnet {
+                            network: *network,
+                            prefix_len: *len as u8,
+                        }
+                        .matches("", *ip)
+                })
+                .map(|(_, len)| *len as u8)
+                .max()
+                .unwrap_or(max_len as u8);
+            // Match either the friendly name ("Ethernet 2") or the adapter GUID
+            for name in [adapter.friendly_name(), adapter.adapter_name()] {
+                interfaces.push(LocalInterface {
+                    name: name.to_string(),
+                    ip: *ip,
+                    prefix_len,
+                });
+            }
+        }
+    }
+    Ok(interfaces)
+}
+
+/// Addresses of the operational local interfaces.
+#[cfg(unix)]
+pub(crate) fn local_interfaces() -> Result<Vec<LocalInterface>> {
+    Ok(if_addrs::get_if_addrs()?
+        .into_iter()
+        .filter(|intf| intf.is_oper_up())
+        .map(|intf| {
+            let prefix_len = match &intf.addr {
+                if_addrs::IfAddr::V4(addr) => addr.prefixlen,
+                if_addrs::IfAddr::V6(addr) => addr.prefixlen,
+            };
+            LocalInterface {
+                ip: intf.ip(),
+                name: intf.name,
+                prefix_len,
+            }
+        })
+        .collect())
+}
+
+/// Local addresses on interfaces matching any of `scopes`.
+fn scoped_addresses(scopes: &[InterfaceScope]) -> Result<BTreeSet<IpAddr>> {
+    Ok(local_interfaces()?
+        .into_iter()
+        .filter(|intf| {
+            scopes
+                .iter()
+                .any(|scope| scope.matches(&intf.name, intf.ip))
+        })
+        .map(|intf| intf.ip)
+        .collect())
+}
+
+fn fallback_local_ip() -> Result<String> {
     // Fallback: UDP socket method (more reliable than before)
     warn!("No physical adapter found, falling back to UDP socket detection");
     let socket = UdpSocket::bind("0.0.0.0:0")?;
@@ -63,25 +196,156 @@ fn get_local_ip() -> Result<String> {
     Ok(local_addr.ip().to_string())
 }
 
-pub fn run(
-    shutdown_rx: Option<Receiver<()>>,
-    config_override: Option<ServiceConfig>,
-) -> Result<()> {
-    info!("Initializing mDNS Responder Service...");
+/// Command-line overrides applied by every [`load_config`] and [`reload`]
+static CLI_OVERRIDES: OnceLock<ConfigOverrides> = OnceLock::new();
+static CONFIG_FALLBACK: OnceLock<ConfigFallback> = OnceLock::new();
 
-    let config = if let Some(config) = config_override {
-        config
-    } else {
-        let config_path = ServiceConfig::config_path();
-        ServiceConfig::from_file(&config_path).or_else(|e| {
+/// Flag of `run` and the service hosts opting into [`ConfigFallback::Defaults`]
+pub const ALLOW_DEFAULT_CONFIG_FLAG: &str = "--allow-default-config";
+
+/// What [`load_config`] does when the config cannot be used.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ConfigFallback {
+    /// Fail, so a host never advertises something nobody configured
+    #[default]
+    Strict,
+    /// Log the error and use the built-in defaults
+    Defaults,
+}
+
+/// Layer `overrides` from the command line over the config file and the
+/// environment for the rest of the process. Only the first call counts.
+pub fn set_cli_overrides(overrides: ConfigOverrides) {
+    CLI_OVERRIDES.set(overrides).ok();
+}
+
+/// Choose how [`load_config`] handles a bad or missing config for the rest
+/// of the process. Only the first call counts.
+pub fn set_config_fallback(fallback: ConfigFallback) {
+    CONFIG_FALLBACK.set(fallback).ok();
+}
+
+/// Merge defaults, the config file, `MDNS_RESPONDER_*` environment variables
+/// and command-line overrides, noting where each value came from.
+///
+/// A missing config file is only accepted when something is overridden.
+pub fn resolve_config() -> Result<ResolvedConfig> {
+    resolve_config_at(&ServiceConfig::config_path())
+}
+
+fn resolve_config_at(config_path: &Path) -> Result<ResolvedConfig> {
+    let mut overrides = ConfigOverrides::from_env(env_overrides()?)?;
+    if let Some(cli) = CLI_OVERRIDES.get() {
+        overrides.extend(cli);
+    }
+    if overrides.is_empty() && !config_path.exists() {
+        return Err(MdnsError::ConfigValidation(format!(
+            "no config file at {} and no overrides",
+            config_path.display()
+        )));
+    }
+    overrides.resolve(config_path)
+}
+
+/// The `MDNS_RESPONDER_*` environment variables. Other variables are skipped
+/// even when they are not valid Unicode; ours must be.
+fn env_overrides() -> Result<Vec<(String, String)>> {
+    let mut vars = Vec::new();
+    for (name, value) in std::env::vars_os() {
+        if !name.as_encoded_bytes().starts_with(ENV_PREFIX.as_bytes()) {
+            continue;
+        }
+        let name = name.into_string().map_err(|name| {
+            MdnsError::ConfigValidation(format!(
+                "environment variable {} is not valid Unicode",
+                name.to_string_lossy()
+            ))
+        })?;
+        let value = value
+            .into_string()
+            .map_err(|_| MdnsError::ConfigValidation(format!("{} is not valid Unicode", name)))?;
+        vars.push((name, value));
+    }
+    Ok(vars)
+}
+
+/// Load the service configuration.
+///
+/// Errors are returned unless [`set_config_fallback`] opted into
+/// [`ConfigFallback::Defaults`]. Reloads go through [`reload`] instead.
+pub fn load_config(config_override: Option<ServiceConfig>) -> Result<ServiceConfig> {
+    if let Some(config) = config_override {
+        return Ok(config);
+    }
+
+    match resolve_config() {
+        Ok(resolved) => Ok(resolved.config),
+        Err(e) if CONFIG_FALLBACK.get() == Some(&ConfigFallback::Defaults) => {
             warn!(
-                "Failed to load config from {:?}: {}, using defaults",
-                config_path, e
+                "Failed to load config from {:?}: {}, using defaults as allowed",
+                ServiceConfig::config_path(),
+                e
             );
-            Ok::<_, crate::error::MdnsError>(ServiceConfig::default())
-        })?
-    };
-    info!("Using configuration: {:?}", config);
+            Ok(ServiceConfig::default())
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Load the config again and replace `responder`'s primary service with it.
+///
+/// Always strict: [`ConfigFallback::Defaults`] only covers startup, so a bad
+/// config keeps the running service instead of advertising the defaults.
+pub fn reload(responder: &mut Responder) -> Result<()> {
+    reload_from(responder, &ServiceConfig::config_path())
+}
+
+pub(crate) fn reload_from(responder: &mut Responder, config_path: &Path) -> Result<()> {
+    responder.update(resolve_config_at(config_path)?.config)
+}
+
+/// Build the `ServiceInfo` advertised for `config`.
+///
+/// A service scoped with `interfaces` carries only the addresses of matching
+/// interfaces and is limited to them with [`ServiceInfo::set_interfaces`], so
+/// the daemon neither announces nor answers for it anywhere else.
+pub(crate) fn build_service_info(config: &ServiceConfig) -> Result<ServiceInfo> {
+    let scopes = config.interface_scopes()?;
+    if !scopes.is_empty() {
+        let scoped = scoped_addresses(&scopes)?;
+        let addresses: Vec<IpAddr> = match &config.bind_address {
+            Some(bind_addr) => {
+                let bind_ip: IpAddr = bind_addr.parse().map_err(|_| {
+                    MdnsError::ConfigValidation(format!(
+                        "bind_address '{}' is not an IP address",
+                        bind_addr
+                    ))
+                })?;
+                if !scoped.contains(&bind_ip) {
+                    return Err(MdnsError::ConfigValidation(format!(
+                        "bind_address {} is not on any of interfaces {:?}",
+                        bind_ip, config.interfaces
+                    )));
+                }
+                vec![bind_ip]
+            }
+            None => scoped.into_iter().collect(),
+        };
+        if addresses.is_empty() {
+            return Err(MdnsError::ConfigValidation(format!(
+                "no local interface matches interfaces {:?}",
+                config.interfaces
+            )));
+        }
+        info!(
+            "Scoping {} to {:?}: {:?}",
+            config.instance_name, config.interfaces, addresses
+        );
+
+        let mut service_info = service_info(config, &addresses[..])?;
+        service_info.set_interfaces(addresses.into_iter().map(IfKind::Addr).collect());
+        return Ok(service_info);
+    }
 
     // Get actual local IP address
     let ip_addr = if let Some(bind_addr) = &config.bind_address {
@@ -93,10 +357,10 @@ pub fn run(
         detected_ip
     };
 
-    let daemon = Arc::new(
-        ServiceDaemon::new().map_err(|e| crate::error::MdnsError::Service(e.to_string()))?,
-    );
+    service_info(config, ip_addr)
+}
 
+fn service_info(config: &ServiceConfig, addresses: impl mdns_sd::AsIpAddrs) -> Result<ServiceInfo> {
     let mut txt_records = HashMap::new();
 
     // Standard SMB/CIFS TXT records (RFC 6763 compatible)
@@ -124,75 +388,39 @@ pub fn run(
     };
     info!("Using hostname: {}", hostname_fqdn);
 
-    let service_info = ServiceInfo::new(
+    ServiceInfo::new(
         &config.service_name,
         &config.instance_name,
         &hostname_fqdn,
-        &ip_addr,
+        addresses,
         config.port,
         Some(txt_records),
     )
-    .map_err(|e| crate::error::MdnsError::Service(e.to_string()))?;
-
-    daemon
-        .register(service_info)
-        .map_err(|e| crate::error::MdnsError::Service(e.to_string()))?;
-    info!(
-        "Successfully registered {} on port {} with IP {}",
-        config.instance_name, config.port, ip_addr
-    );
-
-    // Wait for shutdown signal
-    if let Some(shutdown_rx) = shutdown_rx {
-        shutdown_rx.recv().ok();
-        info!("Received shutdown signal from service control handler.");
-    } else {
-        let (tx, rx) = std::sync::mpsc::channel();
-        ctrlc::set_handler(move || tx.send(()).unwrap())
-            .map_err(|e| crate::error::MdnsError::Thread(e.to_string()))?;
-        info!("Waiting for Ctrl-C...");
-        rx.recv().ok();
-        info!("Received Ctrl-C signal.");
-    }
-
-    graceful_shutdown(daemon)
+    .map(|mut service_info| {
+        service_info.set_requires_probe(config.announce.probe);
+        service_info.set_host_ttl(config.announce.host_ttl_secs);
+        service_info.set_other_ttl(config.announce.other_ttl_secs);
+        service_info
+    })
+    .map_err(MdnsError::daemon(
+        "build service info",
+        Some(&config.instance_name),
+    ))
 }
 
-fn graceful_shutdown(daemon: Arc<ServiceDaemon>) -> Result<()> {
-    info!("Initiating graceful shutdown of mDNS daemon...");
-
-    let shutdown_result = Arc::new(Mutex::new(None));
-    let shutdown_result_clone = Arc::clone(&shutdown_result);
-
-    let shutdown_thread = thread::spawn(move || {
-        let result = daemon.shutdown();
-        let mut locked = shutdown_result_clone.lock().unwrap();
-        *locked = Some(result);
-    });
+/// Register the configured service and block until `shutdown_rx` fires.
+pub fn run(shutdown_rx: Receiver<()>, config_override: Option<ServiceConfig>) -> Result<()> {
+    info!("Initializing mDNS Responder Service...");
 
-    let timeout = Duration::from_secs(SHUTDOWN_TIMEOUT_SECS);
-    let start = std::time::Instant::now();
+    let config = load_config(config_override)?;
+    info!("Using configuration: {:?}", config);
 
-    loop {
-        if shutdown_thread.is_finished() {
-            let result = shutdown_result.lock().unwrap();
-            if let Some(Ok(_)) = *result {
-                info!("Graceful shutdown completed successfully");
-                return Ok(());
-            } else if let Some(Err(e)) = &*result {
-                warn!("Daemon shutdown reported error: {}", e);
-                return Ok(());
-            }
-        }
+    let shutdown_timeout = config.shutdown_timeout();
+    let mut responder = Responder::start(config)?;
 
-        if start.elapsed() > timeout {
-            warn!(
-                "Daemon shutdown exceeded {}s timeout, but will continue gracefully",
-                SHUTDOWN_TIMEOUT_SECS
-            );
-            return Ok(());
-        }
+    // Wait for shutdown signal
+    shutdown_rx.recv().ok();
+    info!("Received shutdown signal.");
 
-        thread::sleep(Duration::from_millis(100));
-    }
+    responder.shutdown(shutdown_timeout)
 }
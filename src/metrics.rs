@@ -0,0 +1,341 @@
+use crate::error::Result;
+use crate::responder::{Responder, ResponderStatus};
+use crate::slot::Slot;
+use log::{info, warn};
+use mdns_sd::{DaemonEvent, Receiver};
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::net::{SocketAddr, TcpListener, TcpStream};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const ACCEPT_POLL_MILLIS: u64 = 100;
+/// Time a client gets to send its whole request before being dropped
+const READ_TIMEOUT_SECS: u64 = 5;
+/// Longest request accepted, request line and headers together
+const MAX_REQUEST_BYTES: usize = 8192;
+/// Connections served at once; further clients are turned away
+const MAX_CONNECTIONS: usize = 16;
+const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Event counters kept by a [`Responder`].
+#[derive(Debug, Default)]
+pub struct Counters {
+    registrations: AtomicU64,
+    unregistrations: AtomicU64,
+    reannounces: AtomicU64,
+    config_reloads: AtomicU64,
+    name_conflicts: AtomicU64,
+    ip_changes: AtomicU64,
+    daemon_errors: AtomicU64,
+    dns_updates: AtomicU64,
+    dns_update_failures: AtomicU64,
+    reflected_packets: AtomicU64,
+}
+
+impl Counters {
+    pub(crate) fn registered(&self) {
+        self.registrations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn unregistered(&self) {
+        self.unregistrations.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn reannounced(&self) {
+        self.reannounces.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn reloaded(&self) {
+        self.config_reloads.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "wide-area")]
+    pub(crate) fn dns_updated(&self) {
+        self.dns_updates.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "wide-area")]
+    pub(crate) fn dns_update_failed(&self) {
+        self.dns_update_failures.fetch_add(1, Ordering::Relaxed);
+    }
+
+    #[cfg(feature = "reflector")]
+    pub(crate) fn reflected(&self) {
+        self.reflected_packets.fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Count daemon events until the daemon shuts down and closes `events`.
+    pub(crate) fn watch(self: Arc<Self>, events: Receiver<DaemonEvent>) {
+        thread::spawn(move || {
+            while let Ok(event) = events.recv() {
+                match event {
+                    DaemonEvent::NameChange(change) => {
+                        warn!(
+                            "Name conflict: {} renamed to {}",
+                            change.original, change.new_name
+                        );
+                        self.name_conflicts.fetch_add(1, Ordering::Relaxed);
+                    }
+                    DaemonEvent::IpAdd(ip) | DaemonEvent::IpDel(ip) => {
+                        info!("Interface address changed: {}", ip);
+                        self.ip_changes.fetch_add(1, Ordering::Relaxed);
+                    }
+                    DaemonEvent::Error(e) => {
+                        warn!("mDNS daemon error: {}", e);
+                        self.daemon_errors.fetch_add(1, Ordering::Relaxed);
+                    }
+                    _ => {}
+                }
+            }
+        });
+    }
+
+    fn samples(&self) -> [(&'static str, &'static str, u64); 10] {
+        let get = |counter: &AtomicU64| counter.load(Ordering::Relaxed);
+        [
+            (
+                "registrations_total",
+                "Services registered with the daemon",
+                get(&self.registrations),
+            ),
+            (
+                "unregistrations_total",
+                "Services unregistered from the daemon",
+                get(&self.unregistrations),
+            ),
+            (
+                "reannounces_total",
+                "Explicit re-announcements of all services",
+                get(&self.reannounces),
+            ),
+            (
+                "config_reloads_total",
+                "Primary service replaced from a reloaded config",
+                get(&self.config_reloads),
+            ),
+            (
+                "name_conflicts_total",
+                "Names changed by the daemon to resolve a conflict",
+                get(&self.name_conflicts),
+            ),
+            (
+                "ip_changes_total",
+                "Interface addresses added or removed",
+                get(&self.ip_changes),
+            ),
+            (
+                "daemon_errors_total",
+                "Errors reported by the daemon",
+                get(&self.daemon_errors),
+            ),
+            (
+                "dns_updates_total",
+                "Wide-area DNS updates accepted by the server",
+                get(&self.dns_updates),
+            ),
+            (
+                "dns_update_failures_total",
+                "Wide-area DNS updates refused or unanswered",
+                get(&self.dns_update_failures),
+            ),
+            (
+                "reflected_packets_total",
+                "mDNS packets relayed between interfaces",
+                get(&self.reflected_packets),
+            ),
+        ]
+    }
+}
+
+/// Render responder state in the Prometheus text exposition format.
+///
+/// `daemon_metrics` are the raw `mdns-sd` counters, exported with a
+/// `counter` label.
+pub fn render(
+    status: &ResponderStatus,
+    counters: &Counters,
+    daemon_metrics: &BTreeMap<String, i64>,
+) -> String {
+    let mut out = String::new();
+
+    gauge(
+        &mut out,
+        "up",
+        "Whether the mDNS daemon answered its status query",
+        u64::from(status.daemon_running),
+    );
+    gauge(
+        &mut out,
+        "services",
+        "Services currently advertised",
+        status.services.len() as u64,
+    );
+    for (name, help, value) in counters.samples() {
+        writeln!(out, "# HELP mdns_responder_{} {}", name, help).ok();
+        writeln!(out, "# TYPE mdns_responder_{} counter", name).ok();
+        writeln!(out, "mdns_responder_{} {}", name, value).ok();
+    }
+
+    if !daemon_metrics.is_empty() {
+        writeln!(out, "# HELP mdns_responder_daemon Counters kept by mdns-sd").ok();
+        writeln!(out, "# TYPE mdns_responder_daemon untyped").ok();
+        for (name, value) in daemon_metrics {
+            writeln!(
+                out,
+                "mdns_responder_daemon{{counter=\"{}\"}} {}",
+                name, value
+            )
+            .ok();
+        }
+    }
+
+    out
+}
+
+fn gauge(out: &mut String, name: &str, help: &str, value: u64) {
+    writeln!(out, "# HELP mdns_responder_{} {}", name, help).ok();
+    writeln!(out, "# TYPE mdns_responder_{} gauge", name).ok();
+    writeln!(out, "mdns_responder_{} {}", name, value).ok();
+}
+
+/// Minimal HTTP listener serving `GET /metrics` for a shared [`Responder`].
+///
+/// Stops accepting connections when dropped.
+pub struct MetricsServer {
+    address: SocketAddr,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl MetricsServer {
+    /// Listen on `address`, e.g. `127.0.0.1:9464`.
+    pub fn start(address: &str, responder: Arc<Mutex<Responder>>) -> Result<Self> {
+        let listener = TcpListener::bind(address)?;
+        listener.set_nonblocking(true)?;
+        let address = listener.local_addr()?;
+        info!("Metrics available at http://{}/metrics", address);
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let connections = Arc::new(AtomicUsize::new(0));
+        let thread = thread::spawn({
+            let stop = Arc::clone(&stop);
+            move || {
+                while !stop.load(Ordering::Relaxed) {
+                    match listener.accept() {
+                        Ok((stream, _)) => {
+                            let Some(slot) = Slot::take(&connections, MAX_CONNECTIONS) else {
+                                respond(&stream, "503 Service Unavailable", "Busy\n").ok();
+                                continue;
+                            };
+                            let responder = Arc::clone(&responder);
+                            thread::spawn(move || {
+                                let _slot = slot;
+                                if let Err(e) = serve_connection(stream, &responder) {
+                                    warn!("Metrics request failed: {}", e);
+                                }
+                            });
+                        }
+                        Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                            thread::sleep(Duration::from_millis(ACCEPT_POLL_MILLIS));
+                        }
+                        Err(e) => {
+                            warn!("Metrics accept failed: {}", e);
+                            thread::sleep(Duration::from_millis(ACCEPT_POLL_MILLIS));
+                        }
+                    }
+                }
+            }
+        });
+
+        Ok(Self {
+            address,
+            stop,
+            thread: Some(thread),
+        })
+    }
+
+    /// Address actually bound, useful when listening on port 0.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.address
+    }
+}
+
+impl Drop for MetricsServer {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// Answer one request of at most [`MAX_REQUEST_BYTES`], which must arrive
+/// within [`READ_TIMEOUT_SECS`] so slow clients cannot hold a connection slot.
+fn serve_connection(stream: TcpStream, responder: &Mutex<Responder>) -> Result<()> {
+    stream.set_nonblocking(false)?;
+    let mut reader = BufReader::new(Deadline {
+        stream: &stream,
+        deadline: Instant::now() + Duration::from_secs(READ_TIMEOUT_SECS),
+    })
+    .take(MAX_REQUEST_BYTES as u64);
+
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    // Skip headers; requests never carry a body we care about
+    let mut header = String::new();
+    while reader.read_line(&mut header)? > 2 {
+        header.clear();
+    }
+    if reader.limit() == 0 {
+        return respond(
+            &stream,
+            "431 Request Header Fields Too Large",
+            "Request too large\n",
+        );
+    }
+
+    let mut parts = request_line.split_whitespace();
+    let (status, body) = match (parts.next(), parts.next()) {
+        (Some("GET"), Some("/metrics")) => {
+            let responder = responder.lock().unwrap_or_else(PoisonError::into_inner);
+            ("200 OK", responder.metrics())
+        }
+        (Some("GET"), Some(_)) => ("404 Not Found", "Not found\n".to_string()),
+        _ => ("405 Method Not Allowed", "Method not allowed\n".to_string()),
+    };
+    respond(&stream, status, &body)
+}
+
+fn respond(mut stream: &TcpStream, status: &str, body: &str) -> Result<()> {
+    let response = format!(
+        "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        CONTENT_TYPE,
+        body.len(),
+        body
+    );
+    stream.write_all(response.as_bytes())?;
+    Ok(())
+}
+
+/// Stream whose reads fail once `deadline` passes, however slowly bytes
+/// trickle in.
+struct Deadline<'a> {
+    stream: &'a TcpStream,
+    deadline: Instant,
+}
+
+impl Read for Deadline<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let remaining = self.deadline.saturating_duration_since(Instant::now());
+        if remaining.is_zero() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+        self.stream.set_read_timeout(Some(remaining))?;
+        self.stream.read(buf)
+    }
+}
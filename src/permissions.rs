@@ -0,0 +1,296 @@
+use crate::error::{MdnsError, Result};
+use std::path::Path;
+
+/// Refuse a config file that accounts other than administrators could change.
+///
+/// The directory is checked as well, since whoever can write to it can
+/// replace the file. Files belonging to the account the process runs as are
+/// accepted, so a config in a developer's home directory still loads.
+///
+/// On Windows the check needs the `windows` feature and is skipped without it.
+pub fn check_config_permissions(path: &Path) -> Result<()> {
+    for target in [path, parent_dir(path)] {
+        if let Some(writer) = imp::untrusted_writer(target)? {
+            return Err(MdnsError::ConfigPermissions {
+                path: target.to_path_buf(),
+                writer,
+            });
+        }
+    }
+    Ok(())
+}
+
+/// Let only administrators change the config at `path` and its directory.
+///
+/// On Windows SYSTEM and Administrators get full control and Users read
+/// access, replacing any inherited ACL. On Unix both are given to root, with
+/// mode 0755 for the directory and 0644 for the file.
+pub fn secure_config(path: &Path) -> Result<()> {
+    imp::restrict(parent_dir(path), true)?;
+    imp::restrict(path, false)
+}
+
+fn parent_dir(path: &Path) -> &Path {
+    path.parent()
+        .filter(|dir| !dir.as_os_str().is_empty())
+        .unwrap_or(Path::new("."))
+}
+
+#[cfg(unix)]
+mod imp {
+    use crate::error::Result;
+    use std::fs::{self, Permissions};
+    use std::os::unix::fs::{MetadataExt, PermissionsExt};
+    use std::path::Path;
+
+    /// Who other than root or the current user could write `path`, if anyone.
+    pub(super) fn untrusted_writer(path: &Path) -> Result<Option<String>> {
+        let metadata = fs::metadata(path)?;
+        // SAFETY: geteuid has no preconditions and cannot fail.
+        let euid = unsafe { libc::geteuid() };
+        if metadata.uid() != 0 && metadata.uid() != euid {
+            return Ok(Some(format!("its owner (uid {})", metadata.uid())));
+        }
+        let mode = metadata.mode();
+        // In a sticky directory such as /tmp others can add files but not
+        // replace ours, and the owner check above covers the file itself
+        let sticky = metadata.is_dir() && mode & 0o1000 != 0;
+        if mode & 0o002 != 0 && !sticky {
+            return Ok(Some("all users".to_string()));
+        }
+        if mode & 0o020 != 0 && metadata.gid() != 0 && !sticky {
+            return Ok(Some(format!("group {}", metadata.gid())));
+        }
+        Ok(None)
+    }
+
+    pub(super) fn restrict(path: &Path, is_dir: bool) -> Result<()> {
+        std::os::unix::fs::chown(path, Some(0), Some(0))?;
+        let mode = if is_dir { 0o755 } else { 0o644 };
+        fs::set_permissions(path, Permissions::from_mode(mode))?;
+        Ok(())
+    }
+}
+
+#[cfg(all(windows, feature = "windows"))]
+mod imp {
+    use crate::error::Result;
+    use std::path::Path;
+    use windows::Win32::Foundation::{CloseHandle, ERROR_SUCCESS, HANDLE, HLOCAL, LocalFree};
+    use windows::Win32::Security::Authorization::{
+        ConvertSidToStringSidW, ConvertStringSecurityDescriptorToSecurityDescriptorW,
+        GetNamedSecurityInfoW, SDDL_REVISION_1, SE_FILE_OBJECT, SetNamedSecurityInfoW,
+    };
+    use windows::Win32::Security::{
+        ACCESS_ALLOWED_ACE, ACE_HEADER, ACL, ACL_SIZE_INFORMATION, AclSizeInformation,
+        DACL_SECURITY_INFORMATION, EqualSid, GetAce, GetAclInformation, GetSecurityDescriptorDacl,
+        GetTokenInformation, INHERIT_ONLY_ACE, IsWellKnownSid, PROTECTED_DACL_SECURITY_INFORMATION,
+        PSECURITY_DESCRIPTOR, PSID, TOKEN_QUERY, TOKEN_USER, TokenUser,
+        WinBuiltinAdministratorsSid, WinLocalSystemSid,
+    };
+    use windows::Win32::System::SystemServices::ACCESS_ALLOWED_ACE_TYPE;
+    use windows::Win32::System::Threading::{GetCurrentProcess, OpenProcessToken};
+    use windows::core::{BOOL, HSTRING, PWSTR};
+
+    /// Full control for SYSTEM and Administrators, read and execute for
+    /// Users, inherited by everything in the directory and not by it.
+    const CONFIG_SDDL: &str = "D:P(A;OICI;FA;;;SY)(A;OICI;FA;;;BA)(A;OICI;0x1200a9;;;BU)";
+
+    /// Rights that let a trustee change a file or what a directory holds:
+    /// write and append data, delete child, delete, write DAC, write owner,
+    /// generic all and generic write.
+    const WRITE_RIGHTS: u32 = 0x0000_0002
+        | 0x0000_0004
+        | 0x0000_0040
+        | 0x0001_0000
+        | 0x0004_0000
+        | 0x0008_0000
+        | 0x1000_0000
+        | 0x4000_0000;
+
+    /// The TrustedInstaller service, which owns much of the system
+    const TRUSTED_INSTALLER: &str =
+        "S-1-5-80-956008885-3418522649-1831038044-1853292631-2271478464";
+
+    /// The SID of the first allow ACE granting write access to someone other
+    /// than SYSTEM, Administrators, TrustedInstaller or the current user.
+    pub(super) fn untrusted_writer(path: &Path) -> Result<Option<String>> {
+        let user = current_user()?;
+        let name = HSTRING::from(path);
+        let mut dacl: *mut ACL = std::ptr::null_mut();
+        let mut descriptor = PSECURITY_DESCRIPTOR::default();
+
+        // SAFETY: `name` is NUL-terminated; `dacl` points into `descriptor`,
+        // which is only freed after the last ACE has been read.
+        unsafe {
+            let status = GetNamedSecurityInfoW(
+                &name,
+                SE_FILE_OBJECT,
+                DACL_SECURITY_INFORMATION,
+                None,
+                None,
+                Some(&mut dacl),
+                None,
+                &mut descriptor,
+            );
+            if status != ERROR_SUCCESS {
+                return Err(windows::core::Error::from(status.to_hresult()).into());
+            }
+            // No DACL at all grants everyone full control
+            let writer = if dacl.is_null() {
+                Ok(Some("everyone (no DACL)".to_string()))
+            } else {
+                write_ace_sids(dacl).map(|sids| {
+                    sids.into_iter()
+                        .find(|&sid| !is_trusted(sid, &user))
+                        .map(|sid| sid_string(sid).unwrap_or_else(|_| "an unknown account".into()))
+                })
+            };
+            LocalFree(Some(HLOCAL(descriptor.0)));
+            Ok(writer?)
+        }
+    }
+
+    pub(super) fn restrict(path: &Path, _is_dir: bool) -> Result<()> {
+        let name = HSTRING::from(path);
+        let sddl = HSTRING::from(CONFIG_SDDL);
+        let mut descriptor = PSECURITY_DESCRIPTOR::default();
+
+        // SAFETY: both strings are NUL-terminated and the DACL lives inside
+        // `descriptor`, which is freed only after it has been applied.
+        unsafe {
+            ConvertStringSecurityDescriptorToSecurityDescriptorW(
+                &sddl,
+                SDDL_REVISION_1,
+                &mut descriptor,
+                None,
+            )?;
+            let mut present = BOOL::default();
+            let mut defaulted = BOOL::default();
+            let mut dacl: *mut ACL = std::ptr::null_mut();
+            let result =
+                GetSecurityDescriptorDacl(descriptor, &mut present, &mut dacl, &mut defaulted)
+                    .and_then(|()| {
+                        SetNamedSecurityInfoW(
+                            &name,
+                            SE_FILE_OBJECT,
+                            DACL_SECURITY_INFORMATION | PROTECTED_DACL_SECURITY_INFORMATION,
+                            None,
+                            None,
+                            Some(dacl),
+                            None,
+                        )
+                        .ok()
+                    });
+            LocalFree(Some(HLOCAL(descriptor.0)));
+            Ok(result?)
+        }
+    }
+
+    /// SIDs of the allow ACEs in `dacl` that grant write access to the object
+    /// itself, rather than only to what is created inside it.
+    ///
+    /// # Safety
+    /// `dacl` must point to a valid ACL.
+    unsafe fn write_ace_sids(dacl: *const ACL) -> windows::core::Result<Vec<PSID>> {
+        let mut sids = Vec::new();
+        let mut info = ACL_SIZE_INFORMATION::default();
+        unsafe {
+            GetAclInformation(
+                dacl,
+                &mut info as *mut _ as *mut _,
+                size_of::<ACL_SIZE_INFORMATION>() as u32,
+                AclSizeInformation,
+            )?;
+            for index in 0..info.AceCount {
+                let mut ace = std::ptr::null_mut();
+                GetAce(dacl, index, &mut ace)?;
+                let header = &*(ace as *const ACE_HEADER);
+                if u32::from(header.AceType) != ACCESS_ALLOWED_ACE_TYPE
+                    || u32::from(header.AceFlags) & INHERIT_ONLY_ACE.0 != 0
+                {
+                    continue;
+                }
+                let allowed = &*(ace as *const ACCESS_ALLOWED_ACE);
+                if allowed.Mask & WRITE_RIGHTS != 0 {
+                    sids.push(PSID(&allowed.SidStart as *const u32 as *mut _));
+                }
+            }
+        }
+        Ok(sids)
+    }
+
+    /// # Safety
+    /// `sid` must point to a valid SID.
+    unsafe fn is_trusted(sid: PSID, user: &[u8]) -> bool {
+        unsafe {
+            IsWellKnownSid(sid, WinLocalSystemSid).as_bool()
+                || IsWellKnownSid(sid, WinBuiltinAdministratorsSid).as_bool()
+                || EqualSid(sid, user_sid(user)).is_ok()
+                || sid_string(sid).is_ok_and(|sid| sid == TRUSTED_INSTALLER)
+        }
+    }
+
+    /// # Safety
+    /// `sid` must point to a valid SID.
+    unsafe fn sid_string(sid: PSID) -> windows::core::Result<String> {
+        let mut string = PWSTR::null();
+        unsafe {
+            ConvertSidToStringSidW(sid, &mut string)?;
+            let result = string
+                .to_string()
+                .map_err(|_| windows::core::Error::empty());
+            LocalFree(Some(HLOCAL(string.0 as _)));
+            result
+        }
+    }
+
+    /// The process token's `TOKEN_USER`, as the raw buffer it is returned in.
+    fn current_user() -> windows::core::Result<Vec<u8>> {
+        let mut token = HANDLE::default();
+        // SAFETY: the token handle is closed before returning and the buffer
+        // is sized by the first GetTokenInformation call.
+        unsafe {
+            OpenProcessToken(GetCurrentProcess(), TOKEN_QUERY, &mut token)?;
+            let mut len = 0;
+            // Fails with ERROR_INSUFFICIENT_BUFFER, reporting the size needed
+            let _ = GetTokenInformation(token, TokenUser, None, 0, &mut len);
+            let mut buffer = vec![0u8; len as usize];
+            let result = GetTokenInformation(
+                token,
+                TokenUser,
+                Some(buffer.as_mut_ptr().cast()),
+                len,
+                &mut len,
+            );
+            CloseHandle(token).ok();
+            result.map(|()| buffer)
+        }
+    }
+
+    /// # Safety
+    /// `user` must be a buffer filled in by [`current_user`].
+    unsafe fn user_sid(user: &[u8]) -> PSID {
+        unsafe {
+            std::ptr::read_unaligned(user.as_ptr() as *const TOKEN_USER)
+                .User
+                .Sid
+        }
+    }
+}
+
+#[cfg(all(windows, not(feature = "windows")))]
+mod imp {
+    use crate::error::{MdnsError, Result};
+    use std::path::Path;
+
+    pub(super) fn untrusted_writer(_path: &Path) -> Result<Option<String>> {
+        Ok(None)
+    }
+
+    pub(super) fn restrict(_path: &Path, _is_dir: bool) -> Result<()> {
+        Err(MdnsError::Service(
+            "setting config ACLs requires the `windows` feature".to_string(),
+        ))
+    }
+}
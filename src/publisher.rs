@@ -0,0 +1,244 @@
+use crate::config::{ServiceConfig, SystemResponderPolicy};
+use crate::error::{MdnsError, Result};
+use log::{info, warn};
+use mdns_sd::{DaemonStatus, ServiceDaemon, ServiceInfo, UnregisterStatus};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError};
+use std::time::{Duration, Instant};
+
+const STATUS_TIMEOUT_SECS: u64 = 2;
+
+/// Where a [`Responder`](crate::Responder) publishes its services: its own
+/// mDNS daemon or the host's responder.
+///
+/// The responder keeps track of what is registered and only asks for
+/// services it has not registered yet, or withdraws ones it has.
+pub trait Publisher: Send {
+    /// What answers for the services, for log and error messages
+    fn name(&self) -> &'static str;
+
+    /// Publish a service that is not registered yet.
+    fn register(&mut self, service_info: &ServiceInfo) -> Result<()>;
+
+    /// Withdraw a registered service, waiting up to `timeout` for its
+    /// goodbye to go out. A missing acknowledgement is an [`MdnsError::Timeout`].
+    fn unregister(&mut self, fullname: &str, timeout: Duration) -> Result<()>;
+
+    /// Announce a registered service again. Responders that keep their
+    /// records fresh on their own ignore this.
+    fn reannounce(&self, _service_info: &ServiceInfo) -> Result<()> {
+        Ok(())
+    }
+
+    /// Whether services can be published right now.
+    fn is_running(&self) -> bool;
+
+    /// Stop, once every service is unregistered.
+    fn shutdown(&mut self, timeout: Duration) -> Result<()>;
+
+    /// The embedded daemon, which announcement pacing, browsing and daemon
+    /// metrics need.
+    fn daemon(&self) -> Option<&ServiceDaemon> {
+        None
+    }
+}
+
+/// The publisher [`Responder::start`](crate::Responder::start) uses for
+/// `config`: the host's responder if `system_responder` is `register`,
+/// otherwise an embedded daemon.
+pub fn for_config(config: &ServiceConfig) -> Result<Box<dyn Publisher>> {
+    if config.system_responder != SystemResponderPolicy::Register {
+        crate::coexistence::check(config)?;
+        return Ok(Box::new(MdnsSdPublisher::new()?));
+    }
+    #[cfg(all(windows, feature = "windows"))]
+    let publisher: Result<Box<dyn Publisher>> =
+        Ok(Box::new(crate::dns_service::DnsServicePublisher::default()));
+    #[cfg(all(unix, feature = "avahi"))]
+    let publisher: Result<Box<dyn Publisher>> = crate::avahi::AvahiPublisher::connect()
+        .map(|publisher| Box::new(publisher) as Box<dyn Publisher>);
+    #[cfg(not(any(all(windows, feature = "windows"), all(unix, feature = "avahi"))))]
+    let publisher: Result<Box<dyn Publisher>> = Err(MdnsError::ConfigValidation(
+        "system_responder \"register\" needs Windows' DnsServiceRegister or avahi-daemon"
+            .to_string(),
+    ));
+    let publisher = publisher?;
+    info!("Registering through {}", publisher.name());
+    Ok(publisher)
+}
+
+/// Publishes through an embedded `mdns-sd` daemon, which answers on UDP 5353
+/// itself.
+pub struct MdnsSdPublisher {
+    daemon: ServiceDaemon,
+}
+
+impl MdnsSdPublisher {
+    /// Start a daemon.
+    pub fn new() -> Result<Self> {
+        let daemon = ServiceDaemon::new().map_err(MdnsError::daemon("start", None))?;
+        Ok(Self { daemon })
+    }
+}
+
+impl Publisher for MdnsSdPublisher {
+    fn name(&self) -> &'static str {
+        "mDNS daemon"
+    }
+
+    fn register(&mut self, service_info: &ServiceInfo) -> Result<()> {
+        self.daemon
+            .register(service_info.clone())
+            .map_err(MdnsError::daemon(
+                "register",
+                Some(service_info.get_fullname()),
+            ))
+    }
+
+    fn unregister(&mut self, fullname: &str, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let receiver = self
+            .daemon
+            .unregister(fullname)
+            .map_err(MdnsError::daemon("unregister", Some(fullname)))?;
+        match receiver.recv_deadline(deadline) {
+            Ok(UnregisterStatus::OK) => Ok(()),
+            Ok(UnregisterStatus::NotFound) => {
+                warn!("{} was not registered with the daemon", fullname);
+                Ok(())
+            }
+            Err(_) if Instant::now() >= deadline => Err(MdnsError::Timeout(format!(
+                "no goodbye for {} within {:?}",
+                fullname, timeout
+            ))),
+            Err(_) => Err(MdnsError::daemon("unregister", Some(fullname))(
+                daemon_stopped(),
+            )),
+        }
+    }
+
+    fn reannounce(&self, service_info: &ServiceInfo) -> Result<()> {
+        // Registering again makes the daemon announce again
+        self.daemon
+            .register(service_info.clone())
+            .map_err(MdnsError::daemon(
+                "re-announce",
+                Some(service_info.get_fullname()),
+            ))
+    }
+
+    fn is_running(&self) -> bool {
+        self.daemon
+            .status()
+            .ok()
+            .and_then(|rx| {
+                rx.recv_timeout(Duration::from_secs(STATUS_TIMEOUT_SECS))
+                    .ok()
+            })
+            .is_some_and(|status| status == DaemonStatus::Running)
+    }
+
+    fn shutdown(&mut self, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let receiver = self
+            .daemon
+            .shutdown()
+            .map_err(MdnsError::daemon("shut down", None))?;
+        match receiver.recv_deadline(deadline) {
+            Ok(DaemonStatus::Shutdown) => Ok(()),
+            Ok(status) => Err(MdnsError::daemon("shut down", None)(mdns_sd::Error::Msg(
+                format!("daemon reported {:?}", status),
+            ))),
+            Err(_) if Instant::now() >= deadline => Err(MdnsError::Timeout(format!(
+                "daemon shutdown within {:?}",
+                timeout
+            ))),
+            Err(_) => Err(MdnsError::daemon("shut down", None)(daemon_stopped())),
+        }
+    }
+
+    fn daemon(&self) -> Option<&ServiceDaemon> {
+        Some(&self.daemon)
+    }
+}
+
+/// Why a daemon's reply channel closed before it answered.
+fn daemon_stopped() -> mdns_sd::Error {
+    mdns_sd::Error::Msg("daemon stopped before replying".to_string())
+}
+
+/// A call made on a [`FakePublisher`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PublisherCall {
+    Register(String),
+    Unregister(String),
+    Reannounce(String),
+    Shutdown,
+}
+
+/// In-memory [`Publisher`] that records its calls instead of publishing,
+/// for testing code that drives a [`Responder`](crate::Responder).
+///
+/// Clones share the record, so keep one to inspect after handing another
+/// to [`Responder::with_publisher`](crate::Responder::with_publisher).
+#[derive(Debug, Clone, Default)]
+pub struct FakePublisher {
+    calls: Arc<Mutex<Vec<PublisherCall>>>,
+    fail_unregister: Arc<AtomicBool>,
+}
+
+impl FakePublisher {
+    /// Calls so far, oldest first.
+    pub fn calls(&self) -> Vec<PublisherCall> {
+        self.record().clone()
+    }
+
+    /// Make `unregister` fail, without recording the call, until called
+    /// again with `false`.
+    pub fn fail_unregister(&self, fail: bool) {
+        self.fail_unregister.store(fail, Ordering::Relaxed);
+    }
+
+    fn record(&self) -> std::sync::MutexGuard<'_, Vec<PublisherCall>> {
+        self.calls.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Publisher for FakePublisher {
+    fn name(&self) -> &'static str {
+        "fake publisher"
+    }
+
+    fn register(&mut self, service_info: &ServiceInfo) -> Result<()> {
+        let fullname = service_info.get_fullname().to_string();
+        self.record().push(PublisherCall::Register(fullname));
+        Ok(())
+    }
+
+    fn unregister(&mut self, fullname: &str, _timeout: Duration) -> Result<()> {
+        if self.fail_unregister.load(Ordering::Relaxed) {
+            return Err(MdnsError::Service(format!(
+                "fake failure to unregister {}",
+                fullname
+            )));
+        }
+        self.record()
+            .push(PublisherCall::Unregister(fullname.to_string()));
+        Ok(())
+    }
+
+    fn reannounce(&self, service_info: &ServiceInfo) -> Result<()> {
+        let fullname = service_info.get_fullname().to_string();
+        self.record().push(PublisherCall::Reannounce(fullname));
+        Ok(())
+    }
+
+    fn is_running(&self) -> bool {
+        !self.record().contains(&PublisherCall::Shutdown)
+    }
+
+    fn shutdown(&mut self, _timeout: Duration) -> Result<()> {
+        self.record().push(PublisherCall::Shutdown);
+        Ok(())
+    }
+}
@@ -0,0 +1,623 @@
+use crate::config::{InterfaceScope, ReflectorConfig};
+use crate::error::{MdnsError, Result};
+use crate::mdns_service::{self, LocalInterface};
+use crate::metrics::Counters;
+use log::{debug, info, warn};
+use socket2::{Domain, Protocol, SockRef, Socket, Type};
+use std::collections::hash_map::DefaultHasher;
+use std::collections::{HashMap, HashSet};
+use std::hash::{Hash, Hasher};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr, UdpSocket};
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+const MDNS_PORT: u16 = 5353;
+const MDNS_GROUP: Ipv4Addr = Ipv4Addr::new(224, 0, 0, 251);
+const POLL_MILLIS: u64 = 200;
+/// Largest mDNS message (RFC 6762 section 17)
+const MAX_PACKET: usize = 9_000;
+/// Identical packets seen again within this window are copies, not repeats;
+/// mDNS itself never repeats a packet sooner than one second
+const DUPLICATE_WINDOW_MILLIS: u64 = 500;
+/// How long a host stays relayed after the last SRV record naming it
+const MIN_HOST_MEMORY_SECS: u64 = 120;
+
+const FLAG_QR: u16 = 0x8000;
+const CLASS_UNICAST_RESPONSE: u16 = 0x8000;
+const TYPE_A: u16 = 1;
+const TYPE_PTR: u16 = 12;
+const TYPE_CNAME: u16 = 5;
+const TYPE_AAAA: u16 = 28;
+const TYPE_SRV: u16 = 33;
+const TYPE_NSEC: u16 = 47;
+const TYPE_ANY: u16 = 255;
+
+/// Domain name as raw labels, compared case-insensitively.
+pub(crate) type Labels = Vec<Vec<u8>>;
+
+/// Relays mDNS traffic for selected service types between interfaces.
+///
+/// Listens on the mDNS group on every configured IPv4 interface and repeats
+/// what it hears on one onto the others, keeping only records of the
+/// selected service types and the hosts they point at. Stops when dropped.
+///
+/// Records are relayed as they are, not rewritten: the links are routed
+/// subnets, so a host's own addresses reach it from every link and the
+/// reflector's address would not. Only link-local addresses, which no other
+/// link can reach, are dropped.
+pub(crate) struct Reflector {
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl Reflector {
+    pub(crate) fn start(config: &ReflectorConfig, counters: Arc<Counters>) -> Result<Self> {
+        let interfaces = mdns_service::local_interfaces()?;
+        let links = links(config, &interfaces)?;
+        let own = interfaces.iter().map(|intf| intf.ip).collect();
+        let socket = bind(&links)?;
+        info!(
+            "Reflecting {} between {}",
+            if config.service_types.is_empty() {
+                "all service types".to_string()
+            } else {
+                config.service_types.join(", ")
+            },
+            links
+                .iter()
+                .map(|link| format!("{} ({})", link.name, link.ip))
+                .collect::<Vec<_>>()
+                .join(", ")
+        );
+
+        let mut relay = Relay::new(config, links, own);
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = thread::spawn({
+            let stop = Arc::clone(&stop);
+            move || {
+                let mut buf = vec![0; MAX_PACKET];
+                while !stop.load(Ordering::Relaxed) {
+                    let (len, source) = match socket.recv_from(&mut buf) {
+                        Ok(received) => received,
+                        Err(e)
+                            if matches!(
+                                e.kind(),
+                                io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut
+                            ) =>
+                        {
+                            continue;
+                        }
+                        Err(e) => {
+                            warn!("Reflector receive failed: {}", e);
+                            thread::sleep(Duration::from_millis(POLL_MILLIS));
+                            continue;
+                        }
+                    };
+                    let Some((from, packet)) = relay.relay(&buf[..len], source, Instant::now())
+                    else {
+                        continue;
+                    };
+                    for (index, link) in relay.links.iter().enumerate() {
+                        if index == from {
+                            continue;
+                        }
+                        if let Err(e) = send(&socket, link, &packet) {
+                            warn!("Reflecting to {} failed: {}", link.name, e);
+                        }
+                    }
+                    counters.reflected();
+                }
+            }
+        });
+
+        Ok(Self {
+            stop,
+            thread: Some(thread),
+        })
+    }
+}
+
+impl Drop for Reflector {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// One IPv4 address per configured interface, skipping any on a subnet
+/// already covered so nothing is ever relayed back onto its own link.
+fn links(config: &ReflectorConfig, interfaces: &[LocalInterface]) -> Result<Vec<LocalInterface>> {
+    let scopes = config
+        .interfaces
+        .iter()
+        .map(|entry| InterfaceScope::parse(entry))
+        .collect::<Result<Vec<_>>>()?;
+
+    let mut links: Vec<LocalInterface> = Vec::new();
+    for intf in interfaces {
+        if !intf.ip.is_ipv4()
+            || intf.ip.is_loopback()
+            || !scopes
+                .iter()
+                .any(|scope| scope.matches(&intf.name, intf.ip))
+            || links.iter().any(|link| link.on_link(intf.ip))
+        {
+            continue;
+        }
+        links.push(intf.clone());
+    }
+
+    if links.len() < 2 {
+        return Err(MdnsError::ConfigValidation(format!(
+            "reflector.interfaces must match at least two IPv4 subnets, found {}",
+            links.len()
+        )));
+    }
+    Ok(links)
+}
+
+/// Socket on port 5353, shared with the mDNS daemon and any other responder.
+///
+/// On Linux the socket is bound to the group address, so the kernel hands it
+/// only datagrams sent to the group and unicast queries and replies still
+/// reach the daemon. Elsewhere it is bound to the wildcard address, and a
+/// unicast datagram to port 5353 may land here instead of with the daemon.
+fn bind(links: &[LocalInterface]) -> Result<UdpSocket> {
+    let socket = Socket::new(Domain::IPV4, Type::DGRAM, Some(Protocol::UDP))?;
+    socket.set_reuse_address(true)?;
+    #[cfg(unix)]
+    socket.set_reuse_port(true)?;
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    let address = MDNS_GROUP;
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    let address = Ipv4Addr::UNSPECIFIED;
+    socket.bind(&SocketAddr::from((address, MDNS_PORT)).into())?;
+    for link in links {
+        if let IpAddr::V4(ip) = link.ip {
+            socket.join_multicast_v4(&MDNS_GROUP, &ip)?;
+        }
+    }
+    // Our own copies must not come back to us or reach the local daemon twice
+    socket.set_multicast_loop_v4(false)?;
+    socket.set_multicast_ttl_v4(255)?;
+    socket.set_read_timeout(Some(Duration::from_millis(POLL_MILLIS)))?;
+    Ok(socket.into())
+}
+
+fn send(socket: &UdpSocket, link: &LocalInterface, packet: &[u8]) -> io::Result<()> {
+    if let IpAddr::V4(ip) = link.ip {
+        SockRef::from(socket).set_multicast_if_v4(&ip)?;
+        socket.send_to(packet, (MDNS_GROUP, MDNS_PORT))?;
+    }
+    Ok(())
+}
+
+/// Decides what to relay; kept apart from the socket so it can be tested.
+pub(crate) struct Relay {
+    links: Vec<LocalInterface>,
+    /// Addresses of this host, whose packets are never relayed
+    own: HashSet<IpAddr>,
+    /// Selected service types; empty relays every type
+    types: Vec<Labels>,
+    /// Hosts named by relayed SRV records, with when to forget them
+    hosts: HashMap<Labels, Instant>,
+    /// Hashes of packets recently received or sent
+    recent: HashMap<u64, Instant>,
+}
+
+impl Relay {
+    pub(crate) fn new(
+        config: &ReflectorConfig,
+        links: Vec<LocalInterface>,
+        own: HashSet<IpAddr>,
+    ) -> Self {
+        Self {
+            links,
+            own,
+            types: config.service_types.iter().map(|ty| labels(ty)).collect(),
+            hosts: HashMap::new(),
+            recent: HashMap::new(),
+        }
+    }
+
+    /// Filter a packet received from `source`, returning the index of the
+    /// link it came from and what to send to the other links.
+    ///
+    /// Loops are broken three ways: packets from this host are ignored, a
+    /// packet is only relayed off the link its sender is on, and a packet
+    /// already seen (e.g. relayed back by another reflector) is dropped.
+    pub(crate) fn relay(
+        &mut self,
+        packet: &[u8],
+        source: SocketAddr,
+        now: Instant,
+    ) -> Option<(usize, Vec<u8>)> {
+        // Legacy unicast queries expect a direct reply we could not relay
+        if source.port() != MDNS_PORT || self.own.contains(&source.ip()) {
+            return None;
+        }
+        let from = self
+            .links
+            .iter()
+            .position(|link| link.on_link(source.ip()))?;
+
+        let window = Duration::from_millis(DUPLICATE_WINDOW_MILLIS);
+        self.recent
+            .retain(|_, seen| now.duration_since(*seen) < window);
+        self.hosts.retain(|_, until| *until > now);
+        if self.recent.insert(hash(packet), now).is_some() {
+            return None;
+        }
+
+        let message = match Message::parse(packet) {
+            Some(message) => message,
+            None => {
+                debug!("Ignoring malformed mDNS packet from {}", source);
+                return None;
+            }
+        };
+        let filtered = self.filter(message, now)?;
+        let out = filtered.encode();
+        if out.len() > MAX_PACKET || self.recent.insert(hash(&out), now).is_some() {
+            return None;
+        }
+        Some((from, out))
+    }
+
+    fn filter(&mut self, mut message: Message, now: Instant) -> Option<Message> {
+        // Learn hosts first so address records in the same packet are kept
+        for record in message.sections.iter().flatten() {
+            if let RData::Srv { target, .. } = &record.rdata {
+                if self.is_service_name(&record.name) {
+                    let memory = u64::from(record.ttl).max(MIN_HOST_MEMORY_SECS);
+                    self.hosts
+                        .insert(lowercase(target), now + Duration::from_secs(memory));
+                }
+            }
+        }
+
+        message.questions.retain(|question| {
+            self.is_service_name(&question.name)
+                || (matches!(question.qtype, TYPE_A | TYPE_AAAA | TYPE_ANY)
+                    && self.is_host(&question.name))
+        });
+        for question in &mut message.questions {
+            // Answers must go to the group for us to relay them
+            question.qclass &= !CLASS_UNICAST_RESPONSE;
+        }
+        for section in &mut message.sections {
+            section.retain(|record| self.keep(record));
+        }
+
+        let is_response = message.flags & FLAG_QR != 0;
+        let useful = if is_response {
+            message.sections.iter().any(|section| !section.is_empty())
+        } else {
+            !message.questions.is_empty()
+        };
+        useful.then_some(message)
+    }
+
+    fn keep(&self, record: &Record) -> bool {
+        if is_enumeration(&record.name) {
+            // Service type enumeration: only the selected types
+            return match &record.rdata {
+                RData::Name(target) => {
+                    self.types.is_empty() || self.types.iter().any(|ty| same(target, ty))
+                }
+                _ => false,
+            };
+        }
+        if self.is_service_name(&record.name) {
+            return true;
+        }
+        if !self.is_host(&record.name) {
+            return false;
+        }
+        // Link-local addresses are unreachable from any other link
+        match (&record.rdata, record.rtype) {
+            (RData::Raw(ip), TYPE_A) => ip.len() == 4 && !(ip[0] == 169 && ip[1] == 254),
+            (RData::Raw(ip), TYPE_AAAA) => {
+                ip.len() == 16 && !(ip[0] == 0xfe && ip[1] & 0xc0 == 0x80)
+            }
+            (_, TYPE_NSEC) => true,
+            _ => false,
+        }
+    }
+
+    fn is_service_name(&self, name: &Labels) -> bool {
+        if self.types.is_empty() {
+            return name.iter().any(|label| {
+                label.eq_ignore_ascii_case(b"_tcp") || label.eq_ignore_ascii_case(b"_udp")
+            });
+        }
+        is_enumeration(name)
+            || self
+                .types
+                .iter()
+                .any(|ty| name.len() >= ty.len() && same(&name[name.len() - ty.len()..], ty))
+    }
+
+    fn is_host(&self, name: &Labels) -> bool {
+        self.hosts.contains_key(&lowercase(name))
+    }
+}
+
+/// `_services._dns-sd._udp.local.`, the service type enumeration name
+fn is_enumeration(name: &Labels) -> bool {
+    same(name, &labels("_services._dns-sd._udp.local."))
+}
+
+fn labels(name: &str) -> Labels {
+    name.trim_end_matches('.')
+        .split('.')
+        .filter(|label| !label.is_empty())
+        .map(|label| label.as_bytes().to_vec())
+        .collect()
+}
+
+fn lowercase(name: &[Vec<u8>]) -> Labels {
+    name.iter()
+        .map(|label| label.to_ascii_lowercase())
+        .collect()
+}
+
+fn same(a: &[Vec<u8>], b: &[Vec<u8>]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).all(|(a, b)| a.eq_ignore_ascii_case(b))
+}
+
+fn hash(packet: &[u8]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    packet.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Parsed mDNS message; only the record types DNS-SD uses are decoded.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Message {
+    pub(crate) id: u16,
+    pub(crate) flags: u16,
+    pub(crate) questions: Vec<Question>,
+    /// Answer, authority and additional records
+    pub(crate) sections: [Vec<Record>; 3],
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Question {
+    pub(crate) name: Labels,
+    pub(crate) qtype: u16,
+    pub(crate) qclass: u16,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) struct Record {
+    pub(crate) name: Labels,
+    pub(crate) rtype: u16,
+    pub(crate) class: u16,
+    pub(crate) ttl: u32,
+    pub(crate) rdata: RData,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum RData {
+    /// PTR or CNAME target
+    Name(Labels),
+    Srv {
+        priority: u16,
+        weight: u16,
+        port: u16,
+        target: Labels,
+    },
+    Nsec {
+        next: Labels,
+        bitmap: Vec<u8>,
+    },
+    Raw(Vec<u8>),
+}
+
+impl Message {
+    pub(crate) fn parse(packet: &[u8]) -> Option<Self> {
+        let mut reader = Reader { packet, pos: 0 };
+        let id = reader.u16()?;
+        let flags = reader.u16()?;
+        let counts = [reader.u16()?, reader.u16()?, reader.u16()?, reader.u16()?];
+
+        let mut questions = Vec::new();
+        for _ in 0..counts[0] {
+            questions.push(Question {
+                name: reader.name()?,
+                qtype: reader.u16()?,
+                qclass: reader.u16()?,
+            });
+        }
+        let mut sections: [Vec<Record>; 3] = Default::default();
+        for (section, count) in sections.iter_mut().zip(&counts[1..]) {
+            for _ in 0..*count {
+                section.push(reader.record()?);
+            }
+        }
+
+        Some(Self {
+            id,
+            flags,
+            questions,
+            sections,
+        })
+    }
+
+    /// Encode with name compression.
+    pub(crate) fn encode(&self) -> Vec<u8> {
+        let mut writer = Writer::default();
+        writer.u16(self.id);
+        writer.u16(self.flags);
+        writer.u16(self.questions.len() as u16);
+        for section in &self.sections {
+            writer.u16(section.len() as u16);
+        }
+        for question in &self.questions {
+            writer.name(&question.name);
+            writer.u16(question.qtype);
+            writer.u16(question.qclass);
+        }
+        for record in self.sections.iter().flatten() {
+            writer.record(record);
+        }
+        writer.buf
+    }
+}
+
+struct Reader<'a> {
+    packet: &'a [u8],
+    pos: usize,
+}
+
+impl Reader<'_> {
+    fn u16(&mut self) -> Option<u16> {
+        let bytes = self.bytes(2)?;
+        Some(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Option<u32> {
+        let bytes = self.bytes(4)?;
+        Some(u32::from_be_bytes(bytes.try_into().ok()?))
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&[u8]> {
+        let bytes = self.packet.get(self.pos..self.pos.checked_add(len)?)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    /// Read a possibly compressed name.
+    fn name(&mut self) -> Option<Labels> {
+        let mut labels = Vec::new();
+        let mut pos = self.pos;
+        let mut resume = None;
+        // Bounds pointer chains, including loops
+        for _ in 0..128 {
+            let len = *self.packet.get(pos)?;
+            match len {
+                0 => {
+                    self.pos = resume.unwrap_or(pos + 1);
+                    return Some(labels);
+                }
+                len if len & 0xC0 == 0xC0 => {
+                    let low = *self.packet.get(pos + 1)?;
+                    resume.get_or_insert(pos + 2);
+                    pos = usize::from(u16::from_be_bytes([len & 0x3F, low]));
+                }
+                len if len <= 63 => {
+                    let label = self.packet.get(pos + 1..pos + 1 + usize::from(len))?;
+                    labels.push(label.to_vec());
+                    pos += 1 + usize::from(len);
+                }
+                _ => return None,
+            }
+        }
+        None
+    }
+
+    fn record(&mut self) -> Option<Record> {
+        let name = self.name()?;
+        let rtype = self.u16()?;
+        let class = self.u16()?;
+        let ttl = self.u32()?;
+        let len = usize::from(self.u16()?);
+        let end = self.pos.checked_add(len)?;
+        if end > self.packet.len() {
+            return None;
+        }
+
+        let rdata = match rtype {
+            TYPE_PTR | TYPE_CNAME => RData::Name(self.name()?),
+            TYPE_SRV => RData::Srv {
+                priority: self.u16()?,
+                weight: self.u16()?,
+                port: self.u16()?,
+                target: self.name()?,
+            },
+            TYPE_NSEC => {
+                let next = self.name()?;
+                RData::Nsec {
+                    next,
+                    bitmap: self.packet.get(self.pos..end)?.to_vec(),
+                }
+            }
+            _ => RData::Raw(self.packet[self.pos..end].to_vec()),
+        };
+        self.pos = end;
+
+        Some(Record {
+            name,
+            rtype,
+            class,
+            ttl,
+            rdata,
+        })
+    }
+}
+
+#[derive(Default)]
+struct Writer {
+    buf: Vec<u8>,
+    /// Offsets of name suffixes already written, keyed in lowercase
+    names: HashMap<Labels, u16>,
+}
+
+impl Writer {
+    fn u16(&mut self, value: u16) {
+        self.buf.extend_from_slice(&value.to_be_bytes());
+    }
+
+    fn name(&mut self, name: &[Vec<u8>]) {
+        for start in 0..name.len() {
+            let suffix = lowercase(&name[start..]);
+            if let Some(&offset) = self.names.get(&suffix) {
+                self.u16(0xC000 | offset);
+                return;
+            }
+            if self.buf.len() < 0x3FFF {
+                self.names.insert(suffix, self.buf.len() as u16);
+            }
+            self.buf.push(name[start].len() as u8);
+            self.buf.extend_from_slice(&name[start]);
+        }
+        self.buf.push(0);
+    }
+
+    fn record(&mut self, record: &Record) {
+        self.name(&record.name);
+        self.u16(record.rtype);
+        self.u16(record.class);
+        self.buf.extend_from_slice(&record.ttl.to_be_bytes());
+
+        let at = self.buf.len();
+        self.u16(0);
+        match &record.rdata {
+            RData::Name(name) => self.name(name),
+            RData::Srv {
+                priority,
+                weight,
+                port,
+                target,
+            } => {
+                self.u16(*priority);
+                self.u16(*weight);
+                self.u16(*port);
+                self.name(target);
+            }
+            RData::Nsec { next, bitmap } => {
+                self.name(next);
+                self.buf.extend_from_slice(bitmap);
+            }
+            RData::Raw(bytes) => self.buf.extend_from_slice(bytes),
+        }
+        let len = (self.buf.len() - at - 2) as u16;
+        self.buf[at..at + 2].copy_from_slice(&len.to_be_bytes());
+    }
+}
@@ -0,0 +1,490 @@
+use crate::announcer::Announcer;
+#[cfg(feature = "reflector")]
+use crate::config::ReflectorConfig;
+use crate::config::{ServiceConfig, SystemResponderPolicy};
+#[cfg(feature = "discovery")]
+use crate::discovery::BrowseCache;
+use crate::error::{MdnsError, Result};
+use crate::mdns_service;
+use crate::metrics::{self, Counters};
+use crate::publisher::{self, Publisher};
+#[cfg(feature = "reflector")]
+use crate::reflector::Reflector;
+#[cfg(feature = "wide-area")]
+use crate::wide_area::{self, WideAreaPublisher};
+use log::{info, warn};
+use mdns_sd::ServiceInfo;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+const STATUS_TIMEOUT_SECS: u64 = 2;
+
+/// Snapshot of a running [`Responder`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ResponderStatus {
+    /// Whether the publisher, usually the mDNS daemon thread, reported itself
+    /// running
+    pub daemon_running: bool,
+    /// Full name of the service registered from the primary config
+    pub primary: Option<String>,
+    pub services: Vec<RegisteredService>,
+}
+
+/// A service currently advertised by a [`Responder`].
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RegisteredService {
+    pub fullname: String,
+    pub hostname: String,
+    pub addresses: Vec<IpAddr>,
+    pub port: u16,
+}
+
+/// A service checked and built by [`Responder::prepare`], ready to register.
+struct Prepared {
+    config: ServiceConfig,
+    service_info: ServiceInfo,
+    #[cfg(feature = "wide-area")]
+    wide_area: Option<wide_area::Registration>,
+}
+
+/// Handle to an embedded mDNS responder.
+///
+/// Owns a [`Publisher`], usually an mDNS daemon, and the services
+/// registered on it. Dropping the handle unregisters every service and stops
+/// the publisher.
+pub struct Responder {
+    /// `None` once shut down
+    publisher: Option<Box<dyn Publisher>>,
+    announcer: Option<Announcer>,
+    /// Started by the first service with a `wide_area` config
+    #[cfg(feature = "wide-area")]
+    wide_area: Option<WideAreaPublisher>,
+    /// Running when the primary config has a `reflector` section
+    #[cfg(feature = "reflector")]
+    reflector: Option<Reflector>,
+    /// Created by the first browse request or `browse` config entry
+    #[cfg(feature = "discovery")]
+    browse_cache: Option<BrowseCache>,
+    primary: Option<String>,
+    /// Config of the primary service, registered again if an update fails
+    primary_config: Option<ServiceConfig>,
+    services: BTreeMap<String, ServiceInfo>,
+    /// Timeout used when the handle is dropped, taken from the primary config
+    shutdown_timeout: Duration,
+    counters: Arc<Counters>,
+}
+
+impl Responder {
+    /// Start the publisher chosen by [`publisher::for_config`], usually an mDNS
+    /// daemon, and register the service described by `config`.
+    ///
+    /// Other responders on the host are handled as `config.system_responder`
+    /// says: warned about, refused, or registered through instead. The host's
+    /// responder publishes on every interface it manages, so a warning is
+    /// logged if `interfaces` asks for fewer.
+    pub fn start(config: ServiceConfig) -> Result<Self> {
+        let publisher = publisher::for_config(&config)?;
+        if config.system_responder == SystemResponderPolicy::Register
+            && !config.interfaces.is_empty()
+        {
+            warn!(
+                "{} publishes on every interface it manages, not only on {:?}",
+                publisher.name(),
+                config.interfaces
+            );
+        }
+        Self::with_publisher(config, publisher)
+    }
+
+    /// Register the service described by `config` through `publisher`.
+    ///
+    /// Announcement pacing and daemon event counters only run when the
+    /// publisher has an embedded daemon.
+    pub fn with_publisher(config: ServiceConfig, publisher: Box<dyn Publisher>) -> Result<Self> {
+        let counters = Arc::new(Counters::default());
+        let announcer = publisher.daemon().map(|daemon| {
+            match daemon.monitor() {
+                Ok(events) => Arc::clone(&counters).watch(events),
+                Err(e) => warn!("Failed to monitor mDNS daemon events: {}", e),
+            }
+            Announcer::start(daemon.clone())
+        });
+
+        let mut responder = Self {
+            announcer,
+            publisher: Some(publisher),
+            #[cfg(feature = "wide-area")]
+            wide_area: None,
+            #[cfg(feature = "reflector")]
+            reflector: None,
+            #[cfg(feature = "discovery")]
+            browse_cache: None,
+            primary: None,
+            primary_config: None,
+            services: BTreeMap::new(),
+            shutdown_timeout: config.shutdown_timeout(),
+            counters,
+        };
+        responder.update(config)?;
+        Ok(responder)
+    }
+
+    /// Replace the primary service with one built from `config`.
+    ///
+    /// A config that fails to validate or build, or an old service that
+    /// cannot be unregistered, leaves the current primary service and
+    /// reflector in place. If the new service cannot be registered, the old
+    /// one is registered again.
+    pub fn update(&mut self, config: ServiceConfig) -> Result<()> {
+        let prepared = self.prepare(config.clone())?;
+        // Restarted so changed interfaces or service types take effect
+        #[cfg(feature = "reflector")]
+        let previous_reflector = self
+            .primary_config
+            .as_ref()
+            .and_then(|previous| previous.reflector.clone());
+        #[cfg(feature = "reflector")]
+        if let Err(e) = self.restart_reflector(config.reflector.as_ref()) {
+            self.restore_reflector(previous_reflector.as_ref());
+            return Err(e);
+        }
+
+        let previous = self.primary_config.take();
+        if let Some(fullname) = self.primary.take() {
+            info!("Updating registration for {}", fullname);
+            // Still advertised, so it stays the primary service
+            if let Err(e) = self.remove_service(&fullname) {
+                self.primary = Some(fullname);
+                self.primary_config = previous;
+                #[cfg(feature = "reflector")]
+                self.restore_reflector(previous_reflector.as_ref());
+                return Err(e);
+            }
+            self.counters.reloaded();
+        }
+        match self.register(prepared) {
+            Ok(fullname) => self.primary = Some(fullname),
+            Err(e) => {
+                if let Some(previous) = previous {
+                    match self.add_service(previous.clone()) {
+                        Ok(fullname) => {
+                            warn!("Kept {} after the update failed", fullname);
+                            self.primary = Some(fullname);
+                            self.primary_config = Some(previous);
+                        }
+                        Err(e) => warn!("Failed to register the previous service again: {}", e),
+                    }
+                }
+                #[cfg(feature = "reflector")]
+                self.restore_reflector(previous_reflector.as_ref());
+                return Err(e);
+            }
+        }
+        self.shutdown_timeout = config.shutdown_timeout();
+        let browse = config.browse.clone();
+        self.primary_config = Some(config);
+
+        // Types dropped from the config stay cached until restart
+        #[cfg(feature = "discovery")]
+        for service_type in &browse {
+            self.browse_cache()?.browse(service_type)?;
+        }
+        #[cfg(not(feature = "discovery"))]
+        if !browse.is_empty() {
+            warn!("Ignoring browse: built without the discovery feature");
+        }
+        Ok(())
+    }
+
+    /// Stop the running reflector, then start one for `config` if given.
+    ///
+    /// The old one goes first so both never hold port 5353 or relay at once.
+    #[cfg(feature = "reflector")]
+    fn restart_reflector(&mut self, config: Option<&ReflectorConfig>) -> Result<()> {
+        self.reflector.take();
+        self.reflector = config
+            .map(|reflector| Reflector::start(reflector, Arc::clone(&self.counters)))
+            .transpose()?;
+        Ok(())
+    }
+
+    /// Bring back the reflector of a config that is being kept.
+    #[cfg(feature = "reflector")]
+    fn restore_reflector(&mut self, config: Option<&ReflectorConfig>) {
+        if let Err(e) = self.restart_reflector(config) {
+            warn!("Failed to restart the previous reflector: {}", e);
+        }
+    }
+
+    /// Cache of remote services browsed on this responder's daemon.
+    #[cfg(feature = "discovery")]
+    pub fn browse_cache(&mut self) -> Result<&BrowseCache> {
+        let publisher = self.publisher()?;
+        let daemon = publisher.daemon().cloned().ok_or_else(|| {
+            MdnsError::Service(format!(
+                "browsing needs the embedded mDNS daemon, not {}",
+                publisher.name()
+            ))
+        })?;
+        Ok(self
+            .browse_cache
+            .get_or_insert_with(|| BrowseCache::new(daemon)))
+    }
+
+    /// Register an additional service and return its full name.
+    pub fn add_service(&mut self, config: ServiceConfig) -> Result<String> {
+        let prepared = self.prepare(config)?;
+        self.register(prepared)
+    }
+
+    /// Everything about registering `config` that can fail without touching
+    /// the responder.
+    fn prepare(&self, config: ServiceConfig) -> Result<Prepared> {
+        config.validate()?;
+        let service_info = mdns_service::build_service_info(&config)?;
+        #[cfg(feature = "wide-area")]
+        let wide_area = config
+            .wide_area
+            .as_ref()
+            .map(|wide_area| wide_area::Registration::new(&service_info, wide_area))
+            .transpose()?;
+        Ok(Prepared {
+            config,
+            service_info,
+            #[cfg(feature = "wide-area")]
+            wide_area,
+        })
+    }
+
+    fn register(&mut self, prepared: Prepared) -> Result<String> {
+        let Prepared {
+            config,
+            service_info,
+            #[cfg(feature = "wide-area")]
+            wide_area,
+        } = prepared;
+        let fullname = service_info.get_fullname().to_string();
+        if self.services.contains_key(&fullname) {
+            return Err(MdnsError::ConfigValidation(format!(
+                "service {} is already registered",
+                fullname
+            )));
+        }
+
+        self.publisher_mut()?.register(&service_info)?;
+        info!(
+            "Successfully registered {} on port {} with IP {}",
+            config.instance_name,
+            config.port,
+            join_addresses(&service_info)
+        );
+
+        if let Some(announcer) = &self.announcer {
+            announcer.add(&service_info, &config.announce);
+        }
+        #[cfg(feature = "wide-area")]
+        if let Some(registration) = wide_area {
+            let counters = Arc::clone(&self.counters);
+            self.wide_area
+                .get_or_insert_with(|| WideAreaPublisher::start(counters))
+                .add(&fullname, registration);
+        }
+        self.services.insert(fullname.clone(), service_info);
+        self.counters.registered();
+        Ok(fullname)
+    }
+
+    /// Unregister the service with the given full name.
+    ///
+    /// A service the publisher fails to unregister stays tracked, so it can be
+    /// removed again later.
+    pub fn remove_service(&mut self, fullname: &str) -> Result<()> {
+        if !self.services.contains_key(fullname) {
+            return Err(MdnsError::Service(format!(
+                "service {} is not registered",
+                fullname
+            )));
+        }
+        if let Some(announcer) = &self.announcer {
+            announcer.remove(fullname);
+        }
+        #[cfg(feature = "wide-area")]
+        if let Some(wide_area) = &self.wide_area {
+            if let Err(e) = wide_area.remove(fullname, Duration::from_secs(STATUS_TIMEOUT_SECS)) {
+                warn!("Failed to withdraw {} from unicast DNS: {}", fullname, e);
+            }
+        }
+
+        // Let the goodbye go out before anything replaces it
+        self.publisher_mut()?
+            .unregister(fullname, Duration::from_secs(STATUS_TIMEOUT_SECS))?;
+        self.services.remove(fullname);
+        if self.primary.as_deref() == Some(fullname) {
+            self.primary = None;
+            self.primary_config = None;
+        }
+        self.counters.unregistered();
+        info!("Unregistered {}", fullname);
+        Ok(())
+    }
+
+    /// Announce every registered service again, e.g. after a network change.
+    pub fn reannounce(&self) -> Result<()> {
+        let publisher = self.publisher()?;
+        for (fullname, service_info) in &self.services {
+            publisher.reannounce(service_info)?;
+            info!("Re-announced {}", fullname);
+        }
+        #[cfg(feature = "wide-area")]
+        if let Some(wide_area) = &self.wide_area {
+            wide_area.refresh();
+        }
+        self.counters.reannounced();
+        Ok(())
+    }
+
+    /// Shutdown timeout configured by the primary service's config.
+    pub fn shutdown_timeout(&self) -> Duration {
+        self.shutdown_timeout
+    }
+
+    /// Report daemon health and the services currently advertised.
+    pub fn status(&self) -> ResponderStatus {
+        let daemon_running = self
+            .publisher
+            .as_ref()
+            .is_some_and(|publisher| publisher.is_running());
+
+        let services = self
+            .services
+            .values()
+            .map(|info| RegisteredService {
+                fullname: info.get_fullname().to_string(),
+                hostname: info.get_hostname().to_string(),
+                addresses: info.get_addresses().iter().copied().collect(),
+                port: info.get_port(),
+            })
+            .collect();
+
+        ResponderStatus {
+            daemon_running,
+            primary: self.primary.clone(),
+            services,
+        }
+    }
+
+    /// Render status, counters and daemon metrics in Prometheus text format.
+    pub fn metrics(&self) -> String {
+        let daemon_metrics = self
+            .publisher
+            .as_ref()
+            .and_then(|publisher| publisher.daemon())
+            .and_then(|daemon| daemon.get_metrics().ok())
+            .and_then(|rx| {
+                rx.recv_timeout(Duration::from_secs(STATUS_TIMEOUT_SECS))
+                    .ok()
+            })
+            .map(|metrics| metrics.into_iter().collect())
+            .unwrap_or_default();
+        metrics::render(&self.status(), &self.counters, &daemon_metrics)
+    }
+
+    /// Send goodbyes for every service, then stop the publisher.
+    ///
+    /// Both steps share one `timeout`. A missing acknowledgement is reported
+    /// as [`MdnsError::Timeout`], any other failure as [`MdnsError::Service`].
+    /// Later calls on the handle fail; shutting down twice is a no-op.
+    pub fn shutdown(&mut self, timeout: Duration) -> Result<()> {
+        let Some(mut publisher) = self.publisher.take() else {
+            return Ok(());
+        };
+        // Nothing may announce a service after its goodbye
+        self.announcer.take();
+        #[cfg(feature = "reflector")]
+        self.reflector.take();
+        #[cfg(feature = "discovery")]
+        self.browse_cache.take();
+        info!("Initiating graceful shutdown of {}...", publisher.name());
+
+        let deadline = Instant::now() + timeout;
+        let mut timed_out = Vec::new();
+        let mut failures = Vec::new();
+
+        #[cfg(feature = "wide-area")]
+        if let Some(wide_area) = self.wide_area.take() {
+            for fullname in self.services.keys() {
+                let remaining = deadline.saturating_duration_since(Instant::now());
+                if let Err(e) = wide_area.remove(fullname, remaining) {
+                    failures.push(format!("withdraw {}: {}", fullname, e));
+                }
+            }
+        }
+
+        for fullname in std::mem::take(&mut self.services).into_keys() {
+            let remaining = deadline.saturating_duration_since(Instant::now());
+            match publisher.unregister(&fullname, remaining) {
+                Ok(()) => {
+                    self.counters.unregistered();
+                    info!("Sent goodbye for {}", fullname)
+                }
+                Err(MdnsError::Timeout(_)) => timed_out.push(format!("unregister {}", fullname)),
+                Err(e) => failures.push(format!("unregister {}: {}", fullname, e)),
+            }
+        }
+        self.primary = None;
+        self.primary_config = None;
+
+        let remaining = deadline.saturating_duration_since(Instant::now());
+        match publisher.shutdown(remaining) {
+            Ok(()) => {}
+            Err(MdnsError::Timeout(_)) => timed_out.push(format!("{} shutdown", publisher.name())),
+            Err(e) => failures.push(format!("{} shutdown: {}", publisher.name(), e)),
+        }
+
+        if !timed_out.is_empty() {
+            return Err(MdnsError::Timeout(format!(
+                "{} did not complete within {:?}",
+                timed_out.join(", "),
+                timeout
+            )));
+        }
+        if !failures.is_empty() {
+            return Err(MdnsError::Service(failures.join(", ")));
+        }
+
+        info!("Graceful shutdown completed successfully");
+        Ok(())
+    }
+
+    fn publisher(&self) -> Result<&dyn Publisher> {
+        self.publisher
+            .as_deref()
+            .ok_or_else(|| MdnsError::Service("responder has been shut down".to_string()))
+    }
+
+    fn publisher_mut(&mut self) -> Result<&mut Box<dyn Publisher>> {
+        self.publisher
+            .as_mut()
+            .ok_or_else(|| MdnsError::Service("responder has been shut down".to_string()))
+    }
+}
+
+impl Drop for Responder {
+    fn drop(&mut self) {
+        if let Err(e) = self.shutdown(self.shutdown_timeout) {
+            warn!("Responder shutdown on drop failed: {}", e);
+        }
+    }
+}
+
+fn join_addresses(service_info: &ServiceInfo) -> String {
+    service_info
+        .get_addresses()
+        .iter()
+        .map(|ip| ip.to_string())
+        .collect::<Vec<_>>()
+        .join(",")
+}
@@ -0,0 +1,134 @@
+use crate::config::ServiceConfig;
+use crate::discovery::DiscoveredService;
+use crate::error::{MdnsError, Result};
+use crate::mdns_service;
+use crate::responder::Responder;
+use log::info;
+use mdns_sd::{ServiceDaemon, ServiceEvent};
+use std::collections::BTreeSet;
+use std::time::{Duration, Instant};
+
+/// One field that differs between the configured and the resolved service.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Mismatch {
+    pub field: String,
+    pub expected: String,
+    pub actual: String,
+}
+
+/// Outcome of a [`run`] self-check.
+#[derive(Debug, Clone)]
+pub struct SelfCheckReport {
+    pub expected: DiscoveredService,
+    /// The service as resolved from the network, if it was seen at all
+    pub resolved: Option<DiscoveredService>,
+    pub mismatches: Vec<Mismatch>,
+}
+
+impl SelfCheckReport {
+    pub fn passed(&self) -> bool {
+        self.resolved.is_some() && self.mismatches.is_empty()
+    }
+}
+
+/// Check that the service described by `config` is visible on the network.
+///
+/// Unless `attach` is set, the service is registered for the duration of the
+/// check; with `attach` the already running responder is expected to
+/// advertise it. A separate daemon then browses and resolves the service
+/// and compares hostname, addresses, port and TXT records with `config`.
+pub fn run(config: &ServiceConfig, attach: bool, timeout: Duration) -> Result<SelfCheckReport> {
+    config.validate()?;
+    let expected = DiscoveredService::from(&mdns_service::build_service_info(config)?);
+
+    let responder = if attach {
+        info!("Attaching to running responder for {}", expected.fullname);
+        None
+    } else {
+        Some(Responder::start(config.clone())?)
+    };
+
+    let resolved = resolve(&expected.service_type, &expected.fullname, timeout);
+
+    if let Some(mut responder) = responder {
+        responder.shutdown(config.shutdown_timeout())?;
+    }
+
+    let resolved = resolved?;
+    let mismatches = resolved
+        .as_ref()
+        .map(|resolved| compare(&expected, resolved))
+        .unwrap_or_default();
+
+    Ok(SelfCheckReport {
+        expected,
+        resolved,
+        mismatches,
+    })
+}
+
+/// Browse for `service_type` on a fresh daemon until `fullname` resolves.
+fn resolve(
+    service_type: &str,
+    fullname: &str,
+    timeout: Duration,
+) -> Result<Option<DiscoveredService>> {
+    let daemon = ServiceDaemon::new().map_err(MdnsError::daemon("start", None))?;
+    let receiver = daemon
+        .browse(service_type)
+        .map_err(MdnsError::daemon("browse", Some(service_type)))?;
+
+    let deadline = Instant::now() + timeout;
+    let mut resolved = None;
+    while let Ok(event) = receiver.recv_deadline(deadline) {
+        if let ServiceEvent::ServiceResolved(service) = event {
+            if service.fullname.eq_ignore_ascii_case(fullname) {
+                resolved = Some(DiscoveredService::from(service.as_ref()));
+                break;
+            }
+        }
+    }
+
+    daemon.shutdown().ok();
+    Ok(resolved)
+}
+
+/// List the fields where `actual` differs from `expected`.
+pub fn compare(expected: &DiscoveredService, actual: &DiscoveredService) -> Vec<Mismatch> {
+    let mut mismatches = Vec::new();
+    let mut check = |field: &str, expected: String, actual: String| {
+        if expected != actual {
+            mismatches.push(Mismatch {
+                field: field.to_string(),
+                expected,
+                actual,
+            });
+        }
+    };
+
+    check(
+        "hostname",
+        expected.hostname.to_lowercase(),
+        actual.hostname.to_lowercase(),
+    );
+    check("port", expected.port.to_string(), actual.port.to_string());
+
+    let expected_addrs: BTreeSet<_> = expected.addresses.iter().collect();
+    let actual_addrs: BTreeSet<_> = actual.addresses.iter().collect();
+    check(
+        "addresses",
+        format!("{:?}", expected_addrs),
+        format!("{:?}", actual_addrs),
+    );
+
+    let keys: BTreeSet<&String> = expected.txt.keys().chain(actual.txt.keys()).collect();
+    for key in keys {
+        check(
+            &format!("txt.{}", key),
+            format!("{:?}", expected.txt.get(key)),
+            format!("{:?}", actual.txt.get(key)),
+        );
+    }
+
+    mismatches
+}
@@ -0,0 +1,355 @@
+use crate::error::{MdnsError, Result};
+use log::{error, info, warn};
+use sd_notify::NotifyState;
+use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use std::path::{Path, PathBuf};
+use std::process::Command;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, mpsc};
+use std::thread;
+use std::time::Duration;
+
+use crate::config::ServiceConfig;
+use crate::control::{self, ControlServer};
+use crate::mdns_service;
+use crate::metrics::MetricsServer;
+use crate::permissions;
+use crate::responder::Responder;
+
+const SERVICE_NAME: &str = "mdns-responder";
+const WATCHDOG_SEC: u64 = 30;
+/// System user the service runs as with [`ServiceAccount::Service`]
+const SERVICE_USER: &str = "mdns-responder";
+/// How long `install` waits for a reduced-privilege service to come up
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Account the installed service runs as.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ServiceAccount {
+    /// root
+    #[default]
+    System,
+    /// The dedicated `mdns-responder` system user, holding only
+    /// `CAP_NET_BIND_SERVICE` as an ambient capability
+    Service,
+}
+
+impl FromStr for ServiceAccount {
+    type Err = MdnsError;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "system" => Ok(ServiceAccount::System),
+            "service" => Ok(ServiceAccount::Service),
+            _ => Err(MdnsError::Service(format!(
+                "unknown account '{}', expected system or service",
+                name
+            ))),
+        }
+    }
+}
+
+/// What `install` sets up besides the unit itself.
+#[derive(Debug, Clone, Default)]
+pub struct InstallOptions {
+    pub account: ServiceAccount,
+}
+
+enum Control {
+    Reload,
+    Shutdown,
+}
+
+/// Run the responder as a systemd `Type=notify` service.
+///
+/// Readiness and watchdog keep-alives are reported over `$NOTIFY_SOCKET`,
+/// SIGHUP reloads the config file and SIGTERM/SIGINT stop the service.
+pub fn run_service() -> Result<()> {
+    let (control_tx, control_rx) = mpsc::channel();
+
+    let mut signals = Signals::new([SIGHUP, SIGTERM, SIGINT])?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            let control = if signal == SIGHUP {
+                Control::Reload
+            } else {
+                Control::Shutdown
+            };
+            if control_tx.send(control).is_err() {
+                break;
+            }
+        }
+    });
+
+    let started = mdns_service::load_config(None).and_then(|config| {
+        info!("Using configuration: {:?}", config);
+        let metrics_address = config.metrics_address.clone();
+        Responder::start(config).map(|responder| (responder, metrics_address))
+    });
+    let (responder, metrics_address) = match started {
+        Ok((responder, metrics_address)) => (Arc::new(Mutex::new(responder)), metrics_address),
+        Err(e) => {
+            notify(&[NotifyState::Status(&e.to_string())]);
+            return Err(e);
+        }
+    };
+
+    let control_server = ControlServer::start(control::default_endpoint(), Arc::clone(&responder))
+        .inspect_err(|e| warn!("Control channel unavailable: {}", e))
+        .ok();
+    let metrics_server = metrics_address.and_then(|address| {
+        MetricsServer::start(&address, Arc::clone(&responder))
+            .inspect_err(|e| warn!("Metrics endpoint unavailable: {}", e))
+            .ok()
+    });
+
+    notify(&[NotifyState::Ready, NotifyState::Status("Advertising")]);
+    info!("Service started successfully");
+
+    // Ping the watchdog at half the interval systemd expects
+    let mut watchdog_usec = 0;
+    let watchdog_interval = sd_notify::watchdog_enabled(false, &mut watchdog_usec)
+        .then(|| Duration::from_micros(watchdog_usec / 2));
+
+    loop {
+        let control = match watchdog_interval {
+            Some(interval) => match control_rx.recv_timeout(interval) {
+                Ok(control) => control,
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    if control::lock(&responder).status().daemon_running {
+                        notify(&[NotifyState::Watchdog]);
+                    } else {
+                        warn!("mDNS daemon is not responding, skipping watchdog ping");
+                    }
+                    continue;
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => Control::Shutdown,
+            },
+            None => control_rx.recv().unwrap_or(Control::Shutdown),
+        };
+
+        match control {
+            Control::Reload => {
+                info!("Received SIGHUP, reloading configuration");
+                notify(&[NotifyState::Reloading]);
+                if let Ok(now) = NotifyState::monotonic_usec_now() {
+                    notify(&[now]);
+                }
+                // A bad config keeps the running one
+                if let Err(e) = mdns_service::reload(&mut control::lock(&responder)) {
+                    error!("Reload failed: {}", e);
+                }
+                notify(&[NotifyState::Ready]);
+            }
+            Control::Shutdown => {
+                info!("Received shutdown signal");
+                break;
+            }
+        }
+    }
+
+    notify(&[NotifyState::Stopping]);
+    drop(control_server);
+    drop(metrics_server);
+    let mut responder = control::lock(&responder);
+    let shutdown_timeout = responder.shutdown_timeout();
+    responder.shutdown(shutdown_timeout)
+}
+
+fn notify(state: &[NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, state) {
+        warn!("Failed to notify systemd: {}", e);
+    }
+}
+
+pub fn install(options: &InstallOptions) -> Result<()> {
+    info!("Installing systemd service: {}", SERVICE_NAME);
+    let account = options.account;
+
+    if account == ServiceAccount::Service {
+        create_service_user()?;
+    }
+
+    let exe_path = std::env::current_exe()?;
+    let unit_path = unit_path();
+    info!("Writing unit file to {:?}", unit_path);
+    std::fs::write(&unit_path, unit_file(&exe_path, account))?;
+
+    let config_path = ServiceConfig::config_path();
+    if let Some(config_dir) = config_path.parent() {
+        if !config_dir.exists() {
+            info!("Creating config directory at {:?}", config_dir);
+            std::fs::create_dir_all(config_dir)?;
+        }
+    }
+
+    if !config_path.exists() {
+        info!("Writing default config to {:?}", config_path);
+        let default_config = ServiceConfig::default();
+        default_config.save_to_file(&config_path)?;
+    }
+    info!("Restricting ownership and mode of {:?}", config_path);
+    permissions::secure_config(&config_path)?;
+
+    systemctl(&["daemon-reload"])?;
+    systemctl(&["enable", SERVICE_NAME])?;
+
+    if account == ServiceAccount::Service {
+        if let Some(log_file) = ServiceConfig::from_file(&config_path)
+            .ok()
+            .and_then(|config| config.logging.file)
+        {
+            warn!(
+                "The log file {:?} and its directory must be writable by {}",
+                log_file, SERVICE_USER
+            );
+        }
+        verify_account()?;
+    }
+
+    info!("Service installed successfully");
+
+    Ok(())
+}
+
+fn create_service_user() -> Result<()> {
+    let exists = Command::new("id").args(["-u", SERVICE_USER]).output()?;
+    if exists.status.success() {
+        return Ok(());
+    }
+    info!("Creating system user {}", SERVICE_USER);
+    let output = Command::new("useradd")
+        .args([
+            "--system",
+            "--no-create-home",
+            "--home-dir",
+            "/nonexistent",
+            "--shell",
+            "/usr/sbin/nologin",
+            SERVICE_USER,
+        ])
+        .output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        error!("useradd {} failed: {}", SERVICE_USER, stderr);
+        return Err(MdnsError::Service(format!(
+            "cannot create user {}",
+            SERVICE_USER
+        )));
+    }
+    Ok(())
+}
+
+/// Start the service as its reduced-privilege user and wait for its daemon
+/// to bind UDP 5353, then leave it running or stopped as it was found.
+fn verify_account() -> Result<()> {
+    info!(
+        "Checking that the service can bind UDP 5353 as {}",
+        SERVICE_USER
+    );
+    let was_active = Command::new("systemctl")
+        .args(["is-active", "--quiet", SERVICE_NAME])
+        .status()?
+        .success();
+    let started = systemctl(&["restart", SERVICE_NAME])
+        .and_then(|()| control::wait_for_daemon(control::default_endpoint(), VERIFY_TIMEOUT));
+    if !was_active {
+        systemctl(&["stop", SERVICE_NAME])?;
+    }
+    match started {
+        Ok(status) => {
+            info!(
+                "Service came up as {} with {} service(s) advertised",
+                SERVICE_USER,
+                status.responder.services.len()
+            );
+            Ok(())
+        }
+        Err(e) => Err(MdnsError::Service(format!(
+            "service did not come up as {}: {}; see `journalctl -u {}` or install with --account system",
+            SERVICE_USER, e, SERVICE_NAME
+        ))),
+    }
+}
+
+pub fn uninstall() -> Result<()> {
+    info!("Uninstalling systemd service: {}", SERVICE_NAME);
+
+    // The unit may already be stopped or disabled
+    Command::new("systemctl")
+        .args(["disable", "--now", SERVICE_NAME])
+        .output()?;
+
+    let unit_path = unit_path();
+    if unit_path.exists() {
+        std::fs::remove_file(&unit_path)?;
+    }
+
+    systemctl(&["daemon-reload"])?;
+
+    info!("Service uninstalled successfully");
+
+    Ok(())
+}
+
+pub fn unit_path() -> PathBuf {
+    PathBuf::from(format!("/etc/systemd/system/{}.service", SERVICE_NAME))
+}
+
+fn unit_file(exe_path: &Path, account: ServiceAccount) -> String {
+    let credentials = match account {
+        ServiceAccount::System => String::new(),
+        ServiceAccount::Service => format!(
+            "User={user}
+Group={user}
+# The control socket's directory, which the user cannot create in /run
+RuntimeDirectory={name}
+# UDP 5353 needs no privilege; this is only for a metrics_address below 1024
+AmbientCapabilities=CAP_NET_BIND_SERVICE
+CapabilityBoundingSet=CAP_NET_BIND_SERVICE
+NoNewPrivileges=yes
+",
+            user = SERVICE_USER,
+            name = SERVICE_NAME
+        ),
+    };
+    format!(
+        "[Unit]
+Description=mDNS Responder - Bonjour service for Samba shares
+Wants=network-online.target
+After=network-online.target
+
+[Service]
+Type=notify
+ExecStart=\"{}\" service
+ExecReload=/bin/kill -HUP $MAINPID
+WatchdogSec={}
+Restart=on-failure
+# Configuration errors (codes 10, 13 and 14) and a strict refusal to run
+# beside another mDNS responder (25) need a fix, not a restart
+RestartPreventExitStatus=10 13 14 25
+{}
+[Install]
+WantedBy=multi-user.target
+",
+        exe_path.display(),
+        WATCHDOG_SEC,
+        credentials
+    )
+}
+
+fn systemctl(args: &[&str]) -> Result<()> {
+    let output = Command::new("systemctl").args(args).output()?;
+
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        error!("systemctl {} failed: {}", args.join(" "), stderr);
+        return Err(MdnsError::Service(format!(
+            "systemctl {} failed",
+            args.join(" ")
+        )));
+    }
+
+    Ok(())
+}
@@ -0,0 +1,440 @@
+use crate::config::WideAreaConfig;
+use crate::dns_update::{self, Name, RData, Update, UpdateClient};
+use crate::error::{MdnsError, Result};
+use crate::metrics::Counters;
+use log::{info, warn};
+use mdns_sd::ServiceInfo;
+use std::collections::{BTreeMap, BTreeSet};
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, TryLockError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// How long to wait for the server to answer one update
+const UPDATE_TIMEOUT_SECS: u64 = 5;
+/// Delay before retrying an update that failed
+const RETRY_SECS: u64 = 30;
+const IDLE_WAIT_SECS: u64 = 3_600;
+const LOCK_POLL_MILLIS: u64 = 10;
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+
+/// Records of one service rewritten from `local.` into the wide-area zone.
+#[derive(Debug, Clone)]
+struct ServiceRecords {
+    service_type: Name,
+    instance: Name,
+    host: Name,
+    port: u16,
+    txt: Vec<Vec<u8>>,
+    addresses: Vec<IpAddr>,
+}
+
+impl ServiceRecords {
+    fn new(service_info: &ServiceInfo, zone: &Name) -> Result<Self> {
+        let ty_domain = service_info.get_type();
+        let instance = service_info
+            .get_fullname()
+            .strip_suffix(ty_domain)
+            .and_then(|instance| instance.strip_suffix('.'))
+            .ok_or_else(|| {
+                MdnsError::Service(format!(
+                    "{} is not an instance of {}",
+                    service_info.get_fullname(),
+                    ty_domain
+                ))
+            })?;
+        let service_type = Name::parse(without_local(ty_domain))?.join(zone)?;
+        let host = Name::parse(without_local(service_info.get_hostname()))?.join(zone)?;
+
+        let txt = service_info
+            .get_properties()
+            .iter()
+            .map(|property| {
+                let mut entry = property.key().as_bytes().to_vec();
+                if let Some(val) = property.val() {
+                    entry.push(b'=');
+                    entry.extend_from_slice(val);
+                }
+                entry
+            })
+            .collect();
+        // Link-local addresses mean nothing off the link
+        let mut addresses: Vec<IpAddr> = service_info
+            .get_addresses()
+            .iter()
+            .copied()
+            .filter(|ip| match ip {
+                IpAddr::V4(ip) => !ip.is_link_local(),
+                IpAddr::V6(ip) => !ip.is_unicast_link_local(),
+            })
+            .collect();
+        addresses.sort();
+
+        Ok(Self {
+            instance: Name::label(instance)?.join(&service_type)?,
+            service_type,
+            host,
+            port: service_info.get_port(),
+            txt,
+            addresses,
+        })
+    }
+
+    /// Replace the instance records and add the browse PTR. The host records
+    /// are replaced by `host_addresses`, the union over every service on the
+    /// host, so services scoped to different addresses do not overwrite each
+    /// other.
+    fn publish(&self, ttl: u32, host_addresses: &[IpAddr]) -> Vec<Update> {
+        let mut updates = vec![
+            Update::DeleteRrset {
+                name: self.instance.clone(),
+                rtype: dns_update::TYPE_SRV,
+            },
+            Update::DeleteRrset {
+                name: self.instance.clone(),
+                rtype: dns_update::TYPE_TXT,
+            },
+            Update::Add {
+                name: self.service_type.clone(),
+                ttl,
+                rdata: RData::Ptr(self.instance.clone()),
+            },
+            Update::Add {
+                name: self.instance.clone(),
+                ttl,
+                rdata: RData::Srv {
+                    port: self.port,
+                    target: self.host.clone(),
+                },
+            },
+            Update::Add {
+                name: self.instance.clone(),
+                ttl,
+                rdata: RData::Txt(self.txt.clone()),
+            },
+        ];
+        updates.extend(self.replace_host(ttl, host_addresses));
+        updates
+    }
+
+    /// Remove the browse PTR and instance records, and narrow the host
+    /// records to `host_addresses`, those of the services still on the host.
+    fn withdraw(&self, ttl: u32, host_addresses: &[IpAddr]) -> Vec<Update> {
+        let mut updates = vec![
+            Update::DeleteRecord {
+                name: self.service_type.clone(),
+                rdata: RData::Ptr(self.instance.clone()),
+            },
+            Update::DeleteName {
+                name: self.instance.clone(),
+            },
+        ];
+        updates.extend(self.replace_host(ttl, host_addresses));
+        updates
+    }
+
+    fn replace_host(&self, ttl: u32, addresses: &[IpAddr]) -> Vec<Update> {
+        let delete = [dns_update::TYPE_A, dns_update::TYPE_AAAA].map(|rtype| Update::DeleteRrset {
+            name: self.host.clone(),
+            rtype,
+        });
+        let add = addresses.iter().map(|&ip| Update::Add {
+            name: self.host.clone(),
+            ttl,
+            rdata: RData::address(ip),
+        });
+        delete.into_iter().chain(add).collect()
+    }
+}
+
+/// A service to publish, prepared before it is registered over mDNS so
+/// configuration problems surface first.
+#[derive(Clone)]
+pub(crate) struct Registration {
+    /// Tells a publish in flight whether its registration is still queued
+    id: u64,
+    client: UpdateClient,
+    records: ServiceRecords,
+    ttl: u32,
+    refresh: Option<Duration>,
+    /// When to publish next; `None` once published with refreshing off
+    next: Option<Instant>,
+    published: bool,
+}
+
+impl Registration {
+    pub(crate) fn new(service_info: &ServiceInfo, config: &WideAreaConfig) -> Result<Self> {
+        let client = UpdateClient::new(config)?;
+        let records = ServiceRecords::new(service_info, client.zone())?;
+        Ok(Self {
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            client,
+            records,
+            ttl: config.ttl_secs,
+            refresh: config.refresh_interval(),
+            next: Some(Instant::now()),
+            published: false,
+        })
+    }
+
+    fn shares_host(&self, other: &Registration) -> bool {
+        self.client.server() == other.client.server() && self.records.host == other.records.host
+    }
+}
+
+/// Addresses of every registration in `registrations` on the same host as
+/// `registration`.
+fn host_addresses<'a>(
+    registrations: impl IntoIterator<Item = &'a Registration>,
+    registration: &Registration,
+) -> Vec<IpAddr> {
+    registrations
+        .into_iter()
+        .filter(|other| other.shares_host(registration))
+        .flat_map(|other| other.records.addresses.iter().copied())
+        .collect::<BTreeSet<_>>()
+        .into_iter()
+        .collect()
+}
+
+/// Background thread publishing services to unicast DNS servers.
+///
+/// Updates are sent from the thread so a slow or unreachable server never
+/// holds up mDNS registration; failed updates are retried. Removing a service
+/// withdraws its records synchronously. Stops when dropped.
+pub(crate) struct WideAreaPublisher {
+    registrations: Arc<Mutex<BTreeMap<String, Registration>>>,
+    counters: Arc<Counters>,
+    wake: Option<mpsc::Sender<()>>,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl WideAreaPublisher {
+    pub(crate) fn start(counters: Arc<Counters>) -> Self {
+        let registrations: Arc<Mutex<BTreeMap<String, Registration>>> = Arc::default();
+        let (wake, wake_rx) = mpsc::channel();
+
+        let thread = thread::spawn({
+            let registrations = Arc::clone(&registrations);
+            let counters = Arc::clone(&counters);
+            move || {
+                loop {
+                    let next = publish_due(&registrations, &counters);
+                    let wait = next
+                        .map(|next| next.saturating_duration_since(Instant::now()))
+                        .unwrap_or(Duration::from_secs(IDLE_WAIT_SECS));
+                    match wake_rx.recv_timeout(wait) {
+                        Ok(()) | Err(RecvTimeoutError::Timeout) => {}
+                        Err(RecvTimeoutError::Disconnected) => break,
+                    }
+                }
+            }
+        });
+
+        Self {
+            registrations,
+            counters,
+            wake: Some(wake),
+            thread: Some(thread),
+        }
+    }
+
+    /// Queue a service that was just registered for publishing.
+    pub(crate) fn add(&self, fullname: &str, registration: Registration) {
+        self.lock().insert(fullname.to_string(), registration);
+        self.wake();
+    }
+
+    /// Publish every service again now, e.g. after a network change.
+    pub(crate) fn refresh(&self) {
+        let now = Instant::now();
+        for registration in self.lock().values_mut() {
+            registration.next = Some(now);
+        }
+        self.wake();
+    }
+
+    /// Stop publishing `fullname` and withdraw its records within `timeout`.
+    pub(crate) fn remove(&self, fullname: &str, timeout: Duration) -> Result<()> {
+        let deadline = Instant::now() + timeout;
+        let (registration, remaining) = {
+            let mut registrations = self.lock_until(deadline)?;
+            let Some(registration) = registrations.remove(fullname) else {
+                return Ok(());
+            };
+            if !registration.published {
+                return Ok(());
+            }
+            let remaining = host_addresses(
+                registrations.values().filter(|other| other.published),
+                &registration,
+            );
+            (registration, remaining)
+        };
+
+        let timeout = deadline
+            .saturating_duration_since(Instant::now())
+            .min(Duration::from_secs(UPDATE_TIMEOUT_SECS));
+        if timeout.is_zero() {
+            self.counters.dns_update_failed();
+            return Err(MdnsError::Timeout(format!(
+                "no time left to withdraw {}",
+                registration.records.instance
+            )));
+        }
+        withdraw(&registration, &remaining, timeout, &self.counters)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<String, Registration>> {
+        self.registrations
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Like [`lock`](Self::lock), but give up at `deadline`.
+    fn lock_until(
+        &self,
+        deadline: Instant,
+    ) -> Result<MutexGuard<'_, BTreeMap<String, Registration>>> {
+        loop {
+            match self.registrations.try_lock() {
+                Ok(registrations) => return Ok(registrations),
+                Err(TryLockError::Poisoned(e)) => return Ok(e.into_inner()),
+                Err(TryLockError::WouldBlock) if Instant::now() >= deadline => {
+                    return Err(MdnsError::Timeout(
+                        "wide-area registrations stayed locked".to_string(),
+                    ));
+                }
+                Err(TryLockError::WouldBlock) => {
+                    thread::sleep(Duration::from_millis(LOCK_POLL_MILLIS))
+                }
+            }
+        }
+    }
+
+    fn wake(&self) {
+        if let Some(wake) = &self.wake {
+            wake.send(()).ok();
+        }
+    }
+}
+
+impl Drop for WideAreaPublisher {
+    fn drop(&mut self) {
+        // Disconnecting the wake channel ends the thread
+        self.wake.take();
+        if let Some(thread) = self.thread.take() {
+            thread.join().ok();
+        }
+    }
+}
+
+/// Publish every service that is due and return when the next one is due.
+///
+/// Updates go out with the lock released so removing a service never waits
+/// on a slow server. A service removed while its publish was in flight is
+/// withdrawn again once the publish lands.
+fn publish_due(
+    registrations: &Mutex<BTreeMap<String, Registration>>,
+    counters: &Counters,
+) -> Option<Instant> {
+    let lock = || registrations.lock().unwrap_or_else(PoisonError::into_inner);
+    let now = Instant::now();
+    let due: Vec<(String, Registration, Vec<IpAddr>)> = {
+        let registrations = lock();
+        registrations
+            .iter()
+            .filter(|(_, registration)| registration.next.is_some_and(|next| next <= now))
+            .map(|(fullname, registration)| {
+                let addresses = host_addresses(registrations.values(), registration);
+                (fullname.clone(), registration.clone(), addresses)
+            })
+            .collect()
+    };
+
+    for (fullname, sent, addresses) in due {
+        let updates = sent.records.publish(sent.ttl, &addresses);
+        let result = sent
+            .client
+            .send(&updates, Duration::from_secs(UPDATE_TIMEOUT_SECS));
+        match &result {
+            Ok(()) => {
+                counters.dns_updated();
+                info!(
+                    "Published {} to {}",
+                    sent.records.instance,
+                    sent.client.server()
+                );
+            }
+            Err(e) => {
+                counters.dns_update_failed();
+                warn!("{}; retrying in {} s", e, RETRY_SECS);
+            }
+        }
+
+        let mut registrations = lock();
+        match registrations.get_mut(&fullname) {
+            Some(registration) if registration.id == sent.id => {
+                registration.next = match result {
+                    Ok(()) => {
+                        registration.published = true;
+                        registration.refresh.map(|refresh| Instant::now() + refresh)
+                    }
+                    Err(_) => Some(Instant::now() + Duration::from_secs(RETRY_SECS)),
+                };
+            }
+            _ if result.is_ok() => {
+                let remaining = host_addresses(
+                    registrations.values().filter(|other| other.published),
+                    &sent,
+                );
+                drop(registrations);
+                let timeout = Duration::from_secs(UPDATE_TIMEOUT_SECS);
+                if let Err(e) = withdraw(&sent, &remaining, timeout, counters) {
+                    warn!("Failed to withdraw {} after removal: {}", fullname, e);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    lock()
+        .values()
+        .filter_map(|registration| registration.next)
+        .min()
+}
+
+fn withdraw(
+    registration: &Registration,
+    host_addresses: &[IpAddr],
+    timeout: Duration,
+    counters: &Counters,
+) -> Result<()> {
+    let updates = registration
+        .records
+        .withdraw(registration.ttl, host_addresses);
+    let result = registration.client.send(&updates, timeout);
+    match &result {
+        Ok(()) => {
+            counters.dns_updated();
+            info!(
+                "Withdrew {} from {}",
+                registration.records.instance,
+                registration.client.server()
+            );
+        }
+        Err(_) => counters.dns_update_failed(),
+    }
+    result
+}
+
+/// Strip the trailing `local.` from an mDNS name, leaving the relative part.
+fn without_local(name: &str) -> &str {
+    name.strip_suffix("local.")
+        .or_else(|| name.strip_suffix("local"))
+        .unwrap_or(name)
+        .trim_end_matches('.')
+}
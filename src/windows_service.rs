@@ -1,10 +1,12 @@
-use crate::error::Result;
-use log::{error, info};
+use crate::error::{MdnsError, Result};
+use log::{error, info, warn};
 use std::ffi::OsString;
 use std::path::PathBuf;
 use std::process::Command;
-use std::sync::mpsc;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex, mpsc};
 use std::thread;
+use std::time::Duration;
 use windows_service::{
     define_windows_service,
     service::{
@@ -16,13 +18,91 @@ use windows_service::{
 };
 
 use crate::config::ServiceConfig;
-use crate::mdns_service;
+use crate::control::{self, ControlServer};
+use crate::firewall;
+use crate::logging;
+use crate::mdns_service::{self, ConfigFallback};
+use crate::metrics::MetricsServer;
+use crate::permissions;
+use crate::responder::Responder;
 
 const SERVICE_NAME: &str = "MDNSResponder";
+const EVENT_SOURCE_KEY: &str = r"HKLM\SYSTEM\CurrentControlSet\Services\EventLog\Application";
+/// Message file whose every event ID formats as just its first insertion string
+const EVENT_MESSAGE_FILE: &str =
+    r"%SystemRoot%\Microsoft.NET\Framework64\v4.0.30319\EventLogMessages.dll";
+
+/// The only privilege the service keeps when not running as LocalSystem
+const REQUIRED_PRIVILEGES: &str = "SeChangeNotifyPrivilege";
+/// How long `install` waits for a reduced-privilege service to come up
+const VERIFY_TIMEOUT: Duration = Duration::from_secs(15);
+
+/// Account the installed service runs as.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ServiceAccount {
+    /// LocalSystem, with full control of the machine
+    #[default]
+    System,
+    /// The virtual account `NT SERVICE\MDNSResponder`
+    Service,
+    /// The shared, low-privilege `NT AUTHORITY\LocalService`
+    LocalService,
+}
+
+impl ServiceAccount {
+    /// Name passed to `sc create obj=`
+    pub fn object_name(&self) -> String {
+        match self {
+            ServiceAccount::System => "LocalSystem".to_string(),
+            ServiceAccount::Service => format!("NT SERVICE\\{}", SERVICE_NAME),
+            ServiceAccount::LocalService => "NT AUTHORITY\\LocalService".to_string(),
+        }
+    }
+}
+
+impl FromStr for ServiceAccount {
+    type Err = MdnsError;
+
+    fn from_str(name: &str) -> Result<Self> {
+        match name {
+            "system" => Ok(ServiceAccount::System),
+            "service" => Ok(ServiceAccount::Service),
+            "local-service" => Ok(ServiceAccount::LocalService),
+            _ => Err(MdnsError::Service(format!(
+                "unknown account '{}', expected system, service or local-service",
+                name
+            ))),
+        }
+    }
+}
+
+/// What `install` sets up besides the service itself.
+#[derive(Debug, Clone)]
+pub struct InstallOptions {
+    pub account: ServiceAccount,
+    /// Profiles the inbound UDP 5353 firewall rule applies in
+    pub firewall_profiles: Vec<firewall::Profile>,
+}
+
+impl Default for InstallOptions {
+    fn default() -> Self {
+        Self {
+            account: ServiceAccount::default(),
+            firewall_profiles: firewall::Profile::DEFAULT.to_vec(),
+        }
+    }
+}
 
 define_windows_service!(ffi_service_main, service_main);
 
-pub fn service_main(_args: Vec<OsString>) {
+pub fn service_main(args: Vec<OsString>) {
+    // Start parameters, e.g. `sc start MDNSResponder --allow-default-config`
+    if args
+        .iter()
+        .any(|arg| arg == mdns_service::ALLOW_DEFAULT_CONFIG_FLAG)
+    {
+        mdns_service::set_config_fallback(ConfigFallback::Defaults);
+    }
     if let Err(e) = run_service() {
         error!("Service error: {}", e);
     }
@@ -40,57 +120,98 @@ fn run_service() -> Result<()> {
             }
             ServiceControl::Interrogate => ServiceControlHandlerResult::NoError,
             _ => ServiceControlHandlerResult::NotImplemented,
-        })?;
-
-    status_handle.set_service_status(ServiceStatus {
-        service_type: ServiceType::OWN_PROCESS,
-        current_state: ServiceState::StartPending,
-        controls_accepted: ServiceControlAccept::empty(),
-        exit_code: ServiceExitCode::Win32(0),
-        checkpoint: 1,
-        wait_hint: std::time::Duration::from_secs(5),
-        process_id: None,
-    })?;
-
-    let service_thread =
-        thread::spawn(move || -> Result<()> { mdns_service::run(Some(shutdown_rx), None) });
-
-    status_handle.set_service_status(ServiceStatus {
-        service_type: ServiceType::OWN_PROCESS,
-        current_state: ServiceState::Running,
-        controls_accepted: ServiceControlAccept::STOP,
-        exit_code: ServiceExitCode::Win32(0),
-        checkpoint: 0,
-        wait_hint: std::time::Duration::default(),
-        process_id: None,
-    })?;
-
-    info!("Service started successfully");
-
-    match service_thread.join() {
-        Ok(Ok(_)) => info!("Service stopped gracefully."),
-        Ok(Err(e)) => error!("Service thread failed: {}", e),
-        Err(_) => error!("Service thread panicked."),
-    }
-
-    status_handle.set_service_status(ServiceStatus {
-        service_type: ServiceType::OWN_PROCESS,
-        current_state: ServiceState::Stopped,
-        controls_accepted: ServiceControlAccept::empty(),
-        exit_code: ServiceExitCode::Win32(0),
-        checkpoint: 0,
-        wait_hint: std::time::Duration::default(),
-        process_id: None,
-    })?;
+        })
+        .map_err(MdnsError::dispatcher("register the control handler"))?;
+
+    status_handle
+        .set_service_status(ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: ServiceState::StartPending,
+            controls_accepted: ServiceControlAccept::empty(),
+            exit_code: ServiceExitCode::Win32(0),
+            checkpoint: 1,
+            wait_hint: std::time::Duration::from_secs(5),
+            process_id: None,
+        })
+        .map_err(MdnsError::dispatcher("report start pending"))?;
+
+    let started = mdns_service::load_config(None).and_then(|config| {
+        info!("Using configuration: {:?}", config);
+        let metrics_address = config.metrics_address.clone();
+        Responder::start(config).map(|responder| (responder, metrics_address))
+    });
+    let exit_code = match started {
+        Ok((responder, metrics_address)) => {
+            let responder = Arc::new(Mutex::new(responder));
+            let control_server =
+                ControlServer::start(control::default_endpoint(), Arc::clone(&responder))
+                    .inspect_err(|e| error!("Control channel unavailable: {}", e))
+                    .ok();
+            let metrics_server = metrics_address.and_then(|address| {
+                MetricsServer::start(&address, Arc::clone(&responder))
+                    .inspect_err(|e| error!("Metrics endpoint unavailable: {}", e))
+                    .ok()
+            });
+
+            status_handle
+                .set_service_status(ServiceStatus {
+                    service_type: ServiceType::OWN_PROCESS,
+                    current_state: ServiceState::Running,
+                    controls_accepted: ServiceControlAccept::STOP,
+                    exit_code: ServiceExitCode::Win32(0),
+                    checkpoint: 0,
+                    wait_hint: std::time::Duration::default(),
+                    process_id: None,
+                })
+                .map_err(MdnsError::dispatcher("report running"))?;
+
+            info!("Service started successfully");
+
+            shutdown_rx.recv().ok();
+
+            drop(control_server);
+            drop(metrics_server);
+            let mut responder = control::lock(&responder);
+            let shutdown_timeout = responder.shutdown_timeout();
+            match responder.shutdown(shutdown_timeout) {
+                Ok(_) => {
+                    info!("Service stopped gracefully.");
+                    ServiceExitCode::Win32(0)
+                }
+                Err(e) => {
+                    error!("Service shutdown failed (code {}): {}", e.code(), e);
+                    ServiceExitCode::ServiceSpecific(e.code())
+                }
+            }
+        }
+        Err(e) => {
+            error!("Failed to start responder (code {}): {}", e.code(), e);
+            ServiceExitCode::ServiceSpecific(e.code())
+        }
+    };
+
+    status_handle
+        .set_service_status(ServiceStatus {
+            service_type: ServiceType::OWN_PROCESS,
+            current_state: ServiceState::Stopped,
+            controls_accepted: ServiceControlAccept::empty(),
+            exit_code,
+            checkpoint: 0,
+            wait_hint: std::time::Duration::default(),
+            process_id: None,
+        })
+        .map_err(MdnsError::dispatcher("report stopped"))?;
 
     Ok(())
 }
 
-pub fn install() -> Result<()> {
+pub fn install(options: &InstallOptions) -> Result<()> {
     info!("Installing Windows service: {}", SERVICE_NAME);
 
     let exe_path = std::env::current_exe()?;
     let bin_path = format!("\"{}\"", exe_path.display());
+    let account = options.account;
+    let object_name = account.object_name();
 
     let output = Command::new("sc")
         .args([
@@ -101,6 +222,8 @@ pub fn install() -> Result<()> {
             "auto",
             "type=",
             "own",
+            "obj=",
+            &object_name,
         ])
         .output()?;
 
@@ -111,9 +234,7 @@ pub fn install() -> Result<()> {
             "Failed to create service. stdout: {}, stderr: {}",
             stdout, stderr
         );
-        return Err(crate::error::MdnsError::Service(
-            "Service creation failed".to_string(),
-        ));
+        return Err(MdnsError::Service("Service creation failed".to_string()));
     }
 
     info!("Service installed successfully");
@@ -131,6 +252,8 @@ pub fn install() -> Result<()> {
         let default_config = ServiceConfig::default();
         default_config.save_to_file(&config_path)?;
     }
+    info!("Restricting ACL of {:?}", config_path);
+    permissions::secure_config(&config_path)?;
 
     Command::new("sc")
         .args([
@@ -142,6 +265,88 @@ pub fn install() -> Result<()> {
 
     info!("Service description set");
 
+    let event_source = format!("{}\\{}", EVENT_SOURCE_KEY, logging::EVENT_SOURCE);
+    let output = Command::new("reg")
+        .args([
+            "add",
+            &event_source,
+            "/v",
+            "EventMessageFile",
+            "/t",
+            "REG_EXPAND_SZ",
+            "/d",
+            EVENT_MESSAGE_FILE,
+            "/f",
+        ])
+        .output()?;
+    if !output.status.success() {
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        error!("Failed to register event source: {}", stderr);
+        return Err(MdnsError::Service(
+            "Event source registration failed".to_string(),
+        ));
+    }
+
+    info!("Event source {} registered", logging::EVENT_SOURCE);
+
+    firewall::add_rule(&exe_path, &options.firewall_profiles)?;
+
+    if account != ServiceAccount::System {
+        // A service SID lets ACLs name the service; the privilege list
+        // strips everything else from its token
+        sc(&["sidtype", SERVICE_NAME, "unrestricted"])?;
+        sc(&["privs", SERVICE_NAME, REQUIRED_PRIVILEGES])?;
+        if let Some(log_file) = ServiceConfig::from_file(&config_path)
+            .ok()
+            .and_then(|config| config.logging.file)
+        {
+            warn!(
+                "The log file {:?} and its directory must be writable by {}",
+                log_file, object_name
+            );
+        }
+        verify_account(&object_name)?;
+    }
+
+    Ok(())
+}
+
+/// Start the newly created service under its reduced-privilege account,
+/// wait for its daemon to bind UDP 5353, then stop it again.
+fn verify_account(object_name: &str) -> Result<()> {
+    info!(
+        "Checking that the service can bind UDP 5353 as {}",
+        object_name
+    );
+    let started = sc(&["start", SERVICE_NAME])
+        .and_then(|()| control::wait_for_daemon(control::default_endpoint(), VERIFY_TIMEOUT));
+    Command::new("sc").args(["stop", SERVICE_NAME]).output()?;
+    match started {
+        Ok(status) => {
+            info!(
+                "Service came up as {} with {} service(s) advertised",
+                object_name,
+                status.responder.services.len()
+            );
+            Ok(())
+        }
+        Err(e) => Err(MdnsError::Service(format!(
+            "service did not come up as {}: {}; check the Application event log or install with --account system",
+            object_name, e
+        ))),
+    }
+}
+
+fn sc(args: &[&str]) -> Result<()> {
+    let output = Command::new("sc").args(args).output()?;
+
+    if !output.status.success() {
+        // sc reports its errors on stdout
+        let stdout = String::from_utf8_lossy(&output.stdout);
+        error!("sc {} failed: {}", args.join(" "), stdout);
+        return Err(MdnsError::Service(format!("sc {} failed", args.join(" "))));
+    }
+
     Ok(())
 }
 
@@ -157,9 +362,22 @@ pub fn uninstall() -> Result<()> {
     if !output.status.success() {
         let error = String::from_utf8_lossy(&output.stderr);
         error!("Failed to delete service: {}", error);
-        return Err(crate::error::MdnsError::Service(
-            "Service deletion failed".to_string(),
-        ));
+        return Err(MdnsError::Service("Service deletion failed".to_string()));
+    }
+
+    if !firewall::remove_rule()? {
+        warn!("No firewall rule '{}' to remove", firewall::RULE_NAME);
+    }
+
+    let event_source = format!("{}\\{}", EVENT_SOURCE_KEY, logging::EVENT_SOURCE);
+    let output = Command::new("reg")
+        .args(["delete", &event_source, "/f"])
+        .output()?;
+    if !output.status.success() {
+        warn!(
+            "Failed to remove event source: {}",
+            String::from_utf8_lossy(&output.stderr)
+        );
     }
 
     info!("Service uninstalled successfully");
@@ -177,6 +395,7 @@ pub fn service_path() -> PathBuf {
 
 pub fn run_dispatcher() -> Result<()> {
     info!("Starting service dispatcher");
-    service_dispatcher::start(SERVICE_NAME, ffi_service_main)?;
+    service_dispatcher::start(SERVICE_NAME, ffi_service_main)
+        .map_err(MdnsError::dispatcher("start the service dispatcher"))?;
     Ok(())
 }